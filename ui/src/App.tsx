@@ -5,7 +5,7 @@ import { WikiList } from "./components/WikiList";
 import { WikiPage } from "./components/WikiPage";
 import { InviteCenter } from "./components/InviteCenter";
 import { GlobalSearch } from "./components/GlobalSearch";
-import { useWikiStore } from "./store/wikiStore";
+import { useWikiStore, WsNotification } from "./store/wikiStore";
 
 const BASE_URL = import.meta.env.BASE_URL;
 if (window.our) window.our.process = BASE_URL?.replace("/", "");
@@ -21,6 +21,8 @@ function App() {
   const { currentWiki } = useWikiStore();
   const [nodeConnected, setNodeConnected] = useState(true);
   const [api, setApi] = useState<HyperwareClientApi | undefined>();
+  // Counts socket (re)connections; subscriptions are sent once it's open
+  const [connection, setConnection] = useState(0);
   const [showInviteCenter, setShowInviteCenter] = useState(false);
 
   useEffect(() => {
@@ -31,14 +33,17 @@ function App() {
         uri: WEBSOCKET_URL,
         nodeId: window.our.node,
         processId: window.our.process,
-        onOpen: (_event, _api) => {
+        onOpen: (_event, api) => {
           console.log("Connected to Hyperware");
+          api.send({ data: "Connect" });
+          setConnection((count) => count + 1);
         },
         onMessage: (json, _api) => {
           console.log('WEBSOCKET MESSAGE', json)
           try {
-            const data = JSON.parse(json);
+            const data = JSON.parse(json) as WsNotification;
             console.log("WebSocket received message", data);
+            useWikiStore.getState().handleNotification(data);
           } catch (error) {
             console.error("Error parsing WebSocket message", error);
           }
@@ -51,6 +56,19 @@ function App() {
     }
   }, []);
 
+  // Subscribe to push notifications for the wiki being viewed. Waits for the
+  // socket to open, and subscribes again after a reconnect. Notifications come
+  // from our own node, which doesn't hear about other members' edits to a
+  // remote wiki unless we watch it
+  useEffect(() => {
+    if (!api || connection === 0 || !currentWiki) return;
+    const wiki_id = currentWiki.id;
+    api.send({ data: { Subscribe: { wiki_id, path: null } } });
+    return () => {
+      api.send({ data: { Unsubscribe: { wiki_id, path: null } } });
+    };
+  }, [api, connection, currentWiki?.id]);
+
   return (
     <div className="app">
       <header className="app-header">
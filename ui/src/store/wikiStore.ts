@@ -9,6 +9,14 @@ const getErrorMessage = (error: any, fallback: string): string => {
   return error.message || fallback;
 };
 
+// Notifications pushed by the backend over the /ws binding
+export type WsNotification =
+  | 'WikiListUpdated'
+  | { WikiUpdated: { wiki_id: string } }
+  | { PageListUpdated: { wiki_id: string } }
+  | { PageUpdated: { wiki_id: string; path: string } }
+  | { RoleUpdated: { wiki_id: string; new_role: WikiRole } };
+
 interface WikiStore {
   // State
   wikis: Wiki[];
@@ -33,6 +41,7 @@ interface WikiStore {
   inviteUser: (wiki_id: string, invitee_id: string) => Promise<void>;
   manageMember: (wiki_id: string, member_id: string, action: 'add' | 'remove' | 'update', role?: WikiRole) => Promise<void>;
   deletePage: (wiki_id: string, path: string) => Promise<void>;
+  handleNotification: (notification: WsNotification) => Promise<void>;
   setError: (error: string | null) => void;
   clearError: () => void;
 }
@@ -216,6 +225,40 @@ export const useWikiStore = create<WikiStore>((set, get) => ({
     }
   },
 
+  handleNotification: async (notification) => {
+    const { currentWiki, currentPage } = get();
+    try {
+      if (notification === 'WikiListUpdated') {
+        const wikis = await wikiApi.listWikis();
+        set({ wikis });
+      } else if ('RoleUpdated' in notification) {
+        const wikis = await wikiApi.listWikis();
+        set({ wikis });
+        if (currentWiki && currentWiki.id === notification.RoleUpdated.wiki_id) {
+          await get().loadWiki(currentWiki.id);
+        }
+      } else if ('WikiUpdated' in notification) {
+        if (currentWiki && currentWiki.id === notification.WikiUpdated.wiki_id) {
+          await get().loadWiki(currentWiki.id);
+        }
+      } else if ('PageListUpdated' in notification) {
+        if (currentWiki && currentWiki.id === notification.PageListUpdated.wiki_id) {
+          const pages = await wikiApi.listPages(currentWiki.id);
+          set({ pages });
+        }
+      } else if ('PageUpdated' in notification) {
+        const { wiki_id, path } = notification.PageUpdated;
+        if (currentWiki && currentWiki.id === wiki_id && currentPage && currentPage.path === path) {
+          const page = await wikiApi.getPage(wiki_id, path);
+          set({ currentPage: page });
+        }
+      }
+    } catch (error) {
+      // Background refresh; keep showing cached data
+      console.error('Failed to handle notification:', error);
+    }
+  },
+
   setError: (error) => {
     set({ error });
   },
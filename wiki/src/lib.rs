@@ -1,25 +1,60 @@
-use hyperware_app_common::SaveOptions;
+use hyperware_app_common::{source, SaveOptions};
 use hyperprocess_macro::hyperprocess;
-use hyperware_process_lib::{our, println, Address};
+use hyperware_process_lib::{our, println, Address, LazyLoadBlob};
+use hyperware_process_lib::http::server::{send_ws_push, WsMessageType};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use yrs::{Doc, GetString, Text, Transact, ReadTxn};
-use yrs::updates::encoder::{Encoder, EncoderV1};
+use yrs::updates::encoder::{Encode, Encoder, EncoderV1};
 use yrs::updates::decoder::Decode;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use uuid::Uuid;
 use chrono::Utc;
 
 const ICON: &str = include_str!("./icon");
 const WIKI_PROCESS_ID: (&str, &str, &str) = ("wiki", "wiki", "nick.hypr");
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+const DEFAULT_LOCK_MINUTES: i64 = 30;
+const MAX_LOCK_MINUTES: i64 = 24 * 60;
+const INVITE_VALID_DAYS: i64 = 7;
+const INVITE_RETENTION_DAYS: i64 = 30; // How long settled invites are kept after they expire
+const MAINTENANCE_INTERVAL_MINUTES: i64 = 60;
+const WIKI_DELETE_GRACE_DAYS: i64 = 30; // Deleted wikis can be restored until they're purged
+const AUDIT_PAGE_SIZE: usize = 50;
+const MAX_AUDIT_PAGE_SIZE: usize = 500;
+const RECENT_CHANGES_PAGE_SIZE: usize = 50;
+const MAX_RECENT_CHANGES_PAGE_SIZE: usize = 500;
+const VERSION_SNAPSHOT_INTERVAL: usize = 20; // Every Nth version of a page is stored in full
+const DEFAULT_DIFF_CONTEXT_LINES: usize = 3;
+const MAX_DIFF_EDITS: usize = 5000; // Beyond this, the rest of a diff is shown as replaced
+const INBOX_LIMIT: usize = 500; // Oldest watch notices are dropped past this
+const WATCH_OUTBOX_LIMIT: usize = 200; // Per unreachable watcher
+const EDIT_BASE_LIMIT: usize = 4; // Loaded states of a remote page kept to base edits on
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
 enum WikiRole {
+    #[default]
     Reader,
     Writer,
     Admin,
     SuperAdmin,
 }
 
+impl WikiRole {
+    fn rank(&self) -> u8 {
+        match self {
+            WikiRole::Reader => 0,
+            WikiRole::Writer => 1,
+            WikiRole::Admin => 2,
+            WikiRole::SuperAdmin => 3,
+        }
+    }
+
+    /// Whether this role grants everything `required` does.
+    fn includes(&self, required: &WikiRole) -> bool {
+        self.rank() >= required.rank()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct WikiMembership {
     wiki_id: String,
@@ -36,15 +71,217 @@ struct Wiki {
     created_by: String, // Node ID of creator (e.g., "alice.os")
     created_at: String,
     members: HashMap<String, WikiRole>, // Keys are node IDs (e.g., "alice.os")
+    #[serde(default)]
+    replica_nodes: Vec<String>, // Nodes holding a read-only copy of this wiki
+    #[serde(default)]
+    replica_of: Option<String>, // Set on replica copies: the node hosting the wiki
+    #[serde(default)]
+    acl: Vec<PageAclEntry>, // Page and path-prefix rules layered over `members`
+    #[serde(default)]
+    join_codes: Vec<JoinCode>, // Only shared with admins
+    #[serde(default)]
+    default_join_role: WikiRole, // Role for anyone joining a public wiki
+    #[serde(default)]
+    pending_transfer: Option<OwnershipTransfer>, // Offered to a member, awaiting their acceptance
+    #[serde(default)]
+    retention: RetentionPolicy,
+}
+
+// How much page history a wiki keeps. A version is kept if any rule keeps it;
+// with no version rules set, everything is kept.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RetentionPolicy {
+    keep_last: Option<usize>, // Newest versions of each page to keep
+    keep_days: Option<u32>, // Keep every version younger than this
+    deleted_page_days: Option<u32>, // Deleted pages are purged this long after deletion
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OwnershipTransfer {
+    wiki_id: String, // Wiki ID on the host
+    wiki_name: String,
+    from_id: String, // Current owner, who hosts the wiki
+    to_id: String,
+    requested_at: String,
+    expires_at: String,
+}
+
+// A full copy of a wiki, as sent to a replica or to a new owner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WikiSnapshot {
+    wiki: Wiki,
+    pages: Vec<WikiPage>,
+    histories: Vec<PageHistory>,
+    deleted_pages: HashMap<String, DeletedPage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JoinCode {
+    code: String,
+    role: WikiRole, // Role granted to whoever joins with the code
+    created_by: String,
+    created_at: String,
+    max_uses: Option<u32>,
+    uses: u32,
+    expires_at: Option<String>,
+}
+
+impl JoinCode {
+    fn is_usable(&self) -> bool {
+        let expired = self.expires_at.as_ref().is_some_and(|expires_at| {
+            chrono::DateTime::parse_from_rfc3339(expires_at).map_or(true, |expires_at| expires_at <= Utc::now())
+        });
+        !expired && self.max_uses.is_none_or(|max_uses| self.uses < max_uses)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct PageAclEntry {
+    pattern: String, // A page path, or a path prefix ending in '*' (e.g. "Drafts/*")
+    node_id: Option<String>, // Member the entry is for; None applies to everyone
+    role: Option<WikiRole>, // Member entries: replaces the member's wiki role on matching pages
+    read_role: Option<WikiRole>, // Everyone entries: lowest role that may read matching pages
+    write_role: Option<WikiRole>, // Everyone entries: lowest role that may edit matching pages
+}
+
+impl PageAclEntry {
+    /// How closely the entry matches `path`: exact paths beat prefixes and
+    /// longer prefixes beat shorter ones. None if it doesn't match.
+    fn specificity(&self, path: &str) -> Option<usize> {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix).then_some(prefix.len()),
+            None => (self.pattern == path).then_some(usize::MAX),
+        }
+    }
+}
+
+impl Wiki {
+    /// Checks `actor` may give `member_id` a new role (None removes them):
+    /// only SuperAdmins can change SuperAdmins or create new ones, and the
+    /// wiki must keep at least one.
+    fn check_member_change(&self, actor: &str, member_id: &str, new_role: Option<&WikiRole>) -> Result<(), String> {
+        let actor_is_super_admin = self.members.get(actor) == Some(&WikiRole::SuperAdmin);
+        let was_super_admin = self.members.get(member_id) == Some(&WikiRole::SuperAdmin);
+        let becomes_super_admin = new_role == Some(&WikiRole::SuperAdmin);
+
+        if (was_super_admin || becomes_super_admin) && !actor_is_super_admin {
+            return Err("Only a SuperAdmin can change SuperAdmin roles".to_string());
+        }
+        if was_super_admin && !becomes_super_admin {
+            let super_admins = self.members.values().filter(|role| **role == WikiRole::SuperAdmin).count();
+            if super_admins <= 1 {
+                return Err("A wiki must keep at least one SuperAdmin".to_string());
+            }
+        }
+        if member_id == self.created_by && new_role.is_none() {
+            return Err("Transfer ownership before removing the owner".to_string());
+        }
+        Ok(())
+    }
+
+    /// Adds `node_id` to a public wiki with its default join role. Existing
+    /// members keep their role if it's already at least that.
+    fn join_public(&mut self, node_id: &str) -> WikiRole {
+        match self.members.get(node_id) {
+            Some(role) if role.includes(&self.default_join_role) => role.clone(),
+            _ => {
+                self.members.insert(node_id.to_string(), self.default_join_role.clone());
+                self.default_join_role.clone()
+            }
+        }
+    }
+
+    /// Adds `node_id` as a member using a join code, consuming one use of it.
+    /// Existing members keep their role if it's already at least the code's.
+    fn join_with_code(&mut self, node_id: &str, code: &str) -> Result<WikiRole, String> {
+        let join_code = self.join_codes.iter_mut()
+            .find(|join_code| join_code.code == code && join_code.is_usable())
+            .ok_or_else(|| "Invalid or expired join code".to_string())?;
+
+        if let Some(role) = self.members.get(node_id) {
+            if role.includes(&join_code.role) {
+                return Ok(role.clone());
+            }
+        }
+
+        join_code.uses += 1;
+        let role = join_code.role.clone();
+        self.members.insert(node_id.to_string(), role.clone());
+        Ok(role)
+    }
+
+    /// Picks a value from the most specific ACL entry matching `path` that has one.
+    fn acl_lookup<T>(&self, path: &str, select: impl Fn(&PageAclEntry) -> Option<T>) -> Option<T> {
+        self.acl.iter()
+            .filter_map(|entry| Some((entry.specificity(path)?, select(entry)?)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, value)| value)
+    }
+
+    /// The role a member holds on one page, after member-specific ACL entries.
+    fn page_role(&self, node_id: &str, path: &str) -> Option<WikiRole> {
+        let wiki_role = self.members.get(node_id)?;
+        let override_role = self.acl_lookup(path, |entry| {
+            if entry.node_id.as_deref() == Some(node_id) { entry.role.clone() } else { None }
+        });
+        Some(override_role.unwrap_or_else(|| wiki_role.clone()))
+    }
+
+    /// Checks `node_id` may access `path` with `required_role` (Reader to read,
+    /// Writer or above to change it) once the ACL is applied.
+    fn check_page_access(&self, node_id: &str, path: &str, required_role: WikiRole) -> Result<(), String> {
+        // Admins maintain the ACL, so it never binds them
+        if self.members.get(node_id).is_some_and(|role| role.includes(&WikiRole::Admin)) {
+            return Ok(());
+        }
+
+        let read_role = self.acl_lookup(path, |entry| {
+            if entry.node_id.is_none() { entry.read_role.clone() } else { None }
+        }).unwrap_or(WikiRole::Reader);
+        let required = if required_role == WikiRole::Reader {
+            read_role
+        } else {
+            let write_role = self.acl_lookup(path, |entry| {
+                if entry.node_id.is_none() { entry.write_role.clone() } else { None }
+            }).unwrap_or(WikiRole::Writer);
+            [required_role, read_role, write_role].into_iter()
+                .max_by_key(WikiRole::rank)
+                .unwrap_or(WikiRole::Writer)
+        };
+
+        match self.page_role(node_id, path) {
+            Some(role) if role.includes(&required) => Ok(()),
+            Some(_) => Err("Insufficient permissions for this page".to_string()),
+            // Public wikis are readable without joining
+            None if self.is_public && required == WikiRole::Reader => Ok(()),
+            None => Err("Not a member of this wiki".to_string()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct PageVersion {
     version_id: String, // UUID for the version
-    content: Vec<u8>,
+    content: Vec<u8>, // yrs v1 update: the full state, or a delta if `delta` is set
+    #[serde(default)]
+    delta: bool, // Only what changed since the previous version; see PageHistory::version_doc
     updated_by: String, // Node ID of updater (e.g., "alice.os")
     updated_at: String,
     commit_message: Option<String>, // Optional commit message describing the change
+    #[serde(default)]
+    change: PageChange,
+    #[serde(default)]
+    tag: Option<String>, // Tagged versions survive retention pruning
+}
+
+// What a version did to its page
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+enum PageChange {
+    Created,
+    #[default]
+    Edited,
+    Renamed { from: String }, // Edited, and the new title moved the page
+    Restored { deleted_at: String, deleted_by: String }, // Brought back after a deletion
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,6 +290,24 @@ struct WikiPage {
     wiki_id: String,
     current_version: PageVersion,
     yrs_doc: Vec<u8>,
+    #[serde(default)]
+    protected: bool, // Only Admin/SuperAdmin may edit
+    #[serde(default)]
+    lock: Option<PageLock>, // Edit lock held by one user; ignored once expired
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct PageLock {
+    node_id: String,
+    acquired_at: String,
+    expires_at: String,
+}
+
+impl PageLock {
+    fn is_active(&self) -> bool {
+        chrono::DateTime::parse_from_rfc3339(&self.expires_at)
+            .is_ok_and(|expires_at| expires_at > Utc::now())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,6 +316,203 @@ struct PageHistory {
     wiki_id: String,
     versions: Vec<PageVersion>, // All versions, ordered from oldest to newest
     current_version_id: String, // ID of the current version
+    #[serde(default)]
+    head_state_vector: Vec<u8>, // State vector of the newest version; empty for histories not yet compacted
+}
+
+impl PageHistory {
+    /// Appends a version holding `doc`'s current state. Every
+    /// VERSION_SNAPSHOT_INTERVAL versions it's stored in full, otherwise as the
+    /// changes since the previous version.
+    fn record_version(&mut self, mut version: PageVersion, doc: &Doc) -> PageVersion {
+        let txn = doc.transact();
+        let previous = Some(&self.head_state_vector)
+            .filter(|state_vector| !state_vector.is_empty() && !self.versions.len().is_multiple_of(VERSION_SNAPSHOT_INTERVAL))
+            .and_then(|state_vector| yrs::StateVector::decode_v1(state_vector).ok());
+        match previous {
+            Some(state_vector) => {
+                version.content = txn.encode_diff_v1(&state_vector);
+                version.delta = true;
+            }
+            None => {
+                version.content = txn.encode_state_as_update_v1(&yrs::StateVector::default());
+                version.delta = false;
+            }
+        }
+        self.head_state_vector = txn.state_vector().encode_v1();
+        self.current_version_id = version.version_id.clone();
+        self.versions.push(version.clone());
+        version
+    }
+
+    /// Rebuilds the version at `index` from the nearest full snapshot before it.
+    fn version_doc(&self, index: usize) -> Result<Doc, String> {
+        let versions = self.versions.get(..=index)
+            .ok_or_else(|| "Version not found".to_string())?;
+        let start = versions.iter().rposition(|version| !version.delta)
+            .ok_or_else(|| "Version history has no snapshot".to_string())?;
+        let doc = Doc::new();
+        for version in &versions[start..] {
+            Self::apply_version(&doc, version)?;
+        }
+        Ok(doc)
+    }
+
+    fn version_text(&self, index: usize) -> Result<String, String> {
+        let doc = self.version_doc(index)?;
+        let text = doc.get_or_insert_text("content");
+        let content = text.get_string(&doc.transact());
+        Ok(content)
+    }
+
+    /// The text of every version, oldest first, replayed in a single pass.
+    fn version_texts(&self) -> Vec<Result<String, String>> {
+        let mut doc = Doc::new();
+        self.versions.iter()
+            .map(|version| {
+                if !version.delta {
+                    doc = Doc::new();
+                }
+                Self::apply_version(&doc, version)?;
+                let text = doc.get_or_insert_text("content");
+                let content = text.get_string(&doc.transact());
+                Ok(content)
+            })
+            .collect()
+    }
+
+    fn apply_version(doc: &Doc, version: &PageVersion) -> Result<(), String> {
+        let update = yrs::Update::decode_v1(&version.content)
+            .map_err(|_| "Failed to decode update".to_string())?;
+        doc.transact_mut().apply_update(update)
+            .map_err(|e| format!("Failed to apply update: {}", e))
+    }
+
+    /// Drops the versions `keep` turns down, always keeping the newest one, and
+    /// re-encodes the rest so the delta chain starts from a full snapshot again.
+    /// Returns how many versions were dropped.
+    fn prune(&mut self, keep: impl Fn(usize, &PageVersion) -> bool) -> usize {
+        let newest = self.versions.len().saturating_sub(1);
+        let kept: Vec<bool> = self.versions.iter().enumerate()
+            .map(|(index, version)| index == newest || keep(index, version))
+            .collect();
+        let dropped = kept.iter().filter(|kept| !**kept).count();
+        if dropped == 0 {
+            return 0;
+        }
+
+        // Rebuild each kept version in full before anything is removed, so a
+        // history that can't be replayed is left alone
+        let mut replay = Doc::new();
+        let mut rebuilt = Vec::new();
+        for (version, kept) in self.versions.iter().zip(&kept) {
+            if !version.delta {
+                replay = Doc::new();
+            }
+            if Self::apply_version(&replay, version).is_err() {
+                return 0;
+            }
+            if *kept {
+                let mut version = version.clone();
+                version.content = replay.transact().encode_state_as_update_v1(&yrs::StateVector::default());
+                version.delta = false;
+                rebuilt.push(version);
+            }
+        }
+
+        let current_version_id = self.current_version_id.clone();
+        self.versions.clear();
+        self.head_state_vector.clear();
+        for version in rebuilt {
+            let doc = Doc::new();
+            if Self::apply_version(&doc, &version).is_ok() {
+                self.record_version(version, &doc);
+            }
+        }
+        self.current_version_id = current_version_id;
+        dropped
+    }
+
+    /// Re-encodes a history saved as full snapshots, or partly as deltas, into
+    /// deltas. A version whose delta wouldn't replay to the same text stays a
+    /// snapshot.
+    fn compact(&mut self) {
+        let current_version_id = self.current_version_id.clone();
+        let versions = std::mem::take(&mut self.versions);
+        self.head_state_vector.clear();
+
+        // `saved` replays the versions as they were stored, `replay` as re-encoded
+        let mut saved = Doc::new();
+        let mut replay = Doc::new();
+        for version in versions {
+            if !version.delta {
+                saved = Doc::new();
+            }
+            if Self::apply_version(&saved, &version).is_err() {
+                // Keep what we can't read as it is, and start over after it
+                self.versions.push(version);
+                self.head_state_vector.clear();
+                continue;
+            }
+            let expected = saved.get_or_insert_text("content").get_string(&saved.transact());
+
+            let recorded = self.record_version(version.clone(), &saved);
+            let replayed = recorded.delta && Self::apply_version(&replay, &recorded).is_ok()
+                && replay.get_or_insert_text("content").get_string(&replay.transact()) == expected;
+            if !replayed {
+                if let Some(last) = self.versions.last_mut() {
+                    last.content = saved.transact().encode_state_as_update_v1(&yrs::StateVector::default());
+                    last.delta = false;
+                    replay = Doc::new();
+                    let _ = Self::apply_version(&replay, last);
+                }
+            }
+        }
+        self.current_version_id = current_version_id;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DeletedWiki {
+    wiki: Wiki, // Its pages stay in place until the wiki is purged
+    deleted_at: String,
+    deleted_by: String,
+    purge_at: String,
+}
+
+// One entry in a wiki's append-only audit log, kept by the wiki's host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    id: String,
+    timestamp: String,
+    actor: String, // Node that performed the action
+    action: AuditAction,
+    target: Option<String>, // Member node ID or page path, depending on the action
+    details: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+enum AuditAction {
+    MemberAdded,
+    MemberRemoved,
+    MemberRoleChanged,
+    MemberJoined,
+    MemberLeft,
+    OwnershipTransferred,
+    SettingsUpdated,
+    WikiDeleted,
+    WikiRestored,
+    InviteSent,
+    InviteRevoked,
+    InviteAccepted,
+    InviteRejected,
+    JoinRequestApproved,
+    JoinRequestDenied,
+    PageCreated,
+    PageUpdated,
+    PageDeleted,
+    PageRestored,
+    HistoryPurged,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -82,6 +534,8 @@ struct WikiInvite {
     created_at: String,
     expires_at: String,
     status: InviteStatus,
+    #[serde(default)]
+    role: WikiRole, // Role granted when the invite is accepted
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -90,6 +544,101 @@ enum InviteStatus {
     Accepted,
     Rejected,
     Expired,
+    Revoked,
+}
+
+// A non-member asking a wiki's admins for access; kept on both the host and the requester
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JoinRequest {
+    id: String,
+    wiki_id: String, // Wiki ID on the host
+    wiki_name: String,
+    host_id: String, // Node hosting the wiki
+    requester_id: String,
+    message: Option<String>,
+    created_at: String,
+    status: JoinRequestStatus,
+    role: Option<WikiRole>, // Set when approved
+    decided_by: Option<String>,
+    decided_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+enum JoinRequestStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+// A page, or a whole wiki when `path` is None, that we want change notices for
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct PageWatch {
+    wiki_id: String, // As we know it (may be "wiki_id@node_id")
+    path: Option<String>,
+    created_at: String,
+}
+
+// A remote node watching a wiki we host
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct PageWatcher {
+    node_id: String,
+    path: Option<String>, // None watches the whole wiki
+}
+
+// An entry in our notifications inbox
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WatchNotice {
+    id: String,
+    wiki_id: String, // As we know it (may be "wiki_id@node_id")
+    change: RecentChange,
+    received_at: String,
+    read: bool,
+}
+
+// Local copy of a remote wiki, kept so it stays readable while the host is offline
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct WikiReplica {
+    wiki: Option<Wiki>, // Last wiki data fetched from the host
+    page_list: Vec<PageSummary>,
+    pages: HashMap<String, ReplicaPage>, // Key: page path
+    pending_edits: Vec<PendingEdit>, // Edits the host hasn't accepted yet, oldest first
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ReplicaPage {
+    yrs_doc: Vec<u8>, // Host state merged with our pending edits
+    updated_by: String,
+    updated_at: String,
+    history: Option<DecodedPageHistory>,
+    #[serde(default)]
+    protected: bool,
+    #[serde(default)]
+    lock: Option<PageLock>,
+    #[serde(default)]
+    edit_bases: Vec<EditBase>, // States the page was served at, oldest first
+}
+
+// The state a remote page was read at, so an edit made from it can be expressed
+// against what the user actually saw
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EditBase {
+    state_vector: String, // Base64 yrs state vector, handed out as PageInfo::base
+    yrs_doc: Vec<u8>,
+}
+
+// Why a host turned down a page sync
+#[derive(Debug)]
+enum SyncRefusal {
+    Rejected(String),
+    Unsupported, // The host predates state-vector sync
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingEdit {
+    path: String, // Page path on the host when the edit was made
+    update: Vec<u8>, // yrs v1 update
+    commit_message: Option<String>,
+    queued_at: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -103,6 +652,32 @@ pub struct WikiState {
     invites: HashMap<String, WikiInvite>,
     #[serde(skip)]
     active_docs: HashMap<String, Doc>,
+    #[serde(skip)]
+    ws_subscriptions: HashMap<u32, Vec<WsSubscription>>, // Key: WebSocket channel ID
+    #[serde(skip)]
+    edit_sessions: HashMap<String, HashSet<u32>>, // Key: "wiki_id:path", value: editing channels
+    #[serde(default)]
+    replicas: HashMap<String, WikiReplica>, // Key: "wiki_id@node_id"
+    #[serde(default)]
+    replication_outbox: HashMap<String, Vec<WikiMessage>>, // Key: "wiki_id@replica_node_id", oldest first
+    #[serde(default)]
+    last_maintenance_at: Option<String>,
+    #[serde(default)]
+    join_requests: HashMap<String, JoinRequest>, // Requests for our wikis, and ones we sent
+    #[serde(default)]
+    ownership_offers: HashMap<String, OwnershipTransfer>, // Offers made to us; key: "wiki_id@from_id"
+    #[serde(default)]
+    deleted_wikis: HashMap<String, DeletedWiki>, // Soft-deleted wikis we host, by wiki ID
+    #[serde(default)]
+    audit_log: HashMap<String, Vec<AuditEntry>>, // Key: ID of a wiki we host, oldest first
+    #[serde(default)]
+    watches: Vec<PageWatch>, // Pages and wikis we watch
+    #[serde(default)]
+    watchers: HashMap<String, Vec<PageWatcher>>, // Remote watchers of wikis we host, by wiki ID
+    #[serde(default)]
+    inbox: Vec<WatchNotice>, // Oldest first
+    #[serde(default)]
+    watch_outbox: HashMap<String, Vec<WikiMessage>>, // PageChanged notices by watcher node, oldest first
 }
 
 #[derive(Deserialize)]
@@ -124,6 +699,59 @@ struct JoinWikiRequest {
     node_id: Option<String>, // Node where the wiki exists (for remote wikis)
 }
 
+#[derive(Deserialize)]
+struct CreateJoinCodeRequest {
+    wiki_id: String,
+    role: WikiRole,
+    max_uses: Option<u32>,
+    expires_in_hours: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct RevokeJoinCodeRequest {
+    wiki_id: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct TransferOwnershipRequest {
+    wiki_id: String,
+    new_owner_id: Option<String>, // None cancels a pending transfer
+}
+
+#[derive(Deserialize)]
+struct RespondToOwnershipOfferRequest {
+    wiki_id: String, // Remote reference: "wiki_id@from_id"
+    accept: bool,
+}
+
+#[derive(Deserialize)]
+struct DeleteWikiRequest {
+    wiki_id: String,
+}
+
+#[derive(Deserialize)]
+struct RestoreWikiRequest {
+    wiki_id: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct AuditLogQuery {
+    actor: Option<String>,
+    action: Option<AuditAction>,
+    since: Option<String>, // RFC 3339, inclusive
+    until: Option<String>, // RFC 3339, exclusive
+    offset: Option<usize>,
+    limit: Option<usize>, // Defaults to AUDIT_PAGE_SIZE
+}
+
+#[derive(Deserialize)]
+struct GetAuditLogRequest {
+    wiki_id: String,
+    #[serde(flatten)]
+    query: AuditLogQuery,
+}
+
 #[derive(Deserialize)]
 struct LeaveWikiRequest {
     wiki_id: String,
@@ -135,6 +763,8 @@ struct UpdateWikiRequest {
     name: Option<String>,
     description: Option<String>,
     is_public: Option<bool>,
+    default_join_role: Option<WikiRole>,
+    retention: Option<RetentionPolicy>, // Replaces the whole policy
 }
 
 #[derive(Deserialize)]
@@ -145,6 +775,35 @@ struct ManageMemberRequest {
     role: Option<WikiRole>,
 }
 
+#[derive(Deserialize)]
+struct ManageReplicaRequest {
+    wiki_id: String,
+    node_id: String, // Node to hold the replica (e.g., "carol.os")
+    action: String,
+}
+
+#[derive(Deserialize)]
+struct ManagePageAclRequest {
+    wiki_id: String,
+    action: String, // "set" adds or replaces the entry for its pattern and node; "remove" deletes it
+    entry: PageAclEntry,
+}
+
+#[derive(Deserialize)]
+struct LockPageRequest {
+    wiki_id: String,
+    path: String,
+    action: String, // "acquire" (or extend), "release", or "break" (Admin only)
+    duration_minutes: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ProtectPageRequest {
+    wiki_id: String,
+    path: String,
+    protected: bool,
+}
+
 #[derive(Deserialize)]
 struct CreatePageRequest {
     wiki_id: String,
@@ -159,6 +818,8 @@ struct UpdatePageRequest {
     path: String,
     content: String,
     commit_message: Option<String>,
+    #[serde(default)]
+    base: Option<String>, // PageInfo::base the edit started from; defaults to the last one served
 }
 
 #[derive(Deserialize)]
@@ -212,19 +873,103 @@ struct ListDeletedPagesRequest {
     wiki_id: String,
 }
 
+#[derive(Deserialize)]
+struct RecentChangesRequest {
+    wiki_id: String,
+    offset: Option<usize>,
+    limit: Option<usize>, // Defaults to RECENT_CHANGES_PAGE_SIZE
+}
+
+#[derive(Deserialize)]
+struct WatchPageRequest {
+    wiki_id: String,
+    path: Option<String>, // None watches the whole wiki
+    watch: bool,
+}
+
+#[derive(Deserialize)]
+struct MarkNoticesReadRequest {
+    notice_ids: Option<Vec<String>>, // None marks the whole inbox read
+}
+
+#[derive(Deserialize)]
+struct RevertPageRequest {
+    wiki_id: String,
+    path: String,
+    version_id: String,
+}
+
+#[derive(Deserialize)]
+struct TagVersionRequest {
+    wiki_id: String,
+    path: String,
+    version_id: String,
+    tag: Option<String>, // None or blank removes the tag
+}
+
+#[derive(Deserialize)]
+struct PurgeHistoryRequest {
+    wiki_id: String,
+}
+
+#[derive(Deserialize)]
+struct GetPageBlameRequest {
+    wiki_id: String,
+    path: String,
+}
+
 #[derive(Deserialize)]
 struct GetVersionDiffRequest {
     wiki_id: String,
     path: String,
     version1_id: String,
     version2_id: String,
+    #[serde(flatten)]
+    options: DiffOptions,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct VersionDiff {
-    version1_id: String,
-    version2_id: String,
-    diff_lines: Vec<DiffLine>,
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct DiffOptions {
+    #[serde(default)]
+    granularity: DiffGranularity,
+    #[serde(default)]
+    context_lines: Option<usize>, // Unchanged lines around each hunk; defaults to DEFAULT_DIFF_CONTEXT_LINES
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+enum DiffGranularity {
+    #[default]
+    Line,
+    Word, // Also highlights changed words within replaced lines
+    Char, // Also highlights changed characters within replaced lines
+}
+
+// Who last introduced each line of a page's current version
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PageBlame {
+    path: String,
+    version_id: String, // The version being blamed
+    lines: Vec<BlameLine>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlameLine {
+    line_number: usize,
+    content: String,
+    version_id: String, // Version that introduced the line
+    author: String,
+    timestamp: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VersionDiff {
+    version1_id: String,
+    version2_id: String,
+    diff_lines: Vec<DiffLine>,
+    #[serde(default)]
+    hunks: Vec<DiffHunk>,
+    #[serde(default)]
+    unified: String, // Unified diff text; empty when the versions match
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -233,6 +978,32 @@ struct DiffLine {
     content: String,
     line_number_old: Option<usize>,
     line_number_new: Option<usize>,
+    #[serde(default)]
+    segments: Vec<DiffSegment>, // Intra-line changes; empty at line granularity or for unpaired lines
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DiffSegment {
+    text: String,
+    changed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DiffHunk {
+    old_start: usize,
+    old_lines: usize,
+    new_start: usize,
+    new_lines: usize,
+    first_line: usize, // Index into `diff_lines`
+    line_count: usize,
+}
+
+// One step of an edit script, by index into the old and new sequences
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -247,6 +1018,8 @@ enum WikiMessage {
     FindWikisByUser { username: String },
     GetPublicWiki { wiki_id: String },
     JoinPublicWiki { wiki_id: String, user_id: String },
+    JoinWithCode { wiki_id: String, user_id: String, join_code: String },
+    LeaveWiki { wiki_id: String, user_id: String },
     GetWikiData { wiki_id: String },
     GetWikiPages { wiki_id: String },
     GetWikiPage { wiki_id: String, path: String },
@@ -256,11 +1029,194 @@ enum WikiMessage {
     GetPageHistory { wiki_id: String, path: String },
     RestoreDeletedPage { wiki_id: String, path: String, deleted_key: String, user_id: String },
     ListDeletedPages { wiki_id: String },
-    GetVersionDiff { wiki_id: String, path: String, version1_id: String, version2_id: String },
+    GetVersionDiff { wiki_id: String, path: String, version1_id: String, version2_id: String, #[serde(default)] options: DiffOptions },
+    GetPageBlame { wiki_id: String, path: String },
+    GetRecentChanges { wiki_id: String, offset: Option<usize>, limit: Option<usize> },
     SendInvite { invite: WikiInvite, wiki: Wiki },
     InviteResponse { invite_id: String, status: InviteStatus, invitee_id: String },
+    RevokeInvite { invite_id: String, inviter_id: String },
+    RequestToJoin { request: JoinRequest },
+    JoinRequestDecision { request: JoinRequest, wiki: Option<Wiki> }, // `wiki` is sent on approval
+    OfferOwnership { transfer: OwnershipTransfer },
+    CancelOwnershipOffer { wiki_id: String, from_id: String },
+    RespondToOwnershipOffer { wiki_id: String, user_id: String, accept: bool },
+    GetAuditLog { wiki_id: String, query: AuditLogQuery },
+    WatchPage { wiki_id: String, path: Option<String>, user_id: String, watch: bool },
+    // From a wiki's host to nodes watching the changed page
+    PageChanged { wiki_id: String, host_id: String, change: RecentChange },
+    // From a wiki's host to members and replica nodes
+    MembershipRevoked { wiki_id: String, host_id: String },
+    WikiDeleted { wiki_id: String, host_id: String },
+    WikiRestored { wiki: Wiki, host_id: String },
+    WikiMoved { wiki_id: String, host_id: String, new_host_id: String }, // Its new owner hosts the wiki now
     RoleUpdate { wiki_id: String, member_id: String, new_role: WikiRole },
     SearchPages { wiki_id: String, query: String },
+    // State-vector sync: the host answers with the updates we're missing and its own state vector
+    SyncPage { wiki_id: String, path: String, state_vector: Vec<u8> },
+    ApplyPageUpdate { wiki_id: String, path: String, update: Vec<u8>, user_id: String, commit_message: Option<String> },
+    LockPage { wiki_id: String, path: String, user_id: String, action: String, duration_minutes: Option<i64> },
+    ProtectPage { wiki_id: String, path: String, user_id: String, protected: bool },
+    RevertPage { wiki_id: String, path: String, version_id: String, user_id: String },
+    TagVersion { wiki_id: String, path: String, version_id: String, tag: Option<String>, user_id: String },
+    // Replication from a wiki's host to its replica nodes
+    ReplicateWiki { wiki: Wiki, pages: Vec<WikiPage>, histories: Vec<PageHistory>, deleted_pages: HashMap<String, DeletedPage> },
+    ReplicateMembers { wiki: Wiki },
+    ReplicatePage { wiki_id: String, path: String, previous_path: Option<String>, update: Vec<u8>, version: PageVersion },
+    ReplicatePageDeleted { deleted_key: String, deleted_page: DeletedPage },
+}
+
+impl WikiMessage {
+    fn required_access(&self) -> RemoteAccess<'_> {
+        match self {
+            WikiMessage::FindWikisByUser { .. }
+            | WikiMessage::GetPublicWiki { .. }
+            | WikiMessage::JoinPublicWiki { .. }
+            | WikiMessage::JoinWithCode { .. }
+            | WikiMessage::LeaveWiki { .. }
+            | WikiMessage::SendInvite { .. }
+            | WikiMessage::InviteResponse { .. }
+            | WikiMessage::RevokeInvite { .. }
+            | WikiMessage::RequestToJoin { .. }
+            | WikiMessage::JoinRequestDecision { .. }
+            | WikiMessage::OfferOwnership { .. }
+            | WikiMessage::CancelOwnershipOffer { .. }
+            | WikiMessage::MembershipRevoked { .. }
+            | WikiMessage::WikiDeleted { .. }
+            | WikiMessage::WikiRestored { .. }
+            | WikiMessage::WikiMoved { .. }
+            | WikiMessage::PageChanged { .. } => RemoteAccess::Anyone,
+            // Only the member named in the pending transfer can answer it
+            WikiMessage::RespondToOwnershipOffer { wiki_id, .. } => RemoteAccess::Reader(wiki_id),
+            // Page lists are filtered per page by their handlers
+            WikiMessage::GetWikiData { wiki_id }
+            | WikiMessage::GetWikiPages { wiki_id }
+            | WikiMessage::ListDeletedPages { wiki_id }
+            | WikiMessage::GetRecentChanges { wiki_id, .. }
+            | WikiMessage::SearchPages { wiki_id, .. } => RemoteAccess::Reader(wiki_id),
+            // Notices are only sent for pages the watcher can read
+            WikiMessage::WatchPage { wiki_id, .. } => RemoteAccess::Reader(wiki_id),
+            WikiMessage::GetWikiPage { wiki_id, path }
+            | WikiMessage::GetPageHistory { wiki_id, path }
+            | WikiMessage::GetVersionDiff { wiki_id, path, .. }
+            | WikiMessage::GetPageBlame { wiki_id, path }
+            | WikiMessage::SyncPage { wiki_id, path, .. } => RemoteAccess::Page(wiki_id, path, WikiRole::Reader),
+            WikiMessage::CreatePage { wiki_id, path, .. }
+            | WikiMessage::UpdatePage { wiki_id, path, .. }
+            | WikiMessage::DeletePage { wiki_id, path, .. }
+            | WikiMessage::RestoreDeletedPage { wiki_id, path, .. }
+            | WikiMessage::ApplyPageUpdate { wiki_id, path, .. }
+            | WikiMessage::LockPage { wiki_id, path, .. }
+            | WikiMessage::RevertPage { wiki_id, path, .. }
+            | WikiMessage::TagVersion { wiki_id, path, .. } => RemoteAccess::Page(wiki_id, path, WikiRole::Writer),
+            WikiMessage::ProtectPage { wiki_id, path, .. } => RemoteAccess::Page(wiki_id, path, WikiRole::Admin),
+            WikiMessage::GetAuditLog { wiki_id, .. } => RemoteAccess::Admin(wiki_id),
+            WikiMessage::RoleUpdate { wiki_id, .. } => RemoteAccess::WikiHost(wiki_id),
+            WikiMessage::ReplicateWiki { wiki, .. } => RemoteAccess::NewReplica(wiki),
+            WikiMessage::ReplicateMembers { wiki } => RemoteAccess::ReplicaHost(&wiki.id),
+            WikiMessage::ReplicatePage { wiki_id, .. } => RemoteAccess::ReplicaHost(wiki_id),
+            WikiMessage::ReplicatePageDeleted { deleted_page, .. } => RemoteAccess::ReplicaHost(&deleted_page.wiki_id),
+        }
+    }
+
+    /// The node a message claims to act for, which must be the node that sent it.
+    fn claimed_user_id(&self) -> Option<&str> {
+        match self {
+            WikiMessage::JoinPublicWiki { user_id, .. }
+            | WikiMessage::JoinWithCode { user_id, .. }
+            | WikiMessage::LeaveWiki { user_id, .. }
+            | WikiMessage::CreatePage { user_id, .. }
+            | WikiMessage::UpdatePage { user_id, .. }
+            | WikiMessage::DeletePage { user_id, .. }
+            | WikiMessage::RestoreDeletedPage { user_id, .. }
+            | WikiMessage::ApplyPageUpdate { user_id, .. }
+            | WikiMessage::LockPage { user_id, .. }
+            | WikiMessage::ProtectPage { user_id, .. }
+            | WikiMessage::RevertPage { user_id, .. }
+            | WikiMessage::TagVersion { user_id, .. }
+            | WikiMessage::WatchPage { user_id, .. } => Some(user_id),
+            WikiMessage::SendInvite { invite, .. } => Some(&invite.inviter_id),
+            WikiMessage::InviteResponse { invitee_id, .. } => Some(invitee_id),
+            WikiMessage::RevokeInvite { inviter_id, .. } => Some(inviter_id),
+            WikiMessage::RequestToJoin { request } => Some(&request.requester_id),
+            WikiMessage::JoinRequestDecision { request, .. } => Some(&request.host_id),
+            WikiMessage::OfferOwnership { transfer } => Some(&transfer.from_id),
+            WikiMessage::CancelOwnershipOffer { from_id, .. } => Some(from_id),
+            WikiMessage::RespondToOwnershipOffer { user_id, .. } => Some(user_id),
+            WikiMessage::MembershipRevoked { host_id, .. }
+            | WikiMessage::WikiDeleted { host_id, .. }
+            | WikiMessage::WikiRestored { host_id, .. }
+            | WikiMessage::WikiMoved { host_id, .. }
+            | WikiMessage::PageChanged { host_id, .. } => Some(host_id),
+            WikiMessage::FindWikisByUser { .. }
+            | WikiMessage::GetPublicWiki { .. }
+            | WikiMessage::GetWikiData { .. }
+            | WikiMessage::GetWikiPages { .. }
+            | WikiMessage::GetWikiPage { .. }
+            | WikiMessage::GetPageHistory { .. }
+            | WikiMessage::ListDeletedPages { .. }
+            | WikiMessage::GetVersionDiff { .. }
+            | WikiMessage::GetPageBlame { .. }
+            | WikiMessage::GetRecentChanges { .. }
+            | WikiMessage::GetAuditLog { .. }
+            | WikiMessage::RoleUpdate { .. }
+            | WikiMessage::SearchPages { .. }
+            | WikiMessage::SyncPage { .. }
+            | WikiMessage::ReplicateWiki { .. }
+            | WikiMessage::ReplicateMembers { .. }
+            | WikiMessage::ReplicatePage { .. }
+            | WikiMessage::ReplicatePageDeleted { .. } => None,
+        }
+    }
+
+    /// The wiki a message modifies, if any. Replica copies refuse these.
+    fn written_wiki_id(&self) -> Option<&str> {
+        match self {
+            WikiMessage::JoinPublicWiki { wiki_id, .. }
+            | WikiMessage::JoinWithCode { wiki_id, .. }
+            | WikiMessage::LeaveWiki { wiki_id, .. }
+            | WikiMessage::CreatePage { wiki_id, .. }
+            | WikiMessage::UpdatePage { wiki_id, .. }
+            | WikiMessage::DeletePage { wiki_id, .. }
+            | WikiMessage::RestoreDeletedPage { wiki_id, .. }
+            | WikiMessage::ApplyPageUpdate { wiki_id, .. }
+            | WikiMessage::LockPage { wiki_id, .. }
+            | WikiMessage::ProtectPage { wiki_id, .. }
+            | WikiMessage::RevertPage { wiki_id, .. }
+            | WikiMessage::TagVersion { wiki_id, .. }
+            | WikiMessage::WatchPage { wiki_id, .. }
+            | WikiMessage::RespondToOwnershipOffer { wiki_id, .. } => Some(wiki_id),
+            WikiMessage::FindWikisByUser { .. }
+            | WikiMessage::GetPublicWiki { .. }
+            | WikiMessage::GetWikiData { .. }
+            | WikiMessage::GetWikiPages { .. }
+            | WikiMessage::GetWikiPage { .. }
+            | WikiMessage::GetPageHistory { .. }
+            | WikiMessage::ListDeletedPages { .. }
+            | WikiMessage::GetVersionDiff { .. }
+            | WikiMessage::GetPageBlame { .. }
+            | WikiMessage::GetRecentChanges { .. }
+            | WikiMessage::GetAuditLog { .. }
+            | WikiMessage::SendInvite { .. }
+            | WikiMessage::InviteResponse { .. }
+            | WikiMessage::RevokeInvite { .. }
+            | WikiMessage::RequestToJoin { .. }
+            | WikiMessage::JoinRequestDecision { .. }
+            | WikiMessage::OfferOwnership { .. }
+            | WikiMessage::CancelOwnershipOffer { .. }
+            | WikiMessage::MembershipRevoked { .. }
+            | WikiMessage::WikiDeleted { .. }
+            | WikiMessage::WikiRestored { .. }
+            | WikiMessage::WikiMoved { .. }
+            | WikiMessage::PageChanged { .. }
+            | WikiMessage::RoleUpdate { .. }
+            | WikiMessage::SearchPages { .. }
+            | WikiMessage::SyncPage { .. }
+            | WikiMessage::ReplicateWiki { .. }
+            | WikiMessage::ReplicateMembers { .. }
+            | WikiMessage::ReplicatePage { .. }
+            | WikiMessage::ReplicatePageDeleted { .. } => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -275,10 +1231,37 @@ enum WikiResponse {
     DeletedPagesList(Vec<DeletedPageSummary>),
     SearchResults(Vec<SearchResult>),
     VersionDiff(VersionDiff),
+    PageBlame(PageBlame),
+    RecentChanges(RecentChangesPage),
+    PageSync { update: Vec<u8>, state_vector: Vec<u8> },
+    JoinedWiki { role: WikiRole },
+    WikiHandedOver { snapshot: WikiSnapshot, watchers: Vec<PageWatcher> },
+    AuditLog(AuditLogPage),
+    PermissionDenied(PermissionError),
+    UnsupportedMessage(String), // The receiver couldn't parse the message, e.g. it predates it
     Success(bool),
     Error(String),
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PermissionError {
+    wiki_id: String,
+    node_id: String, // The node that was refused
+    required_role: Option<WikiRole>, // None when the message isn't open to members at all
+    reason: String,
+}
+
+// What a remote message requires of its sender; see `WikiState::authorize`
+enum RemoteAccess<'a> {
+    Anyone, // Discovery and invites; the handler checks the rest
+    Reader(&'a str), // Members, or anyone if the wiki is public
+    Page(&'a str, &'a str, WikiRole), // A page, with this role after the wiki's ACL
+    Admin(&'a str), // Admins of a wiki
+    WikiHost(&'a str), // The node hosting a wiki we're a member of
+    ReplicaHost(&'a str), // The node hosting our replica copy of a wiki
+    NewReplica(&'a Wiki), // The host of a wiki that lists us as a replica
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct DeletedPageSummary {
     path: String,
@@ -291,6 +1274,41 @@ struct DeletedPageSummary {
 struct InviteUserRequest {
     wiki_id: String,
     invitee_id: String, // Node ID (e.g., "alice.os")
+    role: Option<WikiRole>, // Defaults to the wiki's default_join_role
+}
+
+#[derive(Deserialize)]
+struct ListSentInvitesRequest {
+    wiki_id: String,
+}
+
+#[derive(Deserialize)]
+struct RevokeInviteRequest {
+    invite_id: String,
+}
+
+#[derive(Deserialize)]
+struct ResendInviteRequest {
+    invite_id: String,
+}
+
+#[derive(Deserialize)]
+struct RequestToJoinRequest {
+    wiki_id: String,
+    node_id: String, // Node hosting the wiki
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListJoinRequestsRequest {
+    wiki_id: String,
+}
+
+#[derive(Deserialize)]
+struct RespondToJoinRequestRequest {
+    request_id: String,
+    approve: bool,
+    role: Option<WikiRole>, // Defaults to the wiki's default_join_role
 }
 
 #[derive(Deserialize)]
@@ -307,6 +1325,71 @@ enum WsNotification {
     PageListUpdated { wiki_id: String },
     PageUpdated { wiki_id: String, path: String },
     RoleUpdated { wiki_id: String, new_role: WikiRole },
+    InvitesUpdated, // Invites we sent or received changed
+    JoinRequestsUpdated, // Join requests for our wikis, or ones we sent, changed
+    OwnershipOffersUpdated,
+    WatchNotice { notice: WatchNotice }, // A watched page changed; also stored in the inbox
+    // Collaborative editing; yrs updates and state vectors are base64-encoded v1
+    EditSync { wiki_id: String, path: String, update: String, state_vector: String },
+    EditUpdate { wiki_id: String, path: String, update: String },
+    EditCommitted { wiki_id: String, previous_path: String, path: String },
+    Awareness { wiki_id: String, path: String, channel_id: u32, state: Option<serde_json::Value> },
+    EditError { wiki_id: String, path: String, error: String },
+}
+
+// Messages sent by clients over the /ws binding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum WsClientMessage {
+    Connect, // Registers the channel for wiki list updates only
+    // Notifications are raised by this node only. Hosts don't push changes to
+    // members' nodes, so for a remote wiki ("wiki_id@node_id") a subscriber
+    // hears about our own edits, and about other members' only through watch
+    // notices
+    Subscribe { wiki_id: String, path: Option<String> },
+    Unsubscribe { wiki_id: String, path: Option<String> },
+    // Collaborative editing; yrs updates and state vectors are base64-encoded v1
+    JoinEditSession { wiki_id: String, path: String, state_vector: Option<String> },
+    LeaveEditSession { wiki_id: String, path: String },
+    EditUpdate { wiki_id: String, path: String, update: String },
+    Awareness { wiki_id: String, path: String, state: serde_json::Value },
+    CommitEdit { wiki_id: String, path: String, commit_message: Option<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct WsSubscription {
+    wiki_id: String, // Wiki ID as the client knows it (may be "wiki_id@node_id")
+    path: Option<String>, // None subscribes to the whole wiki
+}
+
+impl WsNotification {
+    fn matches(&self, subscriptions: &[WsSubscription]) -> bool {
+        match self {
+            // Changes to our own wiki list and roles are relevant to every open tab
+            WsNotification::WikiListUpdated
+            | WsNotification::RoleUpdated { .. }
+            | WsNotification::InvitesUpdated
+            | WsNotification::JoinRequestsUpdated
+            | WsNotification::OwnershipOffersUpdated
+            | WsNotification::WatchNotice { .. } => true,
+            WsNotification::WikiUpdated { wiki_id } => {
+                subscriptions.iter().any(|s| &s.wiki_id == wiki_id)
+            }
+            WsNotification::PageListUpdated { wiki_id } => {
+                subscriptions.iter().any(|s| &s.wiki_id == wiki_id && s.path.is_none())
+            }
+            WsNotification::PageUpdated { wiki_id, path } => {
+                subscriptions.iter().any(|s| {
+                    &s.wiki_id == wiki_id && s.path.as_ref().is_none_or(|p| p == path)
+                })
+            }
+            // Editing traffic is addressed to edit session channels directly
+            WsNotification::EditSync { .. }
+            | WsNotification::EditUpdate { .. }
+            | WsNotification::EditCommitted { .. }
+            | WsNotification::Awareness { .. }
+            | WsNotification::EditError { .. } => false,
+        }
+    }
 }
 
 // Response types
@@ -315,12 +1398,25 @@ struct SuccessResponse {
     success: bool,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PurgeResult {
+    versions_removed: usize,
+    deleted_pages_removed: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CreateWikiResponse {
     wiki_id: String,
     wiki: Wiki,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CreateJoinCodeResponse {
+    code: String,
+    wiki_id: String,
+    node_id: String, // With `wiki_id` and `code`, everything a join link needs
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CreatePageResponse {
     success: bool,
@@ -346,6 +1442,12 @@ struct PageInfo {
     content: String,
     updated_by: String,
     updated_at: String,
+    #[serde(default)]
+    protected: bool,
+    #[serde(default)]
+    lock: Option<PageLock>, // Only set while the lock is active
+    #[serde(default)]
+    base: Option<String>, // For remote pages: the state `content` was read at, to send back with an edit
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -355,6 +1457,10 @@ struct DecodedPageVersion {
     updated_by: String,
     updated_at: String,
     commit_message: Option<String>,
+    #[serde(default)]
+    change: PageChange,
+    #[serde(default)]
+    tag: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -380,6 +1486,81 @@ struct SearchResult {
     snippet: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+enum RecentChangeKind {
+    Created,
+    Edited,
+    Renamed,
+    Deleted,
+    Restored,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecentChange {
+    path: String, // Page path right after the change
+    kind: RecentChangeKind,
+    previous_path: Option<String>, // Set for renames
+    version_id: Option<String>, // None for deletions
+    author: String,
+    timestamp: String,
+    commit_message: Option<String>,
+}
+
+impl RecentChange {
+    fn from_version(path: &str, version: &PageVersion) -> Self {
+        let (kind, previous_path) = match &version.change {
+            PageChange::Created => (RecentChangeKind::Created, None),
+            PageChange::Edited => (RecentChangeKind::Edited, None),
+            PageChange::Renamed { from } => (RecentChangeKind::Renamed, Some(from.clone())),
+            PageChange::Restored { .. } => (RecentChangeKind::Restored, None),
+        };
+        RecentChange {
+            path: path.to_string(),
+            kind,
+            previous_path,
+            version_id: Some(version.version_id.clone()),
+            author: version.updated_by.clone(),
+            timestamp: version.updated_at.clone(),
+            commit_message: version.commit_message.clone(),
+        }
+    }
+
+    fn deletion(path: &str, deleted_by: &str, deleted_at: &str) -> Self {
+        RecentChange {
+            path: path.to_string(),
+            kind: RecentChangeKind::Deleted,
+            previous_path: None,
+            version_id: None,
+            author: deleted_by.to_string(),
+            timestamp: deleted_at.to_string(),
+            commit_message: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecentChangesPage {
+    changes: Vec<RecentChange>, // Newest first
+    total: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditLogPage {
+    entries: Vec<AuditEntry>, // Newest first
+    total: usize, // Entries matching the filters, across all pages
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SentInviteInfo {
+    id: String,
+    wiki_id: String,
+    invitee_id: String,
+    role: WikiRole,
+    created_at: String,
+    expires_at: String,
+    status: InviteStatus,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct InviteInfo {
     id: String,
@@ -389,92 +1570,422 @@ struct InviteInfo {
     created_at: String,
     expires_at: String,
     is_expired: bool,
+    role: WikiRole,
 }
 
 impl WikiState {
-    fn decode_yrs_content(&self, yrs_doc: &[u8]) -> Result<String, String> {
-        let doc = Doc::new();
-        {
-            let mut txn = doc.transact_mut();
-            if let Ok(update) = yrs::Update::decode_v1(yrs_doc) {
-                if let Err(e) = txn.apply_update(update) {
-                    return Err(format!("Failed to apply update: {}", e));
-                }
-            } else {
-                return Err("Failed to decode update".to_string());
-            }
-        }
-        let text = doc.get_or_insert_text("content");
-        let content = text.get_string(&doc.transact());
-        Ok(content)
-    }
-
-    fn calculate_diff(&self, text1: &str, text2: &str) -> Vec<DiffLine> {
+    /// Line diff of two texts, with removed/added line pairs split into
+    /// segments when `options` asks for word or character granularity.
+    fn calculate_diff(&self, text1: &str, text2: &str, options: &DiffOptions) -> Vec<DiffLine> {
         let lines1: Vec<&str> = text1.lines().collect();
         let lines2: Vec<&str> = text2.lines().collect();
         let mut diff_lines = Vec::new();
 
-        // Simple line-by-line diff algorithm
-        let _max_len = lines1.len().max(lines2.len());
-        let mut i = 0;
-        let mut j = 0;
-
-        while i < lines1.len() || j < lines2.len() {
-            if i >= lines1.len() {
-                // All remaining lines in text2 are additions
-                diff_lines.push(DiffLine {
-                    line_type: DiffLineType::Added,
-                    content: lines2[j].to_string(),
-                    line_number_old: None,
-                    line_number_new: Some(j + 1),
-                });
-                j += 1;
-            } else if j >= lines2.len() {
-                // All remaining lines in text1 are deletions
-                diff_lines.push(DiffLine {
-                    line_type: DiffLineType::Removed,
-                    content: lines1[i].to_string(),
-                    line_number_old: Some(i + 1),
-                    line_number_new: None,
-                });
-                i += 1;
-            } else if lines1[i] == lines2[j] {
-                // Lines are the same
+        let ops = Self::myers_diff(&lines1, &lines2);
+        let mut index = 0;
+        while index < ops.len() {
+            if let DiffOp::Equal(i, j) = ops[index] {
                 diff_lines.push(DiffLine {
                     line_type: DiffLineType::Unchanged,
                     content: lines1[i].to_string(),
                     line_number_old: Some(i + 1),
                     line_number_new: Some(j + 1),
+                    segments: Vec::new(),
                 });
-                i += 1;
-                j += 1;
-            } else {
-                // Lines are different - mark as removed then added
-                diff_lines.push(DiffLine {
+                index += 1;
+                continue;
+            }
+
+            // A run of changes: show every removed line, then every added one
+            let mut removed = Vec::new();
+            let mut added = Vec::new();
+            while let Some(op) = ops.get(index) {
+                match *op {
+                    DiffOp::Delete(i) => removed.push(i),
+                    DiffOp::Insert(j) => added.push(j),
+                    DiffOp::Equal(..) => break,
+                }
+                index += 1;
+            }
+
+            let mut removed_lines: Vec<DiffLine> = removed.iter()
+                .map(|&i| DiffLine {
                     line_type: DiffLineType::Removed,
                     content: lines1[i].to_string(),
                     line_number_old: Some(i + 1),
                     line_number_new: None,
-                });
-                diff_lines.push(DiffLine {
+                    segments: Vec::new(),
+                })
+                .collect();
+            let mut added_lines: Vec<DiffLine> = added.iter()
+                .map(|&j| DiffLine {
                     line_type: DiffLineType::Added,
                     content: lines2[j].to_string(),
                     line_number_old: None,
                     line_number_new: Some(j + 1),
-                });
-                i += 1;
-                j += 1;
+                    segments: Vec::new(),
+                })
+                .collect();
+
+            // Lines replaced one for one get intra-line highlighting
+            if options.granularity != DiffGranularity::Line {
+                for (old_line, new_line) in removed_lines.iter_mut().zip(added_lines.iter_mut()) {
+                    let (old_segments, new_segments) =
+                        Self::inline_diff(&old_line.content, &new_line.content, &options.granularity);
+                    old_line.segments = old_segments;
+                    new_line.segments = new_segments;
+                }
             }
+
+            diff_lines.extend(removed_lines);
+            diff_lines.extend(added_lines);
         }
 
         diff_lines
     }
+
+    /// Splits a changed line pair into segments, marking the words or
+    /// characters that differ.
+    fn inline_diff(old: &str, new: &str, granularity: &DiffGranularity) -> (Vec<DiffSegment>, Vec<DiffSegment>) {
+        let old_tokens = Self::tokenize(old, granularity);
+        let new_tokens = Self::tokenize(new, granularity);
+
+        let mut old_segments: Vec<DiffSegment> = Vec::new();
+        let mut new_segments: Vec<DiffSegment> = Vec::new();
+        let push = |segments: &mut Vec<DiffSegment>, text: &str, changed: bool| {
+            match segments.last_mut() {
+                Some(last) if last.changed == changed => last.text.push_str(text),
+                _ => segments.push(DiffSegment { text: text.to_string(), changed }),
+            }
+        };
+        for op in Self::myers_diff(&old_tokens, &new_tokens) {
+            match op {
+                DiffOp::Equal(i, j) => {
+                    push(&mut old_segments, old_tokens[i], false);
+                    push(&mut new_segments, new_tokens[j], false);
+                }
+                DiffOp::Delete(i) => push(&mut old_segments, old_tokens[i], true),
+                DiffOp::Insert(j) => push(&mut new_segments, new_tokens[j], true),
+            }
+        }
+
+        (old_segments, new_segments)
+    }
+
+    /// Words are runs of alphanumerics or of whitespace; any other character
+    /// stands alone.
+    fn tokenize<'a>(line: &'a str, granularity: &DiffGranularity) -> Vec<&'a str> {
+        let mut tokens = Vec::new();
+        let mut start = 0;
+        let mut chars = line.char_indices().peekable();
+        while let Some((index, c)) = chars.next() {
+            let end = index + c.len_utf8();
+            let joins_next = match (granularity, chars.peek()) {
+                (DiffGranularity::Word, Some(&(_, next))) => {
+                    (c.is_alphanumeric() && next.is_alphanumeric())
+                        || (c.is_whitespace() && next.is_whitespace())
+                }
+                _ => false,
+            };
+            if !joins_next {
+                tokens.push(&line[start..end]);
+                start = end;
+            }
+        }
+        tokens
+    }
+
+    /// Shortest edit script turning `a` into `b`, using Myers' O(ND) algorithm
+    /// in linear space. Past MAX_DIFF_EDITS edits the rest is reported as
+    /// replaced. Within each change, removals come before additions.
+    fn myers_diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<DiffOp> {
+        let mut ops = Vec::new();
+        Self::myers_ops(a, b, (0, 0), Some(MAX_DIFF_EDITS), &mut ops);
+
+        let mut start = 0;
+        while start < ops.len() {
+            let end = ops[start..].iter()
+                .position(|op| matches!(op, DiffOp::Equal(..)))
+                .map_or(ops.len(), |len| start + len);
+            ops[start..end].sort_by_key(|op| matches!(op, DiffOp::Insert(_)));
+            start = end + 1;
+        }
+        ops
+    }
+
+    /// Appends the edit script for `a` and `b`, which start at `offset` in the
+    /// full inputs. Gives up on the middle if it takes more than `limit` edits.
+    fn myers_ops<T: PartialEq>(a: &[T], b: &[T], offset: (usize, usize), limit: Option<usize>, ops: &mut Vec<DiffOp>) {
+        // The common prefix and suffix never need searching
+        let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        let suffix = a[prefix..].iter().rev()
+            .zip(b[prefix..].iter().rev())
+            .take_while(|(x, y)| x == y)
+            .count();
+        let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+        let (a_start, b_start) = (offset.0 + prefix, offset.1 + prefix);
+
+        ops.extend((0..prefix).map(|i| DiffOp::Equal(offset.0 + i, offset.1 + i)));
+        let split = if a_mid.is_empty() || b_mid.is_empty() {
+            None
+        } else {
+            Self::myers_split(a_mid, b_mid, limit)
+        };
+        match split {
+            // Both halves of a shortest path are shortest paths themselves
+            Some((x, y)) => {
+                Self::myers_ops(&a_mid[..x], &b_mid[..y], (a_start, b_start), None, ops);
+                Self::myers_ops(&a_mid[x..], &b_mid[y..], (a_start + x, b_start + y), None, ops);
+            }
+            None => {
+                ops.extend((a_start..a_start + a_mid.len()).map(DiffOp::Delete));
+                ops.extend((b_start..b_start + b_mid.len()).map(DiffOp::Insert));
+            }
+        }
+        let (a_end, b_end) = (a_start + a_mid.len(), b_start + b_mid.len());
+        ops.extend((0..suffix).map(|i| DiffOp::Equal(a_end + i, b_end + i)));
+    }
+
+    /// Finds a point on a shortest edit path between two non-empty inputs that
+    /// differ at both ends, by searching from both ends until the paths meet.
+    /// Only two diagonal arrays are kept, so memory stays linear. None once the
+    /// path would need more than `limit` edits.
+    fn myers_split<T: PartialEq>(a: &[T], b: &[T], limit: Option<usize>) -> Option<(usize, usize)> {
+        let (n, m) = (a.len() as isize, b.len() as isize);
+        let max_d = (n + m + 1) / 2;
+        let offset = max_d + 1;
+        let size = (2 * offset + 1) as usize;
+        // forward[k + offset]: furthest x reached on diagonal k = x - y from the
+        // start; backward[k + offset]: the same from the end, on the reversed inputs
+        let mut forward = vec![-1isize; size];
+        let mut backward = vec![-1isize; size];
+        forward[(offset + 1) as usize] = 0;
+        backward[(offset + 1) as usize] = 0;
+        let delta = n - m;
+        // With an odd delta the paths meet on a forward step, otherwise on a backward one
+        let meets_forward = delta % 2 != 0;
+        // Diagonals past the edge of the grid are dropped from the search
+        let (mut forward_start, mut forward_end, mut backward_start, mut backward_end) = (0, 0, 0, 0);
+
+        for d in 0..max_d {
+            if limit.is_some_and(|limit| (2 * d - 1).max(0) as usize > limit) {
+                return None;
+            }
+
+            for k in (-d + forward_start..=d - forward_end).step_by(2) {
+                let index = (k + offset) as usize;
+                let mut x = if k == -d || (k != d && forward[index - 1] < forward[index + 1]) {
+                    forward[index + 1]
+                } else {
+                    forward[index - 1] + 1
+                };
+                let mut y = x - k;
+                while x < n && y < m && a[x as usize] == b[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                forward[index] = x;
+                if x > n {
+                    forward_end += 2;
+                } else if y > m {
+                    forward_start += 2;
+                } else if meets_forward {
+                    let opposite = offset + delta - k;
+                    if (0..size as isize).contains(&opposite) && backward[opposite as usize] != -1
+                        && x >= n - backward[opposite as usize]
+                    {
+                        return Some((x as usize, y as usize));
+                    }
+                }
+            }
+
+            for k in (-d + backward_start..=d - backward_end).step_by(2) {
+                let index = (k + offset) as usize;
+                let mut x = if k == -d || (k != d && backward[index - 1] < backward[index + 1]) {
+                    backward[index + 1]
+                } else {
+                    backward[index - 1] + 1
+                };
+                let mut y = x - k;
+                while x < n && y < m && a[(n - 1 - x) as usize] == b[(m - 1 - y) as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                backward[index] = x;
+                if x > n {
+                    backward_end += 2;
+                } else if y > m {
+                    backward_start += 2;
+                } else if !meets_forward {
+                    let opposite = offset + delta - k;
+                    if (0..size as isize).contains(&opposite) && forward[opposite as usize] != -1 {
+                        let forward_x = forward[opposite as usize];
+                        let forward_y = forward_x - (opposite - offset);
+                        if forward_x >= n - x {
+                            return Some((forward_x as usize, forward_y as usize));
+                        }
+                    }
+                }
+            }
+        }
+        // Only reached past the edge cases above; nothing in common
+        None
+    }
+
+    /// Groups changed lines into hunks with `context` unchanged lines around
+    /// each; changes closer than twice that share a hunk.
+    fn diff_hunks(diff_lines: &[DiffLine], context: usize) -> Vec<DiffHunk> {
+        let changed: Vec<usize> = diff_lines.iter()
+            .enumerate()
+            .filter(|(_, line)| !matches!(line.line_type, DiffLineType::Unchanged))
+            .map(|(index, _)| index)
+            .collect();
+
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for index in changed {
+            let start = index.saturating_sub(context);
+            let end = (index + context + 1).min(diff_lines.len());
+            match ranges.last_mut() {
+                Some(last) if start <= last.1 => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+
+        ranges.into_iter()
+            .map(|(start, end)| {
+                let count = |lines: &[DiffLine], old: bool| lines.iter()
+                    .filter(|line| if old { line.line_number_old.is_some() } else { line.line_number_new.is_some() })
+                    .count();
+                let (old_before, new_before) = (count(&diff_lines[..start], true), count(&diff_lines[..start], false));
+                let (old_lines, new_lines) = (count(&diff_lines[start..end], true), count(&diff_lines[start..end], false));
+                DiffHunk {
+                    // Unified diffs number an empty side by the line before it
+                    old_start: if old_lines > 0 { old_before + 1 } else { old_before },
+                    old_lines,
+                    new_start: if new_lines > 0 { new_before + 1 } else { new_before },
+                    new_lines,
+                    first_line: start,
+                    line_count: end - start,
+                }
+            })
+            .collect()
+    }
+
+    fn unified_diff(old_label: &str, new_label: &str, diff_lines: &[DiffLine], hunks: &[DiffHunk]) -> String {
+        if hunks.is_empty() {
+            return String::new();
+        }
+        let mut out = format!("--- {}\n+++ {}\n", old_label, new_label);
+        for hunk in hunks {
+            out.push_str(&format!(
+                "@@ -{},{} +{},{} @@\n",
+                hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines
+            ));
+            for line in &diff_lines[hunk.first_line..hunk.first_line + hunk.line_count] {
+                let marker = match line.line_type {
+                    DiffLineType::Added => '+',
+                    DiffLineType::Removed => '-',
+                    DiffLineType::Unchanged => ' ',
+                };
+                out.push(marker);
+                out.push_str(&line.content);
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Credits each line of the newest version to the version that introduced
+    /// it, following lines through a diff of every version against the one
+    /// before. Lines older than the kept history go to its oldest version.
+    fn page_blame(history: &PageHistory) -> PageBlame {
+        let mut lines: Vec<String> = Vec::new();
+        let mut origins: Vec<usize> = Vec::new();
+        for (index, text) in history.version_texts().into_iter().enumerate() {
+            // An unreadable version leaves the lines as they were
+            let Ok(text) = text else { continue };
+            let next_lines: Vec<String> = text.lines().map(str::to_string).collect();
+            let mut next_origins = vec![index; next_lines.len()];
+            for op in Self::myers_diff(&lines, &next_lines) {
+                if let DiffOp::Equal(i, j) = op {
+                    next_origins[j] = origins[i];
+                }
+            }
+            lines = next_lines;
+            origins = next_origins;
+        }
+
+        let lines = lines.into_iter().zip(origins).enumerate()
+            .map(|(index, (content, origin))| {
+                let version = &history.versions[origin];
+                BlameLine {
+                    line_number: index + 1,
+                    content,
+                    version_id: version.version_id.clone(),
+                    author: version.updated_by.clone(),
+                    timestamp: version.updated_at.clone(),
+                }
+            })
+            .collect();
+
+        PageBlame {
+            path: history.path.clone(),
+            version_id: history.current_version_id.clone(),
+            lines,
+        }
+    }
+
+    /// Diffs two versions from a page's history.
+    fn version_diff(
+        &self,
+        history: &PageHistory,
+        version1_id: &str,
+        version2_id: &str,
+        options: &DiffOptions,
+    ) -> Result<VersionDiff, String> {
+        // Find the two versions
+        let version1 = history.versions.iter().position(|v| v.version_id == version1_id);
+        let version2 = history.versions.iter().position(|v| v.version_id == version2_id);
+        let (Some(v1), Some(v2)) = (version1, version2) else {
+            return Err("One or both versions not found".to_string());
+        };
+
+        // Rebuild the content of both versions
+        let content1 = history.version_text(v1)
+            .map_err(|_| "Failed to decode version 1 content".to_string())?;
+        let content2 = history.version_text(v2)
+            .map_err(|_| "Failed to decode version 2 content".to_string())?;
+
+        let diff_lines = self.calculate_diff(&content1, &content2, options);
+        let hunks = Self::diff_hunks(&diff_lines, options.context_lines.unwrap_or(DEFAULT_DIFF_CONTEXT_LINES));
+        let unified = Self::unified_diff(
+            &format!("{}@{}", history.path, version1_id),
+            &format!("{}@{}", history.path, version2_id),
+            &diff_lines,
+            &hunks,
+        );
+
+        Ok(VersionDiff {
+            version1_id: version1_id.to_string(),
+            version2_id: version2_id.to_string(),
+            diff_lines,
+            hunks,
+            unified,
+        })
+    }
 }
 
 impl Default for WikiState {
     fn default() -> Self {
+        WikiState::new(&our().node)
+    }
+}
+
+impl WikiState {
+    /// Empty state for the node `node_id`.
+    fn new(node_id: &str) -> Self {
         WikiState {
-            node_id: our().node.to_string(),
+            node_id: node_id.to_string(),
             wikis: HashMap::new(),
             pages: HashMap::new(),
             page_histories: HashMap::new(),
@@ -482,6 +1993,19 @@ impl Default for WikiState {
             my_memberships: Vec::new(),
             invites: HashMap::new(),
             active_docs: HashMap::new(),
+            ws_subscriptions: HashMap::new(),
+            edit_sessions: HashMap::new(),
+            replicas: HashMap::new(),
+            replication_outbox: HashMap::new(),
+            last_maintenance_at: None,
+            join_requests: HashMap::new(),
+            ownership_offers: HashMap::new(),
+            deleted_wikis: HashMap::new(),
+            audit_log: HashMap::new(),
+            watches: Vec::new(),
+            watchers: HashMap::new(),
+            inbox: Vec::new(),
+            watch_outbox: HashMap::new(),
         }
     }
 }
@@ -500,6 +2024,8 @@ impl WikiState {
     #[init]
     async fn init(&mut self) {
         hyperware_process_lib::homepage::add_to_homepage("wiki", Some(ICON), Some(""), None);
+        self.compact_page_histories();
+        self.run_maintenance();
 
         println!("begin");
     }
@@ -511,11 +2037,34 @@ impl WikiState {
         let message: WikiMessage = match serde_json::from_str(&body_str) {
             Ok(msg) => msg,
             Err(e) => {
-                let error_response = WikiResponse::Error(format!("Failed to parse message: {}", e));
+                let error_response = WikiResponse::UnsupportedMessage(format!("Failed to parse message: {}", e));
                 return Ok(serde_json::to_string(&error_response).unwrap().into_bytes());
             }
         };
 
+        self.run_maintenance();
+
+        // The acting user is whoever sent the message, not whoever it names
+        let caller = source().node().to_string();
+        if message.claimed_user_id().is_some_and(|claimed| claimed != caller) {
+            let error_response = WikiResponse::Error("User ID does not match message source".to_string());
+            return Ok(serde_json::to_string(&error_response).unwrap().into_bytes());
+        }
+
+        // Replica copies are read-only; writes go to the wiki's host
+        if let Some(host) = message.written_wiki_id()
+            .and_then(|wiki_id| self.wikis.get(wiki_id))
+            .and_then(|wiki| wiki.replica_of.clone())
+        {
+            let error_response = WikiResponse::Error(format!("Wiki is a read-only replica; its host is {}", host));
+            return Ok(serde_json::to_string(&error_response).unwrap().into_bytes());
+        }
+
+        if let Err(err) = self.authorize(&message, &caller) {
+            let error_response = WikiResponse::PermissionDenied(err);
+            return Ok(serde_json::to_string(&error_response).unwrap().into_bytes());
+        }
+
         let response = match message {
             WikiMessage::FindWikisByUser { username } => {
                 // Find all public wikis that have this user as a member
@@ -555,56 +2104,80 @@ impl WikiState {
             WikiMessage::JoinPublicWiki { wiki_id, user_id } => {
                 match self.wikis.get_mut(&wiki_id) {
                     Some(wiki) if wiki.is_public => {
-                        // Add the user as a member with Writer role for public wikis
-                        // This allows collaboration on public wikis
-                        wiki.members.insert(user_id.clone(), WikiRole::Writer);
-                        println!("User {} joined wiki {} as Writer", user_id, wiki_id);
-                        WikiResponse::Success(true)
+                        let role = wiki.join_public(&user_id);
+                        println!("User {} joined wiki {} as {:?}", user_id, wiki_id, role);
+                        self.audit(&wiki_id, &user_id, AuditAction::MemberJoined, Some(&user_id), Some(format!("Joined as {:?}", role)));
+                        self.replicate_wiki(&wiki_id);
+                        self.notify(WsNotification::WikiUpdated { wiki_id });
+                        WikiResponse::JoinedWiki { role }
                     }
                     Some(_) => WikiResponse::Error("Wiki is not public".to_string()),
                     None => WikiResponse::Error("Wiki not found".to_string()),
                 }
             }
-            WikiMessage::GetWikiData { wiki_id } => {
-                match self.wikis.get(&wiki_id) {
-                    Some(wiki) => {
-                        // Allow access to wiki data for both public and private wikis
-                        // TODO: In production, verify requester is a member for private wikis
-                        WikiResponse::WikiData(wiki.clone())
+            WikiMessage::JoinWithCode { wiki_id, user_id, join_code } => {
+                match self.wikis.get_mut(&wiki_id) {
+                    Some(wiki) => match wiki.join_with_code(&user_id, &join_code) {
+                        Ok(role) => {
+                            println!("User {} joined wiki {} with a join code as {:?}", user_id, wiki_id, role);
+                            self.audit(&wiki_id, &user_id, AuditAction::MemberJoined, Some(&user_id), Some(format!("Joined as {:?} with a join code", role)));
+                            self.replicate_wiki(&wiki_id);
+                            self.notify(WsNotification::WikiUpdated { wiki_id });
+                            WikiResponse::JoinedWiki { role }
+                        }
+                        Err(e) => WikiResponse::Error(e),
+                    },
+                    None => WikiResponse::Error("Wiki not found".to_string()),
+                }
+            }
+            WikiMessage::LeaveWiki { wiki_id, user_id } => {
+                match self.wikis.get_mut(&wiki_id) {
+                    Some(wiki) if wiki.members.contains_key(&user_id) => {
+                        match wiki.check_member_change(&user_id, &user_id, None) {
+                            Ok(()) => {
+                                wiki.members.remove(&user_id);
+                                if wiki.pending_transfer.as_ref().is_some_and(|transfer| transfer.to_id == user_id) {
+                                    wiki.pending_transfer = None;
+                                }
+                                println!("User {} left wiki {}", user_id, wiki_id);
+                                self.audit(&wiki_id, &user_id, AuditAction::MemberLeft, Some(&user_id), None);
+                                self.replicate_wiki(&wiki_id);
+                                self.notify(WsNotification::WikiUpdated { wiki_id });
+                                WikiResponse::Success(true)
+                            }
+                            Err(e) => WikiResponse::Error(e),
+                        }
                     }
+                    Some(_) => WikiResponse::Error("Not a member of this wiki".to_string()),
                     None => WikiResponse::Error("Wiki not found".to_string()),
                 }
             }
-            WikiMessage::GetWikiPages { wiki_id } => {
+            WikiMessage::GetWikiData { wiki_id } => {
                 match self.wikis.get(&wiki_id) {
                     Some(wiki) => {
-                        // For now, allow access to pages if wiki is public
-                        // TODO: In the future, check if the requester is a member for private wikis
-                        if wiki.is_public {
-                            let pages: Vec<PageSummary> = self.pages
-                                .iter()
-                                .filter(|(_, page)| page.wiki_id == wiki_id)
-                                .map(|(_, page)| PageSummary {
-                                    path: page.path.clone(),
-                                    updated_by: page.current_version.updated_by.clone(),
-                                    updated_at: page.current_version.updated_at.clone(),
-                                })
-                                .collect();
-                            WikiResponse::PageList(pages)
-                        } else {
-                            // For private wikis, we'll allow access for now
-                            // In a production system, we'd verify the requester is a member
-                            let pages: Vec<PageSummary> = self.pages
-                                .iter()
-                                .filter(|(_, page)| page.wiki_id == wiki_id)
-                                .map(|(_, page)| PageSummary {
-                                    path: page.path.clone(),
-                                    updated_by: page.current_version.updated_by.clone(),
-                                    updated_at: page.current_version.updated_at.clone(),
-                                })
-                                .collect();
-                            WikiResponse::PageList(pages)
+                        let mut wiki = wiki.clone();
+                        if !wiki.members.get(&caller).is_some_and(|role| role.includes(&WikiRole::Admin)) {
+                            wiki.join_codes.clear();
                         }
+                        WikiResponse::WikiData(wiki)
+                    }
+                    None => WikiResponse::Error("Wiki not found".to_string()),
+                }
+            }
+            WikiMessage::GetWikiPages { wiki_id } => {
+                match self.wikis.get(&wiki_id) {
+                    Some(wiki) => {
+                        let pages: Vec<PageSummary> = self.pages
+                            .iter()
+                            .filter(|(_, page)| page.wiki_id == wiki_id)
+                            .filter(|(_, page)| wiki.check_page_access(&caller, &page.path, WikiRole::Reader).is_ok())
+                            .map(|(_, page)| PageSummary {
+                                path: page.path.clone(),
+                                updated_by: page.current_version.updated_by.clone(),
+                                updated_at: page.current_version.updated_at.clone(),
+                            })
+                            .collect();
+                        WikiResponse::PageList(pages)
                     }
                     None => WikiResponse::Error("Wiki not found".to_string()),
                 }
@@ -612,8 +2185,6 @@ impl WikiState {
             WikiMessage::GetWikiPage { wiki_id, path } => {
                 match self.wikis.get(&wiki_id) {
                     Some(_wiki) => {
-                        // Allow access to pages for both public and private wikis
-                        // TODO: In production, verify requester is a member for private wikis
                         let page_key = format!("{}:{}", wiki_id, path);
                         if let Some(page) = self.pages.get(&page_key) {
                             let doc = self.active_docs.entry(page_key.clone())
@@ -637,6 +2208,9 @@ impl WikiState {
                                 content,
                                 updated_by: page.current_version.updated_by.clone(),
                                 updated_at: page.current_version.updated_at.clone(),
+                                protected: page.protected,
+                                lock: page.lock.clone().filter(PageLock::is_active),
+                                base: None,
                             })
                         } else {
                             WikiResponse::PageData(PageInfo {
@@ -645,6 +2219,9 @@ impl WikiState {
                                 content: String::new(),
                                 updated_by: String::new(),
                                 updated_at: String::new(),
+                                protected: false,
+                                lock: None,
+                                base: None,
                             })
                         }
                     }
@@ -652,186 +2229,60 @@ impl WikiState {
                 }
             }
             WikiMessage::CreatePage { wiki_id, path, initial_content, user_id, commit_message } => {
-                match self.wikis.get_mut(&wiki_id) {
-                    Some(wiki) => {
-                        // Check if user has write permissions
-                        match wiki.members.get(&user_id) {
-                            Some(role) if matches!(role, WikiRole::Writer | WikiRole::Admin | WikiRole::SuperAdmin) => {
-                                let page_key = format!("{}:{}", wiki_id, path);
-
-                                // Create CRDT document
-                                let doc = Doc::new();
-                                let text = doc.get_or_insert_text("content");
-                                {
-                                    let mut txn = doc.transact_mut();
-                                    text.insert(&mut txn, 0, &initial_content);
-                                    txn.commit();
-                                }
-
-                                let mut encoder = EncoderV1::new();
-                                doc.transact().encode_state_as_update(&yrs::StateVector::default(), &mut encoder);
-                                let update = encoder.to_vec();
-
-                                // Create the first version
-                                let version_id = Uuid::new_v4().to_string();
-                                let first_version = PageVersion {
-                                    version_id: version_id.clone(),
-                                    content: update.clone(),
-                                    updated_by: user_id.clone(),
-                                    updated_at: Utc::now().to_rfc3339(),
-                                    commit_message,
-                                };
-
-                                let page = WikiPage {
-                                    path: path.clone(),
-                                    wiki_id: wiki_id.clone(),
-                                    current_version: first_version.clone(),
-                                    yrs_doc: update,
-                                };
-
-                                // Create page history
-                                let history = PageHistory {
-                                    path: path.clone(),
-                                    wiki_id: wiki_id.clone(),
-                                    versions: vec![first_version],
-                                    current_version_id: version_id,
-                                };
-
-                                self.pages.insert(page_key.clone(), page);
-                                self.page_histories.insert(page_key.clone(), history);
-                                self.active_docs.insert(page_key, doc);
-
-                                WikiResponse::Success(true)
-                            }
-                            _ => WikiResponse::Error("Insufficient permissions".to_string()),
+                if !self.wikis.contains_key(&wiki_id) {
+                    WikiResponse::Error("Wiki not found".to_string())
+                } else {
+                    match self.add_page(&wiki_id, &path, &initial_content, &user_id, commit_message) {
+                        Ok(()) => {
+                            self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.clone() });
+                            self.notify(WsNotification::PageUpdated { wiki_id, path });
+                            WikiResponse::Success(true)
                         }
+                        Err(e) => WikiResponse::Error(e),
                     }
-                    None => WikiResponse::Error("Wiki not found".to_string()),
                 }
             }
             WikiMessage::UpdatePage { wiki_id, path, content, user_id, commit_message } => {
                 match self.wikis.get(&wiki_id) {
                     Some(wiki) => {
-                        // Check if user has write permissions
-                        match wiki.members.get(&user_id) {
-                            Some(role) if matches!(role, WikiRole::Writer | WikiRole::Admin | WikiRole::SuperAdmin) => {
-                                let page_key = format!("{}:{}", wiki_id, path);
-
-                                // Check if page exists
-                                if !self.pages.contains_key(&page_key) {
-                                    return Ok(serde_json::to_vec(&WikiResponse::Error("Page not found".to_string())).unwrap());
-                                }
-
-                                let doc = self.active_docs.entry(page_key.clone())
-                                    .or_insert_with(|| {
-                                        if let Some(page) = self.pages.get(&page_key) {
-                                            let new_doc = Doc::new();
-                                            {
-                                                let mut txn = new_doc.transact_mut();
-                                                if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
-                                                    let _ = txn.apply_update(update);
-                                                }
-                                            }
-                                            new_doc
-                                        } else {
-                                            Doc::new()
-                                        }
-                                    });
-
-                                let text = doc.get_or_insert_text("content");
-
-                                // Replace content
-                                {
-                                    let mut txn = doc.transact_mut();
-                                    let current_len = text.len(&txn);
-                                    if current_len > 0 {
-                                        text.remove_range(&mut txn, 0, current_len);
-                                    }
-                                    text.insert(&mut txn, 0, &content);
-                                    txn.commit();
-                                }
-
-                                // Encode the update
-                                let mut encoder = EncoderV1::new();
-                                doc.transact().encode_state_as_update(&yrs::StateVector::default(), &mut encoder);
-                                let update = encoder.to_vec();
-
-                                // Create new version
-                                let version_id = Uuid::new_v4().to_string();
-                                let new_version = PageVersion {
-                                    version_id: version_id.clone(),
-                                    content: update.clone(),
-                                    updated_by: user_id.clone(),
-                                    updated_at: Utc::now().to_rfc3339(),
-                                    commit_message,
-                                };
-
-                                // Update the page
-                                if let Some(page) = self.pages.get_mut(&page_key) {
-                                    page.current_version = new_version.clone();
-                                    page.yrs_doc = update;
-                                }
+                        // A retitle moves the page, so the caller must be able to write there too
+                        let new_title = Self::extract_title_from_markdown(&content);
+                        if let Err(reason) = wiki.check_page_access(&user_id, &new_title, WikiRole::Writer) {
+                            return Ok(serde_json::to_vec(&WikiResponse::PermissionDenied(PermissionError {
+                                wiki_id,
+                                node_id: user_id,
+                                required_role: Some(WikiRole::Writer),
+                                reason,
+                            })).unwrap());
+                        }
 
-                                // Update history
-                                if let Some(history) = self.page_histories.get_mut(&page_key) {
-                                    history.versions.push(new_version);
-                                    history.current_version_id = version_id;
-                                } else {
-                                    // If no history exists (shouldn't happen), create it
-                                    let history = PageHistory {
-                                        path: path.clone(),
-                                        wiki_id: wiki_id.clone(),
-                                        versions: vec![new_version],
-                                        current_version_id: version_id,
-                                    };
-                                    self.page_histories.insert(page_key.clone(), history);
+                        match self.edit_page(&wiki_id, &path, &content, &user_id, commit_message) {
+                            Ok(new_path) => {
+                                self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.clone() });
+                                if new_path != path {
+                                    self.notify(WsNotification::PageUpdated { wiki_id: wiki_id.clone(), path });
                                 }
-
+                                self.notify(WsNotification::PageUpdated { wiki_id, path: new_path });
                                 WikiResponse::Success(true)
                             }
-                            _ => WikiResponse::Error("Insufficient permissions".to_string()),
+                            Err(e) => WikiResponse::Error(e),
                         }
                     }
                     None => WikiResponse::Error("Wiki not found".to_string()),
                 }
             }
             WikiMessage::DeletePage { wiki_id, path, user_id } => {
-                match self.wikis.get(&wiki_id) {
-                    Some(wiki) => {
-                        // Check if user has write permissions
-                        match wiki.members.get(&user_id) {
-                            Some(role) if matches!(role, WikiRole::Writer | WikiRole::Admin | WikiRole::SuperAdmin) => {
-                                let page_key = format!("{}:{}", wiki_id, path);
-
-                                // Check if page exists
-                                if let Some(_page) = self.pages.remove(&page_key) {
-                                    // Get the page history
-                                    if let Some(history) = self.page_histories.remove(&page_key) {
-                                        // Create deleted page entry
-                                        let deleted_key = format!("{}:{}:{}", wiki_id, path, Utc::now().timestamp());
-                                        let deleted_page = DeletedPage {
-                                            path: path.clone(),
-                                            wiki_id: wiki_id.clone(),
-                                            deleted_at: Utc::now().to_rfc3339(),
-                                            deleted_by: user_id.clone(),
-                                            history,
-                                        };
-
-                                        self.deleted_pages.insert(deleted_key, deleted_page);
-                                    }
-
-                                    // Remove from active docs
-                                    self.active_docs.remove(&page_key);
-
-                                    WikiResponse::Success(true)
-                                } else {
-                                    WikiResponse::Error("Page not found".to_string())
-                                }
-                            }
-                            _ => WikiResponse::Error("Insufficient permissions".to_string()),
+                if !self.wikis.contains_key(&wiki_id) {
+                    WikiResponse::Error("Wiki not found".to_string())
+                } else {
+                    match self.remove_page(&wiki_id, &path, &user_id) {
+                        Ok(()) => {
+                            self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.clone() });
+                            self.notify(WsNotification::PageUpdated { wiki_id, path });
+                            WikiResponse::Success(true)
                         }
+                        Err(e) => WikiResponse::Error(e),
                     }
-                    None => WikiResponse::Error("Wiki not found".to_string()),
                 }
             }
             WikiMessage::GetPageHistory { wiki_id, path } => {
@@ -841,17 +2292,17 @@ impl WikiState {
                         if let Some(history) = self.page_histories.get(&page_key) {
                             // Decode all versions
                             let decoded_versions: Vec<DecodedPageVersion> = history.versions.iter()
-                                .map(|version| {
-                                    let content = match self.decode_yrs_content(&version.content) {
-                                        Ok(text) => text,
-                                        Err(_) => "[Failed to decode content]".to_string(),
-                                    };
+                                .zip(history.version_texts())
+                                .map(|(version, content)| {
+                                    let content = content.unwrap_or_else(|_| "[Failed to decode content]".to_string());
                                     DecodedPageVersion {
                                         version_id: version.version_id.clone(),
                                         content,
                                         updated_by: version.updated_by.clone(),
                                         updated_at: version.updated_at.clone(),
                                         commit_message: version.commit_message.clone(),
+                                        change: version.change.clone(),
+                                        tag: version.tag.clone(),
                                     }
                                 })
                                 .collect();
@@ -873,47 +2324,63 @@ impl WikiState {
             }
             WikiMessage::RestoreDeletedPage { wiki_id, path, deleted_key, user_id } => {
                 match self.wikis.get(&wiki_id) {
-                    Some(wiki) => {
-                        // Check if user has write permissions
-                        match wiki.members.get(&user_id) {
-                            Some(role) if matches!(role, WikiRole::Writer | WikiRole::Admin | WikiRole::SuperAdmin) => {
-                                if let Some(deleted_page) = self.deleted_pages.remove(&deleted_key) {
-                                    // Check if this is the right page
-                                    if deleted_page.wiki_id != wiki_id || deleted_page.path != path {
-                                        self.deleted_pages.insert(deleted_key, deleted_page);
-                                        return Ok(serde_json::to_vec(&WikiResponse::Error("Deleted page key mismatch".to_string())).unwrap());
-                                    }
+                    Some(_wiki) => {
+                        if let Some(deleted_page) = self.deleted_pages.remove(&deleted_key) {
+                            // Check if this is the right page
+                            if deleted_page.wiki_id != wiki_id || deleted_page.path != path {
+                                self.deleted_pages.insert(deleted_key, deleted_page);
+                                return Ok(serde_json::to_vec(&WikiResponse::Error("Deleted page key mismatch".to_string())).unwrap());
+                            }
 
-                                    let page_key = format!("{}:{}", wiki_id, path);
+                            let page_key = format!("{}:{}", wiki_id, path);
 
-                                    // Check if page already exists
-                                    if self.pages.contains_key(&page_key) {
-                                        self.deleted_pages.insert(deleted_key, deleted_page);
-                                        return Ok(serde_json::to_vec(&WikiResponse::Error("Page already exists".to_string())).unwrap());
-                                    }
+                            // Check if page already exists
+                            if self.pages.contains_key(&page_key) {
+                                self.deleted_pages.insert(deleted_key, deleted_page);
+                                return Ok(serde_json::to_vec(&WikiResponse::Error("Page already exists".to_string())).unwrap());
+                            }
 
-                                    // Restore the page with its latest version
-                                    let history = deleted_page.history;
-                                    if let Some(latest_version) = history.versions.last() {
-                                        let page = WikiPage {
-                                            path: path.clone(),
-                                            wiki_id: wiki_id.clone(),
-                                            current_version: latest_version.clone(),
-                                            yrs_doc: latest_version.content.clone(),
-                                        };
+                            // Restore the page with its latest content, recorded as a new version
+                            let mut history = deleted_page.history;
+                            let latest = history.versions.len().checked_sub(1)
+                                .and_then(|index| history.version_doc(index).ok());
+                            if let Some(doc) = latest {
+                                let restored_version = history.record_version(PageVersion {
+                                    version_id: Uuid::new_v4().to_string(),
+                                    content: Vec::new(),
+                                    delta: false,
+                                    updated_by: user_id.clone(),
+                                    updated_at: Utc::now().to_rfc3339(),
+                                    commit_message: None,
+                                    change: PageChange::Restored {
+                                        deleted_at: deleted_page.deleted_at,
+                                        deleted_by: deleted_page.deleted_by,
+                                    },
+                                    tag: None,
+                                }, &doc);
+                                let page = WikiPage {
+                                    path: path.clone(),
+                                    wiki_id: wiki_id.clone(),
+                                    current_version: restored_version,
+                                    yrs_doc: doc.transact().encode_state_as_update_v1(&yrs::StateVector::default()),
+                                    protected: false,
+                                    lock: None,
+                                };
 
-                                        self.pages.insert(page_key.clone(), page);
-                                        self.page_histories.insert(page_key, history);
+                                self.pages.insert(page_key.clone(), page);
+                                self.page_histories.insert(page_key, history);
+                                self.resync_replicas(&wiki_id);
+                                self.audit(&wiki_id, &user_id, AuditAction::PageRestored, Some(&path), None);
+                                self.notify_page_watchers(&wiki_id, &path);
 
-                                        WikiResponse::Success(true)
-                                    } else {
-                                        WikiResponse::Error("No versions found in deleted page".to_string())
-                                    }
-                                } else {
-                                    WikiResponse::Error("Deleted page not found".to_string())
-                                }
+                                self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.clone() });
+                                self.notify(WsNotification::PageUpdated { wiki_id, path });
+                                WikiResponse::Success(true)
+                            } else {
+                                WikiResponse::Error("No versions found in deleted page".to_string())
                             }
-                            _ => WikiResponse::Error("Insufficient permissions".to_string()),
+                        } else {
+                            WikiResponse::Error("Deleted page not found".to_string())
                         }
                     }
                     None => WikiResponse::Error("Wiki not found".to_string()),
@@ -921,10 +2388,12 @@ impl WikiState {
             }
             WikiMessage::ListDeletedPages { wiki_id } => {
                 match self.wikis.get(&wiki_id) {
-                    Some(_wiki) => {
+                    Some(wiki) => {
                         let mut deleted_summaries = Vec::new();
                         for (key, deleted_page) in &self.deleted_pages {
-                            if deleted_page.wiki_id == wiki_id {
+                            if deleted_page.wiki_id == wiki_id
+                                && wiki.check_page_access(&caller, &deleted_page.path, WikiRole::Reader).is_ok()
+                            {
                                 deleted_summaries.push(DeletedPageSummary {
                                     path: deleted_page.path.clone(),
                                     deleted_at: deleted_page.deleted_at.clone(),
@@ -938,39 +2407,14 @@ impl WikiState {
                     None => WikiResponse::Error("Wiki not found".to_string()),
                 }
             }
-            WikiMessage::GetVersionDiff { wiki_id, path, version1_id, version2_id } => {
+            WikiMessage::GetVersionDiff { wiki_id, path, version1_id, version2_id, options } => {
                 match self.wikis.get(&wiki_id) {
                     Some(_wiki) => {
                         let page_key = format!("{}:{}", wiki_id, path);
                         if let Some(history) = self.page_histories.get(&page_key) {
-                            // Find the two versions
-                            let version1 = history.versions.iter().find(|v| v.version_id == version1_id);
-                            let version2 = history.versions.iter().find(|v| v.version_id == version2_id);
-
-                            match (version1, version2) {
-                                (Some(v1), Some(v2)) => {
-                                    // Decode content for both versions
-                                    let content1 = match self.decode_yrs_content(&v1.content) {
-                                        Ok(text) => text,
-                                        Err(_) => return Ok(serde_json::to_vec(&WikiResponse::Error("Failed to decode version 1 content".to_string())).unwrap()),
-                                    };
-                                    let content2 = match self.decode_yrs_content(&v2.content) {
-                                        Ok(text) => text,
-                                        Err(_) => return Ok(serde_json::to_vec(&WikiResponse::Error("Failed to decode version 2 content".to_string())).unwrap()),
-                                    };
-
-                                    // Calculate diff
-                                    let diff_lines = self.calculate_diff(&content1, &content2);
-
-                                    let version_diff = VersionDiff {
-                                        version1_id: version1_id.clone(),
-                                        version2_id: version2_id.clone(),
-                                        diff_lines,
-                                    };
-
-                                    WikiResponse::VersionDiff(version_diff)
-                                }
-                                _ => WikiResponse::Error("One or both versions not found".to_string()),
+                            match self.version_diff(history, &version1_id, &version2_id, &options) {
+                                Ok(version_diff) => WikiResponse::VersionDiff(version_diff),
+                                Err(e) => WikiResponse::Error(e),
                             }
                         } else {
                             WikiResponse::Error("Page history not found".to_string())
@@ -979,6 +2423,13 @@ impl WikiState {
                     None => WikiResponse::Error("Wiki not found".to_string()),
                 }
             }
+            WikiMessage::GetPageBlame { wiki_id, path } => {
+                match self.page_histories.get(&format!("{}:{}", wiki_id, path)) {
+                    Some(history) => WikiResponse::PageBlame(Self::page_blame(history)),
+                    None if self.wikis.contains_key(&wiki_id) => WikiResponse::Error("Page history not found".to_string()),
+                    None => WikiResponse::Error("Wiki not found".to_string()),
+                }
+            }
             WikiMessage::SendInvite { invite, wiki } => {
                 // Check if the invite is for this user
                 if invite.invitee_id != self.node_id {
@@ -997,31 +2448,280 @@ impl WikiState {
                         // Local wiki - store normally
                         self.wikis.insert(wiki.id.clone(), wiki);
                     }
+                    self.notify(WsNotification::InvitesUpdated);
                     WikiResponse::Success(true)
                 }
             }
             WikiMessage::InviteResponse { invite_id, status, invitee_id } => {
                 // Update the invite status on the inviter's node
-                if let Some(invite) = self.invites.get_mut(&invite_id) {
-                    invite.status = status.clone();
-
-                    // If accepted, update the wiki membership
-                    if status == InviteStatus::Accepted {
-                        if let Some(wiki) = self.wikis.get_mut(&invite.wiki_id) {
-                            wiki.members.insert(invitee_id, WikiRole::Reader);
+                match self.invites.get_mut(&invite_id).filter(|invite| invite.invitee_id == invitee_id) {
+                    Some(invite) if invite.status == InviteStatus::Revoked => {
+                        WikiResponse::Error("Invite was revoked".to_string())
+                    }
+                    Some(invite) if invite.status != InviteStatus::Pending => {
+                        WikiResponse::Error("Invite has already been processed".to_string())
+                    }
+                    Some(invite) if status == InviteStatus::Accepted && Self::invite_expired(invite) => {
+                        invite.status = InviteStatus::Expired;
+                        self.notify(WsNotification::InvitesUpdated);
+                        WikiResponse::Error("Invite has expired".to_string())
+                    }
+                    Some(invite) => {
+                        invite.status = status.clone();
+                        let wiki_id = invite.wiki_id.clone();
+                        let role = invite.role.clone();
+
+                        // If accepted, update the wiki membership
+                        if status == InviteStatus::Accepted {
+                            if let Some(wiki) = self.wikis.get_mut(&wiki_id) {
+                                wiki.members.insert(invitee_id.clone(), role.clone());
+                                self.audit(&wiki_id, &invitee_id, AuditAction::InviteAccepted, Some(&invitee_id), Some(format!("Joined as {:?}", role)));
+                                self.replicate_wiki(&wiki_id);
+                                self.notify(WsNotification::WikiUpdated { wiki_id });
+                            }
+                        } else if status == InviteStatus::Rejected {
+                            self.audit(&wiki_id, &invitee_id, AuditAction::InviteRejected, Some(&invitee_id), None);
+                        }
+                        self.notify(WsNotification::InvitesUpdated);
+                        WikiResponse::Success(true)
+                    }
+                    None => WikiResponse::Error("Invite not found".to_string()),
+                }
+            }
+            WikiMessage::RevokeInvite { invite_id, inviter_id } => {
+                match self.invites.get_mut(&invite_id) {
+                    Some(invite) if invite.inviter_id == inviter_id && invite.invitee_id == self.node_id => {
+                        if invite.status == InviteStatus::Pending {
+                            invite.status = InviteStatus::Revoked;
+
+                            // Drop the wiki details that came with the invite unless we've joined
+                            let remote_wiki_id = format!("{}@{}", invite.wiki_id, inviter_id);
+                            if !self.my_memberships.iter().any(|m| m.wiki_id == remote_wiki_id) {
+                                self.wikis.remove(&remote_wiki_id);
+                            }
+                            self.notify(WsNotification::InvitesUpdated);
+                        }
+                        WikiResponse::Success(true)
+                    }
+                    _ => WikiResponse::Error("Invite not found".to_string()),
+                }
+            }
+            WikiMessage::RequestToJoin { mut request } => {
+                match self.wikis.get(&request.wiki_id) {
+                    Some(wiki) if wiki.members.contains_key(&request.requester_id) => {
+                        WikiResponse::Error("Already a member of this wiki".to_string())
+                    }
+                    Some(wiki) if wiki.is_public => {
+                        WikiResponse::Error("Wiki is public; join it directly".to_string())
+                    }
+                    Some(wiki) => {
+                        let already_pending = self.join_requests.values().any(|existing| {
+                            existing.wiki_id == request.wiki_id
+                                && existing.requester_id == request.requester_id
+                                && existing.status == JoinRequestStatus::Pending
+                        });
+                        if already_pending {
+                            WikiResponse::Error("A request to join this wiki is already pending".to_string())
+                        } else {
+                            // Only the requester's own fields are taken on trust
+                            request.wiki_name = wiki.name.clone();
+                            request.host_id = self.node_id.clone();
+                            request.status = JoinRequestStatus::Pending;
+                            request.role = None;
+                            request.decided_by = None;
+                            request.decided_at = None;
+                            println!("{} asked to join wiki {}", request.requester_id, request.wiki_id);
+                            self.join_requests.insert(request.id.clone(), request);
+                            self.notify(WsNotification::JoinRequestsUpdated);
+                            WikiResponse::Success(true)
+                        }
+                    }
+                    None => WikiResponse::Error("Wiki not found".to_string()),
+                }
+            }
+            WikiMessage::JoinRequestDecision { request, wiki } => {
+                match self.join_requests.get_mut(&request.id) {
+                    Some(sent) if sent.requester_id == self.node_id && sent.host_id == request.host_id => {
+                        if sent.status == JoinRequestStatus::Pending {
+                            sent.status = request.status.clone();
+                            sent.role = request.role.clone();
+                            sent.decided_by = request.decided_by.clone();
+                            sent.decided_at = request.decided_at.clone();
+
+                            if let (JoinRequestStatus::Approved, Some(role), Some(mut wiki)) =
+                                (&request.status, request.role, wiki)
+                            {
+                                // Store the wiki with its remote reference, as accepted invites do
+                                let remote_wiki_id = format!("{}@{}", request.wiki_id, request.host_id);
+                                wiki.id = remote_wiki_id.clone();
+                                self.wikis.insert(remote_wiki_id.clone(), wiki);
+                                self.my_memberships.retain(|m| m.wiki_id != remote_wiki_id);
+                                self.my_memberships.push(WikiMembership {
+                                    wiki_id: remote_wiki_id,
+                                    role,
+                                    joined_at: Utc::now().to_rfc3339(),
+                                });
+                                self.notify(WsNotification::WikiListUpdated);
+                            }
+                            self.notify(WsNotification::JoinRequestsUpdated);
+                        }
+                        WikiResponse::Success(true)
+                    }
+                    _ => WikiResponse::Error("Join request not found".to_string()),
+                }
+            }
+            WikiMessage::OfferOwnership { transfer } => {
+                if transfer.to_id != self.node_id {
+                    WikiResponse::Error("This offer is not for this node".to_string())
+                } else {
+                    let key = format!("{}@{}", transfer.wiki_id, transfer.from_id);
+                    self.ownership_offers.insert(key, transfer);
+                    self.notify(WsNotification::OwnershipOffersUpdated);
+                    WikiResponse::Success(true)
+                }
+            }
+            WikiMessage::CancelOwnershipOffer { wiki_id, from_id } => {
+                if self.ownership_offers.remove(&format!("{}@{}", wiki_id, from_id)).is_some() {
+                    self.notify(WsNotification::OwnershipOffersUpdated);
+                }
+                WikiResponse::Success(true)
+            }
+            WikiMessage::RespondToOwnershipOffer { wiki_id, user_id, accept } => {
+                match self.wikis.get_mut(&wiki_id) {
+                    Some(wiki) => {
+                        match wiki.pending_transfer.clone().filter(|transfer| transfer.to_id == user_id) {
+                            None => WikiResponse::Error("No ownership transfer pending for this user".to_string()),
+                            Some(transfer) => {
+                                let expired = chrono::DateTime::parse_from_rfc3339(&transfer.expires_at)
+                                    .map_or(true, |expires_at| Utc::now() > expires_at);
+                                wiki.pending_transfer = None;
+                                let response = if expired {
+                                    WikiResponse::Error("Ownership offer has expired".to_string())
+                                } else if !wiki.members.contains_key(&user_id) {
+                                    WikiResponse::Error("Not a member of this wiki".to_string())
+                                } else if accept {
+                                    self.audit(&wiki_id, &user_id, AuditAction::OwnershipTransferred, Some(&user_id), Some(format!("From {}", transfer.from_id)));
+                                    self.move_wiki_to(&wiki_id, &user_id).await
+                                } else {
+                                    WikiResponse::Success(true)
+                                };
+                                if !matches!(response, WikiResponse::WikiHandedOver { .. }) {
+                                    self.replicate_wiki(&wiki_id);
+                                }
+                                self.notify(WsNotification::WikiUpdated { wiki_id });
+                                response
+                            }
                         }
                     }
+                    None => WikiResponse::Error("Wiki not found".to_string()),
+                }
+            }
+            WikiMessage::GetRecentChanges { wiki_id, offset, limit } => {
+                match self.collect_recent_changes(&wiki_id, &caller, offset, limit) {
+                    Ok(page) => WikiResponse::RecentChanges(page),
+                    Err(e) => WikiResponse::Error(e),
+                }
+            }
+            WikiMessage::RevertPage { wiki_id, path, version_id, user_id } => {
+                match self.revert_page_to(&wiki_id, &path, &version_id, &user_id) {
+                    Ok(new_path) => {
+                        self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.clone() });
+                        if new_path != path {
+                            self.notify(WsNotification::PageUpdated { wiki_id: wiki_id.clone(), path });
+                        }
+                        self.notify(WsNotification::PageUpdated { wiki_id, path: new_path });
+                        WikiResponse::Success(true)
+                    }
+                    Err(e) => WikiResponse::Error(e),
+                }
+            }
+            WikiMessage::TagVersion { wiki_id, path, version_id, tag, .. } => {
+                match self.set_version_tag(&wiki_id, &path, &version_id, tag) {
+                    Ok(()) => {
+                        self.notify(WsNotification::PageUpdated { wiki_id, path });
+                        WikiResponse::Success(true)
+                    }
+                    Err(e) => WikiResponse::Error(e),
+                }
+            }
+            WikiMessage::WatchPage { wiki_id, path, user_id, watch } => {
+                if self.wikis.contains_key(&wiki_id) {
+                    let watchers = self.watchers.entry(wiki_id).or_default();
+                    let watcher = PageWatcher { node_id: user_id, path };
+                    watchers.retain(|existing| existing != &watcher);
+                    if watch {
+                        watchers.push(watcher);
+                    }
+                    WikiResponse::Success(true)
+                } else {
+                    WikiResponse::Error("Wiki not found".to_string())
+                }
+            }
+            WikiMessage::PageChanged { wiki_id, host_id, change } => {
+                if self.record_watch_notice(&format!("{}@{}", wiki_id, host_id), change) {
+                    WikiResponse::Success(true)
+                } else {
+                    // Lets the host drop us as a watcher
+                    WikiResponse::Error("Not watching this wiki".to_string())
+                }
+            }
+            WikiMessage::GetAuditLog { wiki_id, query } => {
+                match self.query_audit_log(&wiki_id, &query) {
+                    Ok(page) => WikiResponse::AuditLog(page),
+                    Err(e) => WikiResponse::Error(e),
+                }
+            }
+            WikiMessage::MembershipRevoked { wiki_id, host_id } => {
+                println!("Removed from wiki {} by {}", wiki_id, host_id);
+                self.forget_remote_wiki(&format!("{}@{}", wiki_id, host_id));
+                self.notify(WsNotification::WikiListUpdated);
+                WikiResponse::Success(true)
+            }
+            WikiMessage::WikiDeleted { wiki_id, host_id } => {
+                println!("Wiki {} was deleted by its host {}", wiki_id, host_id);
+                // Replica copies go away with the wiki
+                if self.wikis.get(&wiki_id).is_some_and(|wiki| wiki.replica_of.as_deref() == Some(host_id.as_str())) {
+                    self.drop_wiki_copy(&wiki_id);
+                }
+                self.forget_remote_wiki(&format!("{}@{}", wiki_id, host_id));
+                self.notify(WsNotification::WikiListUpdated);
+                WikiResponse::Success(true)
+            }
+            WikiMessage::WikiRestored { mut wiki, host_id } => {
+                if let Some(role) = wiki.members.get(&self.node_id).cloned() {
+                    let remote_wiki_id = format!("{}@{}", wiki.id, host_id);
+                    wiki.id = remote_wiki_id.clone();
+                    self.wikis.insert(remote_wiki_id.clone(), wiki);
+                    self.my_memberships.retain(|m| m.wiki_id != remote_wiki_id);
+                    self.my_memberships.push(WikiMembership {
+                        wiki_id: remote_wiki_id,
+                        role,
+                        joined_at: Utc::now().to_rfc3339(),
+                    });
+                    self.notify(WsNotification::WikiListUpdated);
                 }
                 WikiResponse::Success(true)
             }
+            WikiMessage::WikiMoved { wiki_id, host_id, new_host_id } => {
+                println!("Wiki {} moved from {} to {}", wiki_id, host_id, new_host_id);
+                self.follow_moved_wiki(&wiki_id, &host_id, &new_host_id);
+                self.notify(WsNotification::WikiListUpdated);
+                WikiResponse::Success(true)
+            }
             WikiMessage::RoleUpdate { wiki_id, member_id, new_role } => {
                 // Handle role update notification
                 if member_id == self.node_id {
-                    // Update our local membership record
+                    // Update our local membership record; only the wiki's host can send this
+                    let remote_wiki_id = format!("{}@{}", wiki_id, caller);
+                    if let Some(wiki) = self.replicas.get_mut(&remote_wiki_id).and_then(|r| r.wiki.as_mut()) {
+                        wiki.members.insert(member_id.clone(), new_role.clone());
+                    }
+                    let mut notify_wiki_id = None;
                     if let Some(membership) = self.my_memberships.iter_mut()
-                        .find(|m| m.wiki_id == wiki_id || m.wiki_id.starts_with(&format!("{}@", wiki_id))) {
+                        .find(|m| m.wiki_id == remote_wiki_id) {
                         let old_role = membership.role.clone();
                         membership.role = new_role.clone();
+                        notify_wiki_id = Some(membership.wiki_id.clone());
                         println!("Your role in wiki {} has been updated from {:?} to {:?}", wiki_id, old_role, new_role);
                     }
 
@@ -1029,8 +2729,9 @@ impl WikiState {
                     // First try to find the wiki with a remote reference
                     let mut wiki_found = false;
                     for (stored_wiki_id, wiki) in self.wikis.iter_mut() {
-                        if stored_wiki_id == &wiki_id || stored_wiki_id.starts_with(&format!("{}@", wiki_id)) {
+                        if stored_wiki_id == &remote_wiki_id {
                             wiki.members.insert(member_id.clone(), new_role.clone());
+                            notify_wiki_id.get_or_insert_with(|| stored_wiki_id.clone());
                             wiki_found = true;
                             break;
                         }
@@ -1039,20 +2740,26 @@ impl WikiState {
                     if !wiki_found {
                         println!("Wiki {} not found locally for role update", wiki_id);
                     }
+
+                    if let Some(notify_wiki_id) = notify_wiki_id {
+                        self.notify(WsNotification::RoleUpdated {
+                            wiki_id: notify_wiki_id.clone(),
+                            new_role,
+                        });
+                        self.notify(WsNotification::WikiUpdated { wiki_id: notify_wiki_id });
+                    }
                 }
                 WikiResponse::Success(true)
             }
             WikiMessage::SearchPages { wiki_id, query } => {
                 // Search pages in the wiki
                 match self.wikis.get(&wiki_id) {
-                    Some(_wiki) => {
-                        // Allow search for both public and private wikis
-                        // In production, should verify requester is a member for private wikis
+                    Some(wiki) => {
                         let query_lower = query.to_lowercase();
                         let mut results: Vec<SearchResult> = Vec::new();
 
                         for (page_key, page) in &self.pages {
-                            if page.wiki_id != wiki_id {
+                            if page.wiki_id != wiki_id || wiki.check_page_access(&caller, &page.path, WikiRole::Reader).is_err() {
                                 continue;
                             }
 
@@ -1118,47 +2825,276 @@ impl WikiState {
                     None => WikiResponse::Error("Wiki not found".to_string()),
                 }
             }
-        };
-
-        Ok(serde_json::to_string(&response).unwrap().into_bytes())
-    }
-
-
-    #[http]
-    async fn create_wiki(&mut self, body: String) -> Result<String, String> {
-        let req: CreateWikiRequest = serde_json::from_str(&body)
-            .map_err(|e| format!("Invalid request: {}", e))?;
+            WikiMessage::SyncPage { wiki_id, path, state_vector } => {
+                match self.wikis.get(&wiki_id) {
+                    Some(_wiki) => {
+                        let page_key = format!("{}:{}", wiki_id, path);
+                        match (self.page_doc(&page_key), yrs::StateVector::decode_v1(&state_vector)) {
+                            (Some(doc), Ok(remote_state)) => {
+                                let txn = doc.transact();
+                                WikiResponse::PageSync {
+                                    update: txn.encode_diff_v1(&remote_state),
+                                    state_vector: txn.state_vector().encode_v1(),
+                                }
+                            }
+                            (None, _) => WikiResponse::Error("Page not found".to_string()),
+                            (_, Err(e)) => WikiResponse::Error(format!("Invalid state vector: {}", e)),
+                        }
+                    }
+                    None => WikiResponse::Error("Wiki not found".to_string()),
+                }
+            }
+            WikiMessage::ApplyPageUpdate { wiki_id, path, update, user_id, commit_message } => {
+                match self.wikis.get(&wiki_id) {
+                    Some(_wiki) => {
+                        let page_key = format!("{}:{}", wiki_id, path);
+                        let Some(doc) = self.page_doc(&page_key) else {
+                            return Ok(serde_json::to_vec(&WikiResponse::Error("Page not found".to_string())).unwrap());
+                        };
 
-        let wiki_id = Uuid::new_v4().to_string();
+                        if let Err(e) = self.check_page_editable(&wiki_id, &path, &user_id) {
+                            return Ok(serde_json::to_vec(&WikiResponse::Error(e)).unwrap());
+                        }
 
-        let wiki = Wiki {
-            id: wiki_id.clone(),
-            name: req.name,
-            description: req.description,
-            is_public: req.is_public,
-            created_by: self.node_id.clone(),
-            created_at: Utc::now().to_rfc3339(),
-            members: HashMap::from([(self.node_id.clone(), WikiRole::SuperAdmin)]),
-        };
+                        // A retitle moves the page, so the caller must be able to write there too
+                        if let Ok(content) = Self::preview_update(&doc, &update) {
+                            if let Err(e) = self.check_retitle(&wiki_id, &path, &content) {
+                                return Ok(serde_json::to_vec(&WikiResponse::Error(e)).unwrap());
+                            }
+                            let new_title = Self::extract_title_from_markdown(&content);
+                            let allowed = self.wikis.get(&wiki_id)
+                                .map(|wiki| wiki.check_page_access(&user_id, &new_title, WikiRole::Writer));
+                            if let Some(Err(reason)) = allowed {
+                                return Ok(serde_json::to_vec(&WikiResponse::PermissionDenied(PermissionError {
+                                    wiki_id,
+                                    node_id: user_id,
+                                    required_role: Some(WikiRole::Writer),
+                                    reason,
+                                })).unwrap());
+                            }
+                        }
 
-        self.wikis.insert(wiki_id.clone(), wiki.clone());
-        self.my_memberships.push(WikiMembership {
-            wiki_id: wiki_id.clone(),
-            role: WikiRole::SuperAdmin,
-            joined_at: Utc::now().to_rfc3339(),
-        });
+                        let state_before = doc.transact().state_vector();
+                        let applied = yrs::Update::decode_v1(&update)
+                            .map_err(|e| format!("Failed to decode update: {}", e))
+                            .and_then(|decoded| {
+                                doc.transact_mut().apply_update(decoded)
+                                    .map_err(|e| format!("Failed to apply update: {}", e))
+                            });
+
+                        match applied {
+                            Ok(()) => {
+                                // Record the merged text as a new version; write_page
+                                // relays the update to editors and replicas
+                                let text = doc.get_or_insert_text("content");
+                                let content = text.get_string(&doc.transact());
+                                let new_path = self.write_page_since(&wiki_id, &path, &content, &user_id, commit_message, Some(state_before));
 
-        Ok(serde_json::to_string(&serde_json::json!({
-            "wiki_id": wiki_id,
-            "wiki": wiki
-        })).unwrap())
+                                self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.clone() });
+                                if new_path != path {
+                                    self.notify(WsNotification::PageUpdated { wiki_id: wiki_id.clone(), path });
+                                }
+                                self.notify(WsNotification::PageUpdated { wiki_id, path: new_path });
+                                WikiResponse::Success(true)
+                            }
+                            Err(e) => WikiResponse::Error(e),
+                        }
+                    }
+                    None => WikiResponse::Error("Wiki not found".to_string()),
+                }
+            }
+            WikiMessage::LockPage { wiki_id, path, user_id, action, duration_minutes } => {
+                match self.update_page_lock(&wiki_id, &path, &user_id, &action, duration_minutes) {
+                    Ok(()) => {
+                        self.notify(WsNotification::PageUpdated { wiki_id, path });
+                        WikiResponse::Success(true)
+                    }
+                    Err(e) => WikiResponse::Error(e),
+                }
+            }
+            WikiMessage::ProtectPage { wiki_id, path, user_id, protected } => {
+                match self.set_page_protected(&wiki_id, &path, &user_id, protected) {
+                    Ok(()) => {
+                        self.notify(WsNotification::PageUpdated { wiki_id, path });
+                        WikiResponse::Success(true)
+                    }
+                    Err(e) => WikiResponse::Error(e),
+                }
+            }
+            WikiMessage::ReplicateWiki { mut wiki, pages, histories, deleted_pages } => {
+                // A snapshot replaces whatever copy we had
+                let wiki_id = wiki.id.clone();
+                self.drop_wiki_copy(&wiki_id);
+                wiki.replica_of = Some(caller);
+                self.install_snapshot(WikiSnapshot { wiki, pages, histories, deleted_pages });
+
+                self.notify(WsNotification::WikiUpdated { wiki_id: wiki_id.clone() });
+                self.notify(WsNotification::PageListUpdated { wiki_id });
+                WikiResponse::Success(true)
+            }
+            WikiMessage::ReplicateMembers { mut wiki } => {
+                if !wiki.replica_nodes.contains(&self.node_id) {
+                    // We've been removed as a replica
+                    let wiki_id = wiki.id.clone();
+                    self.drop_wiki_copy(&wiki_id);
+                    self.notify(WsNotification::WikiUpdated { wiki_id });
+                    WikiResponse::Success(true)
+                } else {
+                    let wiki_id = wiki.id.clone();
+                    wiki.replica_of = Some(caller);
+                    self.wikis.insert(wiki_id.clone(), wiki);
+                    self.notify(WsNotification::WikiUpdated { wiki_id });
+                    WikiResponse::Success(true)
+                }
+            }
+            WikiMessage::ReplicatePage { wiki_id, path, previous_path, update, version } => {
+                match self.apply_replicated_page(&wiki_id, &path, previous_path.as_deref(), &update, version) {
+                    Ok(()) => {
+                        self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.clone() });
+                        if let Some(previous_path) = previous_path {
+                            self.notify(WsNotification::PageUpdated { wiki_id: wiki_id.clone(), path: previous_path });
+                        }
+                        self.notify(WsNotification::PageUpdated { wiki_id, path });
+                        WikiResponse::Success(true)
+                    }
+                    Err(e) => WikiResponse::Error(e),
+                }
+            }
+            WikiMessage::ReplicatePageDeleted { deleted_key, deleted_page } => {
+                let wiki_id = deleted_page.wiki_id.clone();
+                let path = deleted_page.path.clone();
+                let page_key = format!("{}:{}", wiki_id, path);
+                self.pages.remove(&page_key);
+                self.page_histories.remove(&page_key);
+                self.active_docs.remove(&page_key);
+                self.deleted_pages.insert(Self::deleted_page_key(&deleted_key, &deleted_page), deleted_page);
+
+                self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.clone() });
+                self.notify(WsNotification::PageUpdated { wiki_id, path });
+                WikiResponse::Success(true)
+            }
+        };
+
+        self.flush_replication().await;
+
+        Ok(serde_json::to_string(&response).unwrap().into_bytes())
+    }
+
+    #[ws]
+    fn websocket(&mut self, channel_id: u32, message_type: WsMessageType, blob: LazyLoadBlob) {
+        if message_type == WsMessageType::Close {
+            self.ws_subscriptions.remove(&channel_id);
+            self.leave_all_edit_sessions(channel_id);
+            return;
+        }
+
+        let message: WsClientMessage = match serde_json::from_slice(blob.bytes()) {
+            Ok(msg) => msg,
+            Err(e) => {
+                println!("Failed to parse WebSocket message on channel {}: {}", channel_id, e);
+                return;
+            }
+        };
+
+        // Any message registers the channel so it receives wiki list updates
+        let subscriptions = self.ws_subscriptions.entry(channel_id).or_default();
+        match message {
+            WsClientMessage::Connect => {}
+            WsClientMessage::Subscribe { wiki_id, path } => {
+                let subscription = WsSubscription { wiki_id, path };
+                if !subscriptions.contains(&subscription) {
+                    subscriptions.push(subscription);
+                }
+            }
+            WsClientMessage::Unsubscribe { wiki_id, path } => {
+                subscriptions.retain(|s| !(s.wiki_id == wiki_id && s.path == path));
+            }
+            WsClientMessage::JoinEditSession { wiki_id, path, state_vector } => {
+                if let Err(error) = self.join_edit_session(channel_id, &wiki_id, &path, state_vector) {
+                    Self::push_to_channel(channel_id, &WsNotification::EditError { wiki_id, path, error });
+                }
+            }
+            WsClientMessage::LeaveEditSession { wiki_id, path } => {
+                self.leave_edit_session(channel_id, &wiki_id, &path);
+            }
+            WsClientMessage::EditUpdate { wiki_id, path, update } => {
+                if let Err(error) = self.apply_edit_update(channel_id, &wiki_id, &path, &update) {
+                    Self::push_to_channel(channel_id, &WsNotification::EditError { wiki_id, path, error });
+                }
+            }
+            WsClientMessage::Awareness { wiki_id, path, state } => {
+                let page_key = format!("{}:{}", wiki_id, path);
+                if let Some(channels) = self.edit_sessions.get(&page_key) {
+                    if channels.contains(&channel_id) {
+                        let notification = WsNotification::Awareness {
+                            wiki_id,
+                            path,
+                            channel_id,
+                            state: Some(state),
+                        };
+                        for other in channels.iter().filter(|c| **c != channel_id) {
+                            Self::push_to_channel(*other, &notification);
+                        }
+                    }
+                }
+            }
+            WsClientMessage::CommitEdit { wiki_id, path, commit_message } => {
+                if let Err(error) = self.commit_edit_session(channel_id, &wiki_id, &path, commit_message) {
+                    Self::push_to_channel(channel_id, &WsNotification::EditError { wiki_id, path, error });
+                }
+            }
+        }
+    }
+
+
+    #[http]
+    async fn create_wiki(&mut self, body: String) -> Result<String, String> {
+        let req: CreateWikiRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        let wiki_id = Uuid::new_v4().to_string();
+
+        let wiki = Wiki {
+            id: wiki_id.clone(),
+            name: req.name,
+            description: req.description,
+            is_public: req.is_public,
+            created_by: self.node_id.clone(),
+            created_at: Utc::now().to_rfc3339(),
+            members: HashMap::from([(self.node_id.clone(), WikiRole::SuperAdmin)]),
+            replica_nodes: Vec::new(),
+            replica_of: None,
+            acl: Vec::new(),
+            join_codes: Vec::new(),
+            default_join_role: WikiRole::Reader,
+            pending_transfer: None,
+            retention: RetentionPolicy::default(),
+        };
+
+        self.wikis.insert(wiki_id.clone(), wiki.clone());
+        self.my_memberships.push(WikiMembership {
+            wiki_id: wiki_id.clone(),
+            role: WikiRole::SuperAdmin,
+            joined_at: Utc::now().to_rfc3339(),
+        });
+        self.notify(WsNotification::WikiListUpdated);
+
+        Ok(serde_json::to_string(&serde_json::json!({
+            "wiki_id": wiki_id,
+            "wiki": wiki
+        })).unwrap())
     }
 
     #[http]
     async fn list_wikis(&mut self) -> Result<String, String> {
+        self.run_maintenance();
+        // Deliver replication left over from edits made outside a request (e.g. over /ws)
+        self.flush_replication().await;
+
         let mut all_wikis = Vec::new();
+        let memberships = self.my_memberships.clone();
 
-        for membership in &self.my_memberships {
+        for membership in &memberships {
             // First check if the wiki exists locally
             let base_wiki_id = if membership.wiki_id.contains('@') {
                 membership.wiki_id.split('@').next().unwrap_or(&membership.wiki_id)
@@ -1173,8 +3109,9 @@ impl WikiState {
                 continue;
             }
 
-            // Also check base wiki ID for backwards compatibility
-            if let Some(wiki) = self.wikis.get(base_wiki_id) {
+            // Also check base wiki ID for backwards compatibility; replica copies
+            // are read-only, so those are still reached through the host
+            if let Some(wiki) = self.wikis.get(base_wiki_id).filter(|wiki| wiki.replica_of.is_none()) {
                 all_wikis.push(wiki.clone());
                 continue;
             }
@@ -1188,25 +3125,24 @@ impl WikiState {
                     let node_id = parts[1];
 
                     // Try to fetch the actual wiki data from the remote node
-                    let target_address = Address::new(node_id, WIKI_PROCESS_ID);
                     let message = WikiMessage::GetWikiData {
                         wiki_id: wiki_id.to_string(),
                     };
 
-                    if let Ok(message_body) = serde_json::to_string(&message).map(|s| s.into_bytes()) {
-                        match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                            Ok(Ok(response_bytes)) => {
-                                if let Ok(response_str) = String::from_utf8(response_bytes) {
-                                    if let Ok(WikiResponse::WikiData(mut wiki)) = serde_json::from_str::<WikiResponse>(&response_str) {
-                                        // Override the ID to include the remote node reference
-                                        wiki.id = format!("{}@{}", wiki.id, node_id);
-                                        all_wikis.push(wiki);
-                                        continue;
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
+                    if let Ok(WikiResponse::WikiData(mut wiki)) = self.send_wiki_read(&membership.wiki_id, &message).await {
+                        // Override the ID to include the remote node reference
+                        wiki.id = format!("{}@{}", wiki.id, node_id);
+                        self.cache_remote_role(&wiki);
+                        self.replicas.entry(membership.wiki_id.clone()).or_default().wiki = Some(wiki.clone());
+                        self.reconcile_replica(&membership.wiki_id).await;
+                        all_wikis.push(wiki);
+                        continue;
+                    }
+
+                    // Host unreachable; use the replica's copy if we have one
+                    if let Some(wiki) = self.replicas.get(&membership.wiki_id).and_then(|r| r.wiki.clone()) {
+                        all_wikis.push(wiki);
+                        continue;
                     }
 
                     // Fallback if we can't fetch the data
@@ -1218,6 +3154,13 @@ impl WikiState {
                         created_by: node_id.to_string(),
                         created_at: membership.joined_at.clone(),
                         members: HashMap::new(),
+                        replica_nodes: Vec::new(),
+                        replica_of: None,
+                        acl: Vec::new(),
+                        join_codes: Vec::new(),
+                        default_join_role: WikiRole::Reader,
+                        pending_transfer: None,
+                        retention: RetentionPolicy::default(),
                     };
                     all_wikis.push(remote_wiki);
                 }
@@ -1243,7 +3186,7 @@ impl WikiState {
         let membership = self.my_memberships.iter()
             .find(|m| m.wiki_id == req.wiki_id || m.wiki_id.starts_with(&format!("{}@", req.wiki_id)));
 
-        if let Some(membership) = membership {
+        if let Some(membership) = membership.cloned() {
             // Check if this is a remote wiki reference
             if membership.wiki_id.contains('@') {
                 let parts: Vec<&str> = membership.wiki_id.split('@').collect();
@@ -1252,23 +3195,19 @@ impl WikiState {
                     let node_id = parts[1];
 
                     // Fetch the actual wiki data from the remote node
-                    let target_address = Address::new(node_id, WIKI_PROCESS_ID);
                     let message = WikiMessage::GetWikiData {
                         wiki_id: wiki_id.to_string(),
                     };
 
-                    let message_body = serde_json::to_string(&message)
-                        .map_err(|e| format!("Failed to serialize message: {}", e))?
-                        .into_bytes();
-
-                    match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                        Ok(Ok(response_bytes)) => {
-                            let response_str = String::from_utf8(response_bytes)
-                                .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                            match serde_json::from_str::<WikiResponse>(&response_str) {
-                                Ok(WikiResponse::WikiData(mut wiki)) => {
+                    match self.send_wiki_read(&membership.wiki_id, &message).await {
+                        Ok(response) => {
+                            match response {
+                                WikiResponse::WikiData(mut wiki) => {
                                     // Override the ID to include the remote node reference
                                     wiki.id = format!("{}@{}", wiki.id, node_id);
+                                    self.cache_remote_role(&wiki);
+                                    self.replicas.entry(membership.wiki_id.clone()).or_default().wiki = Some(wiki.clone());
+                                    self.reconcile_replica(&membership.wiki_id).await;
                                     return Ok(serde_json::to_string(&wiki).unwrap());
                                 }
                                 _ => {
@@ -1281,13 +3220,25 @@ impl WikiState {
                                         created_by: node_id.to_string(),
                                         created_at: membership.joined_at.clone(),
                                         members: HashMap::from([(self.node_id.clone(), membership.role.clone())]),
+                                        replica_nodes: Vec::new(),
+                                        replica_of: None,
+                                        acl: Vec::new(),
+                                        join_codes: Vec::new(),
+                                        default_join_role: WikiRole::Reader,
+                                        pending_transfer: None,
+                                        retention: RetentionPolicy::default(),
                                     };
                                     return Ok(serde_json::to_string(&remote_wiki).unwrap());
                                 }
                             }
                         }
-                        _ => {
-                            // Fallback to basic representation if we can't reach the remote node
+                        Err(_) => {
+                            // Serve the replica's copy if we can't reach the remote node
+                            if let Some(wiki) = self.replicas.get(&membership.wiki_id).and_then(|r| r.wiki.as_ref()) {
+                                return Ok(serde_json::to_string(wiki).unwrap());
+                            }
+
+                            // Fallback to basic representation
                             let remote_wiki = Wiki {
                                 id: wiki_id.to_string(),
                                 name: format!("Remote Wiki on {}", node_id),
@@ -1296,6 +3247,13 @@ impl WikiState {
                                 created_by: node_id.to_string(),
                                 created_at: membership.joined_at.clone(),
                                 members: HashMap::from([(self.node_id.clone(), membership.role.clone())]),
+                                replica_nodes: Vec::new(),
+                                replica_of: None,
+                                acl: Vec::new(),
+                                join_codes: Vec::new(),
+                                default_join_role: WikiRole::Reader,
+                                pending_transfer: None,
+                                retention: RetentionPolicy::default(),
                             };
                             return Ok(serde_json::to_string(&remote_wiki).unwrap());
                         }
@@ -1315,6 +3273,32 @@ impl WikiState {
         // Check if this is a remote wiki
         if let Some(remote_node_id) = &req.node_id {
             if remote_node_id != &self.node_id {
+                // Join codes work for private wikis too, so go straight to the host
+                if let Some(join_code) = &req.join_code {
+                    let message = WikiMessage::JoinWithCode {
+                        wiki_id: req.wiki_id.clone(),
+                        user_id: self.node_id.clone(),
+                        join_code: join_code.clone(),
+                    };
+                    let role = match self.send_wiki_message(remote_node_id, &message).await? {
+                        WikiResponse::JoinedWiki { role } => role,
+                        WikiResponse::Error(err) => return Err(format!("Remote node error: {}", err)),
+                        _ => return Err("Unexpected response from remote node".to_string()),
+                    };
+
+                    let membership_id = format!("{}@{}", req.wiki_id, remote_node_id);
+                    self.my_memberships.retain(|m| m.wiki_id != membership_id);
+                    self.my_memberships.push(WikiMembership {
+                        wiki_id: membership_id,
+                        role,
+                        joined_at: Utc::now().to_rfc3339(),
+                    });
+
+                    println!("Successfully joined remote wiki {} on node {} with a join code", req.wiki_id, remote_node_id);
+                    self.notify(WsNotification::WikiListUpdated);
+                    return Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap());
+                }
+
                 // This is a remote wiki - fetch its info first
                 let target_address = Address::new(remote_node_id, WIKI_PROCESS_ID);
 
@@ -1338,7 +3322,7 @@ impl WikiState {
                                 if let Ok(wiki_info) = serde_json::from_value::<serde_json::Value>(wiki_json) {
                                     if let Some(is_public) = wiki_info.get("is_public").and_then(|v| v.as_bool()) {
                                         if !is_public {
-                                            return Err("Cannot join private wiki on remote node".to_string());
+                                            return Err("Private wiki requires join code".to_string());
                                         }
                                     }
 
@@ -1356,20 +3340,28 @@ impl WikiState {
                                         Ok(Ok(join_response)) => {
                                             let join_response_str = String::from_utf8(join_response)
                                                 .map_err(|e| format!("Failed to convert join response to string: {}", e))?;
-                                            match serde_json::from_str::<WikiResponse>(&join_response_str) {
-                                                Ok(WikiResponse::Success(true)) => {
+                                            let role = match serde_json::from_str::<WikiResponse>(&join_response_str) {
+                                                Ok(WikiResponse::JoinedWiki { role }) => Some(role),
+                                                // Hosts that predate configurable join roles always grant Writer
+                                                Ok(WikiResponse::Success(true)) => Some(WikiRole::Writer),
+                                                _ => None,
+                                            };
+                                            match role {
+                                                Some(role) => {
                                                     // Store the membership with remote node reference
-                                                    // For public wikis, we get Writer role
+                                                    let membership_id = format!("{}@{}", req.wiki_id, remote_node_id);
+                                                    self.my_memberships.retain(|m| m.wiki_id != membership_id);
                                                     self.my_memberships.push(WikiMembership {
-                                                        wiki_id: format!("{}@{}", req.wiki_id, remote_node_id),
-                                                        role: WikiRole::Writer,
+                                                        wiki_id: membership_id,
+                                                        role,
                                                         joined_at: Utc::now().to_rfc3339(),
                                                     });
 
                                                     println!("Successfully joined remote wiki {} on node {}", req.wiki_id, remote_node_id);
+                                                    self.notify(WsNotification::WikiListUpdated);
                                                     return Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap());
                                                 }
-                                                _ => {
+                                                None => {
                                                     return Err("Failed to join remote wiki".to_string());
                                                 }
                                             }
@@ -1403,554 +3395,672 @@ impl WikiState {
         // Local wiki join
         let wiki = self.wikis.get_mut(&req.wiki_id)
             .ok_or_else(|| "Wiki not found".to_string())?;
-
-        if !wiki.is_public && req.join_code.is_none() {
-            return Err("Private wiki requires join code".to_string());
+        if wiki.replica_of.is_some() {
+            return Err("Wiki is a read-only replica".to_string());
         }
 
-        wiki.members.insert(self.node_id.clone(), WikiRole::Reader);
+        let role = match &req.join_code {
+            Some(join_code) => wiki.join_with_code(&self.node_id, join_code)?,
+            None if wiki.is_public => wiki.join_public(&self.node_id),
+            None => return Err("Private wiki requires join code".to_string()),
+        };
+        let actor = self.node_id.clone();
+        self.audit(&req.wiki_id, &actor, AuditAction::MemberJoined, Some(&actor), Some(format!("Joined as {:?}", role)));
 
+        self.my_memberships.retain(|m| m.wiki_id != req.wiki_id);
         self.my_memberships.push(WikiMembership {
             wiki_id: req.wiki_id.clone(),
-            role: WikiRole::Reader,
+            role,
             joined_at: Utc::now().to_rfc3339(),
         });
+        self.replicate_wiki(&req.wiki_id);
+        self.flush_replication().await;
+        self.notify(WsNotification::WikiListUpdated);
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id });
 
         Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
     }
 
     #[http]
-    async fn leave_wiki(&mut self, body: String) -> Result<String, String> {
-        let req: LeaveWikiRequest = serde_json::from_str(&body)
+    async fn create_join_code(&mut self, body: String) -> Result<String, String> {
+        let req: CreateJoinCodeRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
-        if let Some(wiki) = self.wikis.get_mut(&req.wiki_id) {
-            wiki.members.remove(&self.node_id);
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
+
+        if req.role == WikiRole::SuperAdmin {
+            return Err("Join codes can't grant SuperAdmin".to_string());
+        }
+        if req.max_uses == Some(0) {
+            return Err("max_uses must be at least 1".to_string());
         }
+        let expires_at = match req.expires_in_hours {
+            Some(hours) if hours <= 0 => return Err("expires_in_hours must be positive".to_string()),
+            Some(hours) => Some((Utc::now() + chrono::Duration::hours(hours)).to_rfc3339()),
+            None => None,
+        };
 
-        self.my_memberships.retain(|m| m.wiki_id != req.wiki_id);
+        let wiki = self.wikis.get_mut(&req.wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+        let code = Uuid::new_v4().to_string();
+        wiki.join_codes.push(JoinCode {
+            code: code.clone(),
+            role: req.role,
+            created_by: self.node_id.clone(),
+            created_at: Utc::now().to_rfc3339(),
+            max_uses: req.max_uses,
+            uses: 0,
+            expires_at,
+        });
 
-        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+        self.replicate_wiki(&req.wiki_id);
+        self.flush_replication().await;
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id.clone() });
+
+        Ok(serde_json::to_string(&CreateJoinCodeResponse {
+            code,
+            wiki_id: req.wiki_id,
+            node_id: self.node_id.clone(),
+        }).unwrap())
     }
 
     #[http]
-    async fn update_wiki_settings(&mut self, body: String) -> Result<String, String> {
-        let req: UpdateWikiRequest = serde_json::from_str(&body)
+    async fn revoke_join_code(&mut self, body: String) -> Result<String, String> {
+        let req: RevokeJoinCodeRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
         self.check_permission(&req.wiki_id, WikiRole::Admin)?;
 
         let wiki = self.wikis.get_mut(&req.wiki_id)
             .ok_or_else(|| "Wiki not found".to_string())?;
-
-        if let Some(name) = req.name {
-            wiki.name = name;
-        }
-        if let Some(description) = req.description {
-            wiki.description = description;
-        }
-        if let Some(is_public) = req.is_public {
-            wiki.is_public = is_public;
+        let count_before = wiki.join_codes.len();
+        wiki.join_codes.retain(|join_code| join_code.code != req.code);
+        if wiki.join_codes.len() == count_before {
+            return Err("Join code not found".to_string());
         }
 
+        self.replicate_wiki(&req.wiki_id);
+        self.flush_replication().await;
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id });
+
         Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
     }
 
     #[http]
-    async fn manage_member(&mut self, body: String) -> Result<String, String> {
-        let req: ManageMemberRequest = serde_json::from_str(&body)
+    async fn transfer_ownership(&mut self, body: String) -> Result<String, String> {
+        let req: TransferOwnershipRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
-        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
+        self.check_permission(&req.wiki_id, WikiRole::SuperAdmin)?;
 
         let wiki = self.wikis.get_mut(&req.wiki_id)
             .ok_or_else(|| "Wiki not found".to_string())?;
+        if wiki.created_by != self.node_id {
+            return Err("Only the owner can transfer a wiki".to_string());
+        }
 
-        // Store the previous role for comparison
-        let previous_role = wiki.members.get(&req.member_id).cloned();
-        let wiki_name = wiki.name.clone();
-        let wiki_id = wiki.id.clone();
-
-        match req.action.as_str() {
-            "add" => {
-                if let Some(role) = req.role.clone() {
-                    wiki.members.insert(req.member_id.clone(), role.clone());
-
-                    // Send notification to the new member
-                    if req.member_id != self.node_id {
-                        let target_address = Address::new(&req.member_id, WIKI_PROCESS_ID);
-                        let message = WikiMessage::RoleUpdate {
-                            wiki_id: wiki_id.clone(),
-                            member_id: req.member_id.clone(),
-                            new_role: role,
-                        };
+        // Withdraw any earlier offer first
+        if let Some(previous) = wiki.pending_transfer.take() {
+            let message = WikiMessage::CancelOwnershipOffer {
+                wiki_id: previous.wiki_id,
+                from_id: previous.from_id,
+            };
+            let _ = self.send_wiki_message(&previous.to_id, &message).await;
+        }
 
-                        if let Ok(message_body) = serde_json::to_string(&message).map(|s| s.into_bytes()) {
-                            // Fire and forget notification
-                            let _ = caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await;
-                        }
-                    }
-                }
+        if let Some(new_owner_id) = req.new_owner_id {
+            let wiki = self.wikis.get(&req.wiki_id)
+                .ok_or_else(|| "Wiki not found".to_string())?;
+            if new_owner_id == self.node_id {
+                return Err("You already own this wiki".to_string());
+            }
+            if !wiki.members.contains_key(&new_owner_id) {
+                return Err("The new owner must be a member of the wiki".to_string());
             }
-            "remove" => {
-                wiki.members.remove(&req.member_id);
 
-                // Notify the removed member
-                if req.member_id != self.node_id && previous_role.is_some() {
-                    // For removed members, we can send a special notification
-                    // Note: They won't have access to the wiki anymore, but they should know they were removed
-                    println!("Member {} removed from wiki {}", req.member_id, wiki_name);
-                }
+            let now = Utc::now();
+            let transfer = OwnershipTransfer {
+                wiki_id: req.wiki_id.clone(),
+                wiki_name: wiki.name.clone(),
+                from_id: self.node_id.clone(),
+                to_id: new_owner_id.clone(),
+                requested_at: now.to_rfc3339(),
+                expires_at: (now + chrono::Duration::days(INVITE_VALID_DAYS)).to_rfc3339(),
+            };
+            let message = WikiMessage::OfferOwnership { transfer: transfer.clone() };
+            match self.send_wiki_message(&new_owner_id, &message).await {
+                Ok(WikiResponse::Success(true)) => {}
+                Ok(WikiResponse::Error(err)) => return Err(format!("Remote error: {}", err)),
+                Ok(_) => return Err("Unexpected response from remote node".to_string()),
+                Err(_) => return Err("Failed to reach the new owner's node".to_string()),
             }
-            "update" => {
-                if let Some(role) = req.role.clone() {
-                    wiki.members.insert(req.member_id.clone(), role.clone());
+            if let Some(wiki) = self.wikis.get_mut(&req.wiki_id) {
+                wiki.pending_transfer = Some(transfer);
+            }
+        }
 
-                    // Send notification if role actually changed
-                    if req.member_id != self.node_id && previous_role.as_ref() != Some(&role) {
-                        let target_address = Address::new(&req.member_id, WIKI_PROCESS_ID);
-                        let message = WikiMessage::RoleUpdate {
-                            wiki_id: wiki_id.clone(),
-                            member_id: req.member_id.clone(),
-                            new_role: role,
-                        };
+        self.replicate_wiki(&req.wiki_id);
+        self.flush_replication().await;
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id });
 
-                        if let Ok(message_body) = serde_json::to_string(&message).map(|s| s.into_bytes()) {
-                            // Fire and forget notification
-                            let _ = caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await;
-                        }
-                    }
-                }
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
+    #[http]
+    async fn list_ownership_offers(&mut self) -> Result<String, String> {
+        let offers: Vec<&OwnershipTransfer> = self.ownership_offers.values().collect();
+        Ok(serde_json::to_string(&offers).unwrap())
+    }
+
+    #[http]
+    async fn respond_to_ownership_offer(&mut self, body: String) -> Result<String, String> {
+        let req: RespondToOwnershipOfferRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        let offer = self.ownership_offers.get(&req.wiki_id)
+            .cloned()
+            .ok_or_else(|| "Ownership offer not found".to_string())?;
+
+        if req.accept {
+            if self.wikis.get(&offer.wiki_id).is_some_and(|wiki| wiki.replica_of.is_none()) {
+                return Err("A wiki with this ID already exists on this node".to_string());
             }
-            _ => return Err("Invalid action".to_string()),
+            // Edits made offline go to the current host before it hands the wiki over
+            self.reconcile_replica(&format!("{}@{}", offer.wiki_id, offer.from_id)).await;
+        }
+
+        let message = WikiMessage::RespondToOwnershipOffer {
+            wiki_id: offer.wiki_id.clone(),
+            user_id: self.node_id.clone(),
+            accept: req.accept,
+        };
+        let result = match self.send_wiki_message(&offer.from_id, &message).await {
+            Ok(WikiResponse::WikiHandedOver { snapshot, watchers }) if req.accept => Ok(Some((snapshot, watchers))),
+            Ok(WikiResponse::Success(true)) if !req.accept => Ok(None),
+            Ok(WikiResponse::Error(err)) => Err(format!("Remote error: {}", err)),
+            Ok(_) => Err("Unexpected response from remote node".to_string()),
+            Err(_) => return Err("Failed to reach the wiki's host".to_string()),
+        };
+
+        // The host has settled the offer either way
+        self.ownership_offers.remove(&req.wiki_id);
+        self.notify(WsNotification::OwnershipOffersUpdated);
+        if let Some((snapshot, watchers)) = result? {
+            // We host the wiki now
+            self.take_over_wiki(&offer.from_id, snapshot, watchers);
+            self.flush_replication().await;
+            self.notify(WsNotification::WikiListUpdated);
+            self.notify(WsNotification::WikiUpdated { wiki_id: offer.wiki_id });
         }
 
         Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
     }
 
     #[http]
-    async fn create_page(&mut self, body: String) -> Result<String, String> {
-        let req: CreatePageRequest = serde_json::from_str(&body)
+    async fn delete_wiki(&mut self, body: String) -> Result<String, String> {
+        let req: DeleteWikiRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
+        self.check_permission(&req.wiki_id, WikiRole::SuperAdmin)?;
 
-        // Check if this is a remote wiki
-        if req.wiki_id.contains('@') {
-            let parts: Vec<&str> = req.wiki_id.split('@').collect();
-            if parts.len() == 2 {
-                let wiki_id = parts[0];
-                let node_id = parts[1];
+        let actor = self.node_id.clone();
+        self.audit(&req.wiki_id, &actor, AuditAction::WikiDeleted, None, None);
+        let wiki = self.wikis.remove(&req.wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+        let prefix = format!("{}:", req.wiki_id);
+        self.edit_sessions.retain(|key, _| !key.starts_with(&prefix));
+        self.replication_outbox.retain(|key, _| !key.starts_with(&format!("{}@", req.wiki_id)));
 
-                // Send create page request to remote node
-                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::CreatePage {
-                    wiki_id: wiki_id.to_string(),
-                    path: req.path.clone(),
-                    initial_content: req.initial_content.clone(),
-                    user_id: self.node_id.clone(),
-                    commit_message: req.commit_message.clone(),
-                };
+        let now = Utc::now();
+        self.deleted_wikis.insert(req.wiki_id.clone(), DeletedWiki {
+            wiki: wiki.clone(),
+            deleted_at: now.to_rfc3339(),
+            deleted_by: self.node_id.clone(),
+            purge_at: (now + chrono::Duration::days(WIKI_DELETE_GRACE_DAYS)).to_rfc3339(),
+        });
 
-                let message_body = serde_json::to_string(&message)
-                    .map_err(|e| format!("Failed to serialize message: {}", e))?
-                    .into_bytes();
+        // Best effort: members and replicas drop their memberships and copies
+        let message = WikiMessage::WikiDeleted {
+            wiki_id: req.wiki_id.clone(),
+            host_id: self.node_id.clone(),
+        };
+        let recipients: HashSet<&String> = wiki.members.keys().chain(wiki.replica_nodes.iter()).collect();
+        for node_id in recipients {
+            if node_id != &self.node_id {
+                let _ = self.send_wiki_message(node_id, &message).await;
+            }
+        }
 
-                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                    Ok(Ok(response_bytes)) => {
-                        let response_str = String::from_utf8(response_bytes)
-                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                        match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::Success(true)) => {
-                                return Ok(serde_json::to_string(&CreatePageResponse {
-                                    success: true,
-                                    path: req.path.clone(),
-                                }).unwrap());
-                            }
-                            Ok(WikiResponse::Error(err)) => {
-                                return Err(format!("Remote error: {}", err));
-                            }
-                            _ => {
-                                return Err("Unexpected response from remote node".to_string());
-                            }
-                        }
-                    }
-                    _ => {
-                        return Err("Failed to create page on remote wiki".to_string());
-                    }
-                }
-            }
-        }
+        self.notify(WsNotification::WikiListUpdated);
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id });
 
-        // Local wiki handling
-        self.check_permission(&req.wiki_id, WikiRole::Writer)?;
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
 
-        // Extract title from the initial content
-        let title = Self::extract_title_from_markdown(&req.initial_content);
-        let page_key = format!("{}:{}", req.wiki_id, title);
+    #[http]
+    async fn list_deleted_wikis(&mut self) -> Result<String, String> {
+        self.run_maintenance();
+        let deleted: Vec<&DeletedWiki> = self.deleted_wikis
+            .values()
+            .filter(|deleted| deleted.wiki.members.get(&self.node_id) == Some(&WikiRole::SuperAdmin))
+            .collect();
 
-        let doc = Doc::new();
-        let text = doc.get_or_insert_text("content");
+        Ok(serde_json::to_string(&deleted).unwrap())
+    }
 
-        {
-            let mut txn = doc.transact_mut();
-            text.insert(&mut txn, 0, &req.initial_content);
-            txn.commit();
+    #[http]
+    async fn restore_wiki(&mut self, body: String) -> Result<String, String> {
+        let req: RestoreWikiRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        let deleted = self.deleted_wikis.get(&req.wiki_id)
+            .ok_or_else(|| "Deleted wiki not found".to_string())?;
+        if deleted.wiki.members.get(&self.node_id) != Some(&WikiRole::SuperAdmin) {
+            return Err("Insufficient permissions".to_string());
         }
 
-        let mut encoder = EncoderV1::new();
-        doc.transact().encode_state_as_update(&yrs::StateVector::default(), &mut encoder);
-        let update = encoder.to_vec();
-
-        // Create the first version
-        let version_id = Uuid::new_v4().to_string();
-        let first_version = PageVersion {
-            version_id: version_id.clone(),
-            content: update.clone(),
-            updated_by: self.node_id.clone(),
-            updated_at: Utc::now().to_rfc3339(),
-            commit_message: req.commit_message,
-        };
-
-        let page = WikiPage {
-            path: title.clone(),
-            wiki_id: req.wiki_id.clone(),
-            current_version: first_version.clone(),
-            yrs_doc: update,
+        let Some(deleted) = self.deleted_wikis.remove(&req.wiki_id) else {
+            return Err("Deleted wiki not found".to_string());
         };
+        let wiki = deleted.wiki;
+        self.wikis.insert(req.wiki_id.clone(), wiki.clone());
+        let actor = self.node_id.clone();
+        self.audit(&req.wiki_id, &actor, AuditAction::WikiRestored, None, None);
 
-        // Create page history
-        let history = PageHistory {
-            path: title.clone(),
-            wiki_id: req.wiki_id.clone(),
-            versions: vec![first_version],
-            current_version_id: version_id,
+        // Members get their membership back; replicas start over from a snapshot
+        let message = WikiMessage::WikiRestored {
+            wiki: wiki.clone(),
+            host_id: self.node_id.clone(),
         };
+        for node_id in wiki.members.keys() {
+            if node_id != &self.node_id {
+                let _ = self.send_wiki_message(node_id, &message).await;
+            }
+        }
+        self.resync_replicas(&req.wiki_id);
+        self.flush_replication().await;
 
-        self.pages.insert(page_key.clone(), page);
-        self.page_histories.insert(page_key.clone(), history);
-        self.active_docs.insert(page_key, doc);
+        self.notify(WsNotification::WikiListUpdated);
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id });
 
-        Ok(serde_json::to_string(&CreatePageResponse {
-            success: true,
-            path: title,
-        }).unwrap())
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
     }
 
     #[http]
-    async fn update_page(&mut self, body: String) -> Result<String, String> {
-        let req: UpdatePageRequest = serde_json::from_str(&body)
+    async fn leave_wiki(&mut self, body: String) -> Result<String, String> {
+        let req: LeaveWikiRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
 
-        // Check if this is a remote wiki
-        if req.wiki_id.contains('@') {
-            let parts: Vec<&str> = req.wiki_id.split('@').collect();
-            if parts.len() == 2 {
-                let wiki_id = parts[0];
-                let node_id = parts[1];
-
-                // Send update page request to remote node
-                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::UpdatePage {
-                    wiki_id: wiki_id.to_string(),
-                    path: req.path.clone(),
-                    content: req.content.clone(),
-                    user_id: self.node_id.clone(),
-                    commit_message: req.commit_message.clone(),
-                };
-
-                let message_body = serde_json::to_string(&message)
-                    .map_err(|e| format!("Failed to serialize message: {}", e))?
-                    .into_bytes();
-
-                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                    Ok(Ok(response_bytes)) => {
-                        let response_str = String::from_utf8(response_bytes)
-                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                        match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::Success(true)) => {
-                                return Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap());
-                            }
-                            Ok(WikiResponse::Error(err)) => {
-                                return Err(format!("Remote error: {}", err));
-                            }
-                            _ => {
-                                return Err("Unexpected response from remote node".to_string());
-                            }
-                        }
-                    }
-                    _ => {
-                        return Err("Failed to update page on remote wiki".to_string());
-                    }
+        if let Some((wiki_id, node_id)) = req.wiki_id.split_once('@') {
+            // Tell the host, so we stop being listed as a member there
+            let message = WikiMessage::LeaveWiki {
+                wiki_id: wiki_id.to_string(),
+                user_id: self.node_id.clone(),
+            };
+            match self.send_wiki_message(node_id, &message).await {
+                Ok(WikiResponse::Success(true)) => {}
+                // Nothing left to leave on the host
+                Ok(WikiResponse::Error(err)) if err == "Not a member of this wiki" || err == "Wiki not found" => {}
+                Ok(WikiResponse::Error(err)) => return Err(format!("Remote error: {}", err)),
+                Ok(_) => return Err("Unexpected response from remote node".to_string()),
+                Err(_) => return Err("Failed to reach wiki host".to_string()),
+            }
+            self.forget_remote_wiki(&req.wiki_id);
+        } else {
+            if let Some(wiki) = self.wikis.get_mut(&req.wiki_id) {
+                if wiki.members.contains_key(&self.node_id) {
+                    wiki.check_member_change(&self.node_id, &self.node_id, None)?;
+                }
+                if wiki.members.remove(&self.node_id).is_some() {
+                    let actor = self.node_id.clone();
+                    self.audit(&req.wiki_id, &actor, AuditAction::MemberLeft, Some(&actor), None);
                 }
+                self.replicate_wiki(&req.wiki_id);
+                self.flush_replication().await;
             }
+            self.my_memberships.retain(|m| m.wiki_id != req.wiki_id);
         }
+        self.notify(WsNotification::WikiListUpdated);
 
-        // Local wiki handling
-        self.check_permission(&req.wiki_id, WikiRole::Writer)?;
-        let old_page_key = format!("{}:{}", req.wiki_id, req.path);
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
 
-        // Extract the new title from the content
-        let new_title = Self::extract_title_from_markdown(&req.content);
-        let new_page_key = format!("{}:{}", req.wiki_id, new_title);
+    #[http]
+    async fn update_wiki_settings(&mut self, body: String) -> Result<String, String> {
+        let req: UpdateWikiRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
 
-        // Check if title has changed
-        let title_changed = req.path != new_title;
+        let wiki = self.wikis.get_mut(&req.wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
 
-        let doc = if title_changed {
-            // Remove the old page and doc
-            if let Some(old_doc) = self.active_docs.remove(&old_page_key) {
-                self.pages.remove(&old_page_key);
-                old_doc
-            } else if let Some(page) = self.pages.remove(&old_page_key) {
-                let new_doc = Doc::new();
-                {
-                    let mut txn = new_doc.transact_mut();
-                    if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
-                        let _ = txn.apply_update(update);
-                    }
-                }
-                new_doc
-            } else {
-                Doc::new()
+        let mut changes = Vec::new();
+        if let Some(default_join_role) = &req.default_join_role {
+            // Anyone can join a public wiki, so never hand out admin rights that way
+            if !WikiRole::Writer.includes(default_join_role) {
+                return Err("Default join role must be Reader or Writer".to_string());
             }
-        } else {
-            self.active_docs.entry(old_page_key.clone())
-                .or_insert_with(|| {
-                    if let Some(page) = self.pages.get(&old_page_key) {
-                        let new_doc = Doc::new();
-                        {
-                            let mut txn = new_doc.transact_mut();
-                            if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
-                                let _ = txn.apply_update(update);
-                            }
-                        }
-                        new_doc
-                    } else {
-                        Doc::new()
-                    }
-                })
-                .clone()
-        };
+        }
+        if req.retention.as_ref().is_some_and(|retention| retention.keep_last == Some(0)) {
+            return Err("Retention must keep at least one version".to_string());
+        }
+        if let Some(name) = req.name {
+            changes.push(format!("name: {}", name));
+            wiki.name = name;
+        }
+        if let Some(description) = req.description {
+            changes.push("description".to_string());
+            wiki.description = description;
+        }
+        if let Some(is_public) = req.is_public {
+            changes.push(format!("is_public: {}", is_public));
+            wiki.is_public = is_public;
+        }
+        if let Some(default_join_role) = req.default_join_role {
+            changes.push(format!("default_join_role: {:?}", default_join_role));
+            wiki.default_join_role = default_join_role;
+        }
+        if let Some(retention) = req.retention {
+            changes.push(format!("retention: {:?}", retention));
+            wiki.retention = retention;
+        }
 
-        let text = doc.get_or_insert_text("content");
-        {
-            let mut txn = doc.transact_mut();
+        let actor = self.node_id.clone();
+        self.audit(&req.wiki_id, &actor, AuditAction::SettingsUpdated, None, Some(changes.join(", ")));
+        self.replicate_wiki(&req.wiki_id);
+        self.flush_replication().await;
 
-            let current_len = text.len(&txn);
-            if current_len > 0 {
-                text.remove_range(&mut txn, 0, current_len);
-            }
-            text.insert(&mut txn, 0, &req.content);
-            txn.commit();
-        }
+        self.notify(WsNotification::WikiListUpdated);
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id });
 
-        let mut encoder = EncoderV1::new();
-        doc.transact().encode_state_as_update(&yrs::StateVector::default(), &mut encoder);
-        let update = encoder.to_vec();
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
 
-        // Create new version
-        let version_id = Uuid::new_v4().to_string();
-        let new_version = PageVersion {
-            version_id: version_id.clone(),
-            content: update.clone(),
-            updated_by: self.node_id.clone(),
-            updated_at: Utc::now().to_rfc3339(),
-            commit_message: req.commit_message,
-        };
+    #[http]
+    async fn get_audit_log(&mut self, body: String) -> Result<String, String> {
+        let req: GetAuditLogRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
 
-        let page = WikiPage {
-            path: new_title.clone(),
-            wiki_id: req.wiki_id.clone(),
-            current_version: new_version.clone(),
-            yrs_doc: update,
-        };
+        // Only the host keeps the log
+        if let Some((wiki_id, node_id)) = req.wiki_id.split_once('@') {
+            let message = WikiMessage::GetAuditLog {
+                wiki_id: wiki_id.to_string(),
+                query: req.query,
+            };
+            return match self.send_wiki_message(node_id, &message).await? {
+                WikiResponse::AuditLog(page) => Ok(serde_json::to_string(&page).unwrap()),
+                WikiResponse::Error(err) => Err(format!("Remote error: {}", err)),
+                _ => Err("Unexpected response from remote node".to_string()),
+            };
+        }
 
-        // Handle history - if title changed, we need to move the history
-        if title_changed {
-            if let Some(mut history) = self.page_histories.remove(&old_page_key) {
-                history.path = new_title.clone();
-                history.versions.push(new_version);
-                history.current_version_id = version_id;
-                self.page_histories.insert(new_page_key.clone(), history);
-            } else {
-                // Create new history if it doesn't exist
-                let history = PageHistory {
-                    path: new_title.clone(),
-                    wiki_id: req.wiki_id.clone(),
-                    versions: vec![new_version],
-                    current_version_id: version_id,
-                };
-                self.page_histories.insert(new_page_key.clone(), history);
-            }
-        } else {
-            // Update existing history
-            if let Some(history) = self.page_histories.get_mut(&old_page_key) {
-                history.versions.push(new_version);
-                history.current_version_id = version_id;
-            } else {
-                // Create new history if it doesn't exist
-                let history = PageHistory {
-                    path: new_title.clone(),
-                    wiki_id: req.wiki_id.clone(),
-                    versions: vec![new_version],
-                    current_version_id: version_id,
-                };
-                self.page_histories.insert(old_page_key.clone(), history);
-            }
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
+        let page = self.query_audit_log(&req.wiki_id, &req.query)?;
+        Ok(serde_json::to_string(&page).unwrap())
+    }
+
+    #[http]
+    async fn purge_history(&mut self, body: String) -> Result<String, String> {
+        let req: PurgeHistoryRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
+        if !self.wikis.contains_key(&req.wiki_id) {
+            return Err("Wiki not found".to_string());
         }
 
-        // Insert with new key
-        self.pages.insert(new_page_key.clone(), page);
-        self.active_docs.insert(new_page_key, doc);
+        let actor = self.node_id.clone();
+        let result = self.purge_history_for(&req.wiki_id, &actor);
+        self.flush_replication().await;
 
-        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+        if result.versions_removed > 0 || result.deleted_pages_removed > 0 {
+            self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id });
+        }
+
+        Ok(serde_json::to_string(&result).unwrap())
     }
 
     #[http]
-    async fn get_page(&mut self, body: String) -> Result<String, String> {
-        let req: GetPageRequest = serde_json::from_str(&body)
+    async fn manage_member(&mut self, body: String) -> Result<String, String> {
+        let req: ManageMemberRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
 
-        // Check if this is for a remote wiki
-        if req.wiki_id.contains('@') {
-            let parts: Vec<&str> = req.wiki_id.split('@').collect();
-            if parts.len() == 2 {
-                let wiki_id = parts[0];
-                let node_id = parts[1];
+        let wiki = self.wikis.get_mut(&req.wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
 
-                // Fetch page from remote node
-                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::GetWikiPage {
-                    wiki_id: wiki_id.to_string(),
-                    path: req.path.clone(),
-                };
+        let new_role = match req.action.as_str() {
+            "remove" => None,
+            _ => req.role.as_ref(),
+        };
+        if req.action == "remove" || new_role.is_some() {
+            wiki.check_member_change(&self.node_id, &req.member_id, new_role)?;
+        }
 
-                let message_body = serde_json::to_string(&message)
-                    .map_err(|e| format!("Failed to serialize message: {}", e))?
-                    .into_bytes();
+        // Store the previous role for comparison
+        let previous_role = wiki.members.get(&req.member_id).cloned();
+        let wiki_name = wiki.name.clone();
+        let wiki_id = wiki.id.clone();
 
-                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                    Ok(Ok(response_bytes)) => {
-                        let response_str = String::from_utf8(response_bytes)
-                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                        match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::PageData(page_info)) => {
-                                return Ok(serde_json::to_string(&page_info).unwrap());
-                            }
-                            Ok(WikiResponse::Error(err)) => {
-                                return Err(format!("Remote error: {}", err));
-                            }
-                            _ => {
-                                return Err("Failed to fetch page from remote wiki".to_string());
-                            }
-                        }
-                    }
-                    _ => {
-                        return Err("Failed to contact remote wiki".to_string());
+        match req.action.as_str() {
+            "add" => {
+                if let Some(role) = req.role.clone() {
+                    wiki.members.insert(req.member_id.clone(), role.clone());
+
+                    // Send notification to the new member
+                    if req.member_id != self.node_id {
+                        let target_address = Address::new(&req.member_id, WIKI_PROCESS_ID);
+                        let message = WikiMessage::RoleUpdate {
+                            wiki_id: wiki_id.clone(),
+                            member_id: req.member_id.clone(),
+                            new_role: role,
+                        };
+
+                        if let Ok(message_body) = serde_json::to_string(&message).map(|s| s.into_bytes()) {
+                            // Fire and forget notification
+                            let _ = caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await;
+                        }
                     }
                 }
             }
-        }
+            "remove" => {
+                wiki.members.remove(&req.member_id);
 
-        // Local wiki
-        self.check_permission(&req.wiki_id, WikiRole::Reader)?;
+                // Notify the removed member so it drops its membership and cached copy
+                if req.member_id != self.node_id && previous_role.is_some() {
+                    println!("Member {} removed from wiki {}", req.member_id, wiki_name);
+                    let message = WikiMessage::MembershipRevoked {
+                        wiki_id: wiki_id.clone(),
+                        host_id: self.node_id.clone(),
+                    };
+                    let _ = self.send_wiki_message(&req.member_id, &message).await;
+                }
+            }
+            "update" => {
+                if let Some(role) = req.role.clone() {
+                    wiki.members.insert(req.member_id.clone(), role.clone());
 
-        let page_key = format!("{}:{}", req.wiki_id, req.path);
+                    // Send notification if role actually changed
+                    if req.member_id != self.node_id && previous_role.as_ref() != Some(&role) {
+                        let target_address = Address::new(&req.member_id, WIKI_PROCESS_ID);
+                        let message = WikiMessage::RoleUpdate {
+                            wiki_id: wiki_id.clone(),
+                            member_id: req.member_id.clone(),
+                            new_role: role,
+                        };
 
-        if let Some(page) = self.pages.get(&page_key) {
-            let doc = self.active_docs.entry(page_key.clone())
-                .or_insert_with(|| {
-                    let new_doc = Doc::new();
-                    {
-                        let mut txn = new_doc.transact_mut();
-                        if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
-                            let _ = txn.apply_update(update);
+                        if let Ok(message_body) = serde_json::to_string(&message).map(|s| s.into_bytes()) {
+                            // Fire and forget notification
+                            let _ = caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await;
                         }
                     }
-                    new_doc
-                });
-
-            let text = doc.get_or_insert_text("content");
-            let content = text.get_string(&doc.transact());
+                }
+            }
+            _ => return Err("Invalid action".to_string()),
+        }
 
-            Ok(serde_json::to_string(&PageInfo {
-                path: page.path.clone(),
-                wiki_id: page.wiki_id.clone(),
-                content,
-                updated_by: page.current_version.updated_by.clone(),
-                updated_at: page.current_version.updated_at.clone(),
-            }).unwrap())
-        } else {
-            Ok(serde_json::to_string(&PageInfo {
-                path: req.path,
-                wiki_id: req.wiki_id,
-                content: String::new(),
-                updated_by: String::new(),
-                updated_at: String::new(),
-            }).unwrap())
+        let actor = self.node_id.clone();
+        let new_role = self.wikis.get(&wiki_id).and_then(|wiki| wiki.members.get(&req.member_id)).cloned();
+        let entry = match (previous_role, new_role) {
+            (None, Some(role)) => Some((AuditAction::MemberAdded, format!("Added as {:?}", role))),
+            (Some(role), None) => Some((AuditAction::MemberRemoved, format!("Was {:?}", role))),
+            (Some(previous), Some(role)) if previous != role => {
+                Some((AuditAction::MemberRoleChanged, format!("{:?} -> {:?}", previous, role)))
+            }
+            _ => None,
+        };
+        if let Some((action, details)) = entry {
+            self.audit(&wiki_id, &actor, action, Some(&req.member_id), Some(details));
         }
+
+        self.replicate_wiki(&wiki_id);
+        self.flush_replication().await;
+        self.notify(WsNotification::WikiUpdated { wiki_id });
+
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
     }
 
     #[http]
-    async fn list_pages(&mut self, body: String) -> Result<String, String> {
-        let req: ListPagesRequest = serde_json::from_str(&body)
+    async fn manage_replica(&mut self, body: String) -> Result<String, String> {
+        let req: ManageReplicaRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
 
-        // Check if this is for a remote wiki
-        if req.wiki_id.contains('@') {
-            let parts: Vec<&str> = req.wiki_id.split('@').collect();
-            if parts.len() == 2 {
-                let wiki_id = parts[0];
-                let node_id = parts[1];
+        if req.node_id == self.node_id {
+            return Err("The hosting node can't be its own replica".to_string());
+        }
 
-                // Fetch pages from remote node
-                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::GetWikiPages {
-                    wiki_id: wiki_id.to_string(),
-                };
+        let wiki = self.wikis.get_mut(&req.wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
 
-                let message_body = serde_json::to_string(&message)
-                    .map_err(|e| format!("Failed to serialize message: {}", e))?
-                    .into_bytes();
+        match req.action.as_str() {
+            "add" => {
+                if !wiki.replica_nodes.contains(&req.node_id) {
+                    wiki.replica_nodes.push(req.node_id.clone());
+                }
+                self.replicate_wiki(&req.wiki_id);
+                // The new replica starts from a full copy
+                self.resync_replica(&req.wiki_id, &req.node_id);
+            }
+            "remove" => {
+                if !wiki.replica_nodes.contains(&req.node_id) {
+                    return Err("Node is not a replica of this wiki".to_string());
+                }
+                wiki.replica_nodes.retain(|node_id| node_id != &req.node_id);
+                let wiki = wiki.clone();
+                self.replicate_wiki(&req.wiki_id);
+
+                // The removed node drops its copy once it sees it's no longer listed
+                self.replication_outbox.insert(
+                    format!("{}@{}", req.wiki_id, req.node_id),
+                    vec![WikiMessage::ReplicateMembers { wiki }],
+                );
+            }
+            _ => return Err("Invalid action".to_string()),
+        }
 
-                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                    Ok(Ok(response_bytes)) => {
-                        let response_str = String::from_utf8(response_bytes)
-                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                        match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::PageList(pages)) => {
-                                return Ok(serde_json::to_string(&pages).unwrap());
-                            }
-                            Ok(WikiResponse::Error(err)) => {
-                                return Err(format!("Remote error: {}", err));
-                            }
-                            _ => {
-                                return Ok(serde_json::to_string(&Vec::<PageSummary>::new()).unwrap());
-                            }
-                        }
-                    }
-                    _ => {
-                        return Ok(serde_json::to_string(&Vec::<PageSummary>::new()).unwrap());
-                    }
+        self.flush_replication().await;
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id });
+
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
+    #[http]
+    async fn manage_page_acl(&mut self, body: String) -> Result<String, String> {
+        let req: ManagePageAclRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
+
+        let wiki = self.wikis.get_mut(&req.wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+
+        // Entries are keyed by pattern and the node they apply to
+        let position = wiki.acl.iter()
+            .position(|entry| entry.pattern == req.entry.pattern && entry.node_id == req.entry.node_id);
+
+        match req.action.as_str() {
+            "set" => {
+                if req.entry.pattern.is_empty() {
+                    return Err("Pattern cannot be empty".to_string());
+                }
+                if req.entry.node_id.is_some() && req.entry.role.is_none() {
+                    return Err("Member entries need a role".to_string());
+                }
+                if req.entry.node_id.is_none() && req.entry.read_role.is_none() && req.entry.write_role.is_none() {
+                    return Err("Entries for everyone need a read or write role".to_string());
+                }
+                match position {
+                    Some(index) => wiki.acl[index] = req.entry,
+                    None => wiki.acl.push(req.entry),
                 }
             }
+            "remove" => match position {
+                Some(index) => {
+                    wiki.acl.remove(index);
+                }
+                None => return Err("No matching ACL entry".to_string()),
+            },
+            _ => return Err("Invalid action".to_string()),
         }
 
-        // Local wiki
-        self.check_permission(&req.wiki_id, WikiRole::Reader)?;
+        self.replicate_wiki(&req.wiki_id);
+        self.flush_replication().await;
+        self.notify(WsNotification::WikiUpdated { wiki_id: req.wiki_id });
 
-        let pages: Vec<PageSummary> = self.pages
-            .iter()
-            .filter(|(_, page)| page.wiki_id == req.wiki_id)
-            .map(|(_, page)| PageSummary {
-                path: page.path.clone(),
-                updated_by: page.current_version.updated_by.clone(),
-                updated_at: page.current_version.updated_at.clone(),
-            })
-            .collect();
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
 
-        Ok(serde_json::to_string(&pages).unwrap())
+    #[http]
+    async fn lock_page(&mut self, body: String) -> Result<String, String> {
+        let req: LockPageRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        if let Some((wiki_id, node_id)) = req.wiki_id.split_once('@') {
+            let message = WikiMessage::LockPage {
+                wiki_id: wiki_id.to_string(),
+                path: req.path.clone(),
+                user_id: self.node_id.clone(),
+                action: req.action,
+                duration_minutes: req.duration_minutes,
+            };
+            match self.send_wiki_message(node_id, &message).await? {
+                WikiResponse::Success(true) => {}
+                WikiResponse::Error(err) => return Err(format!("Remote error: {}", err)),
+                _ => return Err("Unexpected response from remote node".to_string()),
+            }
+        } else {
+            self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Writer)?;
+            let node_id = self.node_id.clone();
+            self.update_page_lock(&req.wiki_id, &req.path, &node_id, &req.action, req.duration_minutes)?;
+        }
+
+        self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: req.path });
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
     }
 
     #[http]
-    async fn delete_page(&mut self, body: String) -> Result<String, String> {
-        let req: DeletePageRequest = serde_json::from_str(&body)
+    async fn protect_page(&mut self, body: String) -> Result<String, String> {
+        let req: ProtectPageRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        if let Some((wiki_id, node_id)) = req.wiki_id.split_once('@') {
+            let message = WikiMessage::ProtectPage {
+                wiki_id: wiki_id.to_string(),
+                path: req.path.clone(),
+                user_id: self.node_id.clone(),
+                protected: req.protected,
+            };
+            match self.send_wiki_message(node_id, &message).await? {
+                WikiResponse::Success(true) => {}
+                WikiResponse::Error(err) => return Err(format!("Remote error: {}", err)),
+                _ => return Err("Unexpected response from remote node".to_string()),
+            }
+        } else {
+            self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Admin)?;
+            let node_id = self.node_id.clone();
+            self.set_page_protected(&req.wiki_id, &req.path, &node_id, req.protected)?;
+        }
+
+        self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: req.path });
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
+    #[http]
+    async fn create_page(&mut self, body: String) -> Result<String, String> {
+        let req: CreatePageRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
 
         // Check if this is a remote wiki
@@ -1960,12 +4070,14 @@ impl WikiState {
                 let wiki_id = parts[0];
                 let node_id = parts[1];
 
-                // Send delete page request to remote node
+                // Send create page request to remote node
                 let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::DeletePage {
+                let message = WikiMessage::CreatePage {
                     wiki_id: wiki_id.to_string(),
                     path: req.path.clone(),
+                    initial_content: req.initial_content.clone(),
                     user_id: self.node_id.clone(),
+                    commit_message: req.commit_message.clone(),
                 };
 
                 let message_body = serde_json::to_string(&message)
@@ -1978,7 +4090,11 @@ impl WikiState {
                             .map_err(|e| format!("Failed to convert response to string: {}", e))?;
                         match serde_json::from_str::<WikiResponse>(&response_str) {
                             Ok(WikiResponse::Success(true)) => {
-                                return Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap());
+                                self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+                                return Ok(serde_json::to_string(&CreatePageResponse {
+                                    success: true,
+                                    path: req.path.clone(),
+                                }).unwrap());
                             }
                             Ok(WikiResponse::Error(err)) => {
                                 return Err(format!("Remote error: {}", err));
@@ -1989,7 +4105,7 @@ impl WikiState {
                         }
                     }
                     _ => {
-                        return Err("Failed to delete page on remote wiki".to_string());
+                        return Err("Failed to create page on remote wiki".to_string());
                     }
                 }
             }
@@ -1998,323 +4114,349 @@ impl WikiState {
         // Local wiki handling
         self.check_permission(&req.wiki_id, WikiRole::Writer)?;
 
-        let page_key = format!("{}:{}", req.wiki_id, req.path);
-
-        // Check if page exists
-        if let Some(_page) = self.pages.remove(&page_key) {
-            // Get the page history
-            if let Some(history) = self.page_histories.remove(&page_key) {
-                // Create deleted page entry
-                let deleted_key = format!("{}:{}:{}", req.wiki_id, req.path, Utc::now().timestamp());
-                let deleted_page = DeletedPage {
-                    path: req.path.clone(),
-                    wiki_id: req.wiki_id.clone(),
-                    deleted_at: Utc::now().to_rfc3339(),
-                    deleted_by: self.node_id.clone(),
-                    history,
-                };
-
-                self.deleted_pages.insert(deleted_key, deleted_page);
-            }
+        // Extract title from the initial content
+        let title = Self::extract_title_from_markdown(&req.initial_content);
+        self.check_page_permission(&req.wiki_id, &title, WikiRole::Writer)?;
+        let node_id = self.node_id.clone();
+        self.add_page(&req.wiki_id, &title, &req.initial_content, &node_id, req.commit_message)?;
+        self.flush_replication().await;
 
-            // Remove from active docs
-            self.active_docs.remove(&page_key);
+        self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+        self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: title.clone() });
 
-            Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
-        } else {
-            Err("Page not found".to_string())
-        }
+        Ok(serde_json::to_string(&CreatePageResponse {
+            success: true,
+            path: title,
+        }).unwrap())
     }
 
     #[http]
-    async fn search_pages(&mut self, body: String) -> Result<String, String> {
-        #[derive(Deserialize)]
-        struct SearchRequest {
-            wiki_id: String,
-            query: String,
-        }
-
-        let req: SearchRequest = serde_json::from_str(&body)
+    async fn update_page(&mut self, body: String) -> Result<String, String> {
+        let req: UpdatePageRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
 
-        // For remote wikis, perform the search remotely
+        // Check if this is a remote wiki
         if req.wiki_id.contains('@') {
             let parts: Vec<&str> = req.wiki_id.split('@').collect();
             if parts.len() == 2 {
                 let wiki_id = parts[0];
                 let node_id = parts[1];
 
-                // Send search request to remote node
-                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::SearchPages {
-                    wiki_id: wiki_id.to_string(),
-                    query: req.query.clone(),
-                };
-
-                let message_body = serde_json::to_string(&message)
-                    .map_err(|e| format!("Failed to serialize message: {}", e))?
-                    .into_bytes();
-
-                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                    Ok(Ok(response_bytes)) => {
-                        let response_str = String::from_utf8(response_bytes)
-                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                        match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::SearchResults(results)) => {
-                                return Ok(serde_json::to_string(&results).unwrap());
+                // Base the edit on the host's latest state; if the host is offline,
+                // build on our replica and queue the edit until it's back
+                let has_replica = self.replicas.get(&req.wiki_id)
+                    .is_some_and(|replica| replica.pages.contains_key(&req.path));
+                match self.pull_replica_page(&req.wiki_id, &req.path).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(SyncRefusal::Unsupported)) => {
+                        // Host predates delta sync; send the full text instead
+                        let message = WikiMessage::UpdatePage {
+                            wiki_id: wiki_id.to_string(),
+                            path: req.path.clone(),
+                            content: req.content.clone(),
+                            user_id: self.node_id.clone(),
+                            commit_message: req.commit_message.clone(),
+                        };
+                        match self.send_wiki_message(node_id, &message).await {
+                            Ok(WikiResponse::Success(true)) => {
+                                self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+                                self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id.clone(), path: req.path.clone() });
+                                return Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap());
                             }
                             Ok(WikiResponse::Error(err)) => {
-                                return Err(format!("Remote search error: {}", err));
+                                return Err(format!("Remote error: {}", err));
                             }
-                            _ => {
+                            Ok(_) => {
                                 return Err("Unexpected response from remote node".to_string());
                             }
+                            Err(_) => {
+                                return Err("Failed to update page on remote wiki".to_string());
+                            }
                         }
                     }
-                    _ => {
-                        return Err("Failed to search remote wiki".to_string());
+                    Ok(Err(SyncRefusal::Rejected(err))) => {
+                        return Err(format!("Remote error: {}", err));
+                    }
+                    Err(_) if has_replica => {}
+                    Err(_) => {
+                        return Err("Failed to update page on remote wiki".to_string());
                     }
                 }
-            }
-        }
 
-        // For local wikis, check permissions and search directly
-        self.check_permission(&req.wiki_id, WikiRole::Reader)?;
+                let new_path = self.queue_replica_edit(&req.wiki_id, &req.path, &req.content, req.commit_message.clone(), req.base.as_deref());
+                // An unreachable host leaves the edit queued for the next reconcile
+                let rejected = self.flush_pending_edits(&req.wiki_id).await.unwrap_or_default();
 
-        let message = WikiMessage::SearchPages {
-            wiki_id: req.wiki_id,
-            query: req.query,
-        };
+                self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+                if new_path != req.path {
+                    self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id.clone(), path: req.path.clone() });
+                }
+                self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id.clone(), path: new_path });
 
-        // Process the message directly instead of calling handle_wiki_message
-        match message {
-            WikiMessage::SearchPages { wiki_id, query } => {
-                match self.wikis.get(&wiki_id) {
-                    Some(_) => {
-                        let query_lower = query.to_lowercase();
-                        let mut results: Vec<SearchResult> = Vec::new();
+                if let Some(err) = rejected.into_iter().next() {
+                    return Err(format!("Remote error: {}", err));
+                }
+                return Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap());
+            }
+        }
 
-                        for (page_key, page) in &self.pages {
-                            if page.wiki_id != wiki_id {
-                                continue;
-                            }
+        // Local wiki handling
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Writer)?;
+        // A retitle moves the page, so we must be able to write there too
+        self.check_page_permission(&req.wiki_id, &Self::extract_title_from_markdown(&req.content), WikiRole::Writer)?;
+        let node_id = self.node_id.clone();
+        let new_title = self.edit_page(&req.wiki_id, &req.path, &req.content, &node_id, req.commit_message)?;
+        let title_changed = req.path != new_title;
+        self.flush_replication().await;
 
-                            // Search in path and content
-                            let path_matches = page.path.to_lowercase().contains(&query_lower);
+        self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+        if title_changed {
+            self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id.clone(), path: req.path });
+        }
+        self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: new_title });
 
-                            // Decode CRDT content to get actual text
-                            let content = if let Some(doc) = self.active_docs.get(page_key) {
-                                // Use existing doc
-                                let text = doc.get_or_insert_text("content");
-                                let content_string = text.get_string(&doc.transact());
-                                content_string
-                            } else {
-                                // Create temporary doc to decode content
-                                let doc = Doc::new();
-                                {
-                                    let mut txn = doc.transact_mut();
-                                    if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
-                                        let _ = txn.apply_update(update);
-                                    }
-                                }
-                                let text = doc.get_or_insert_text("content");
-                                let content_string = text.get_string(&doc.transact());
-                                content_string
-                            };
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
 
-                            let content_lower = content.to_lowercase();
-                            let content_matches = content_lower.contains(&query_lower);
+    #[http]
+    async fn revert_page(&mut self, body: String) -> Result<String, String> {
+        let req: RevertPageRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
 
-                            if path_matches || content_matches {
-                                // Create a snippet around the match
-                                let snippet = if content_matches {
-                                    if let Some(pos) = content_lower.find(&query_lower) {
-                                        let start = pos.saturating_sub(50);
-                                        let end = (pos + query_lower.len() + 50).min(content.len());
-                                        let mut snippet_text = content[start..end].to_string();
-                                        if start > 0 {
-                                            snippet_text = format!("...{}", snippet_text);
-                                        }
-                                        if end < content.len() {
-                                            snippet_text = format!("{}...", snippet_text);
-                                        }
-                                        snippet_text
-                                    } else {
-                                        content.chars().take(100).collect::<String>() + "..."
-                                    }
-                                } else {
-                                    // If match is only in path, show beginning of content
-                                    content.chars().take(100).collect::<String>() + "..."
-                                };
+        if let Some((wiki_id, node_id)) = req.wiki_id.split_once('@') {
+            // Reverting needs the host's history, so it can't be queued offline
+            let message = WikiMessage::RevertPage {
+                wiki_id: wiki_id.to_string(),
+                path: req.path.clone(),
+                version_id: req.version_id.clone(),
+                user_id: self.node_id.clone(),
+            };
+            return match self.send_wiki_message(node_id, &message).await {
+                Ok(WikiResponse::Success(true)) => {
+                    self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+                    self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: req.path });
+                    Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+                }
+                Ok(WikiResponse::Error(err)) => Err(format!("Remote error: {}", err)),
+                Ok(_) => Err("Unexpected response from remote node".to_string()),
+                Err(_) => Err("Failed to revert page on remote wiki".to_string()),
+            };
+        }
 
-                                results.push(SearchResult {
-                                    path: page.path.clone(),
-                                    updated_by: page.current_version.updated_by.clone(),
-                                    updated_at: page.current_version.updated_at.clone(),
-                                    snippet,
-                                });
-                            }
-                        }
+        // Local wiki handling
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Writer)?;
+        let node_id = self.node_id.clone();
+        let new_path = self.revert_page_to(&req.wiki_id, &req.path, &req.version_id, &node_id)?;
+        self.flush_replication().await;
+
+        self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+        if new_path != req.path {
+            self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id.clone(), path: req.path });
+        }
+        self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: new_path });
 
-                        Ok(serde_json::to_string(&results).unwrap())
-                    }
-                    None => Err("Wiki not found".to_string()),
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
+    #[http]
+    async fn tag_version(&mut self, body: String) -> Result<String, String> {
+        let req: TagVersionRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        if let Some((wiki_id, node_id)) = req.wiki_id.split_once('@') {
+            let message = WikiMessage::TagVersion {
+                wiki_id: wiki_id.to_string(),
+                path: req.path.clone(),
+                version_id: req.version_id,
+                tag: req.tag,
+                user_id: self.node_id.clone(),
+            };
+            return match self.send_wiki_message(node_id, &message).await {
+                Ok(WikiResponse::Success(true)) => {
+                    self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: req.path });
+                    Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
                 }
-            }
-            _ => Err("Invalid message type".to_string()),
+                Ok(WikiResponse::Error(err)) => Err(format!("Remote error: {}", err)),
+                Ok(_) => Err("Unexpected response from remote node".to_string()),
+                Err(_) => Err("Failed to tag version on remote wiki".to_string()),
+            };
         }
+
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Writer)?;
+        self.set_version_tag(&req.wiki_id, &req.path, &req.version_id, req.tag)?;
+        self.flush_replication().await;
+
+        self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: req.path });
+
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
     }
 
     #[http]
-    async fn get_page_history(&mut self, body: String) -> Result<String, String> {
-        let req: GetPageHistoryRequest = serde_json::from_str(&body)
+    async fn get_page(&mut self, body: String) -> Result<String, String> {
+        let req: GetPageRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
 
-        // Check if this is a remote wiki
+        // Check if this is for a remote wiki
         if req.wiki_id.contains('@') {
             let parts: Vec<&str> = req.wiki_id.split('@').collect();
             if parts.len() == 2 {
                 let wiki_id = parts[0];
-                let node_id = parts[1];
 
-                // Send get page history request to remote node
-                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::GetPageHistory {
+                // Push any offline edits first so the host's copy includes them
+                let reachable = self.flush_pending_edits(&req.wiki_id).await.is_ok();
+
+                // Fetch page from remote node
+                let message = WikiMessage::GetWikiPage {
                     wiki_id: wiki_id.to_string(),
                     path: req.path.clone(),
                 };
+                let response = if reachable {
+                    self.send_wiki_read(&req.wiki_id, &message).await
+                } else {
+                    Err("Host unreachable".to_string())
+                };
 
-                let message_body = serde_json::to_string(&message)
-                    .map_err(|e| format!("Failed to serialize message: {}", e))?
-                    .into_bytes();
-
-                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                    Ok(Ok(response_bytes)) => {
-                        let response_str = String::from_utf8(response_bytes)
-                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                        match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::DecodedPageHistory(history)) => {
-                                return Ok(serde_json::to_string(&history).unwrap());
+                match response {
+                    Ok(WikiResponse::PageData(mut page_info)) => {
+                        // Keep the replica current so the page stays readable offline
+                        if let Ok(Ok(())) = self.pull_replica_page(&req.wiki_id, &req.path).await {
+                            if let Some(page) = self.replicas.get_mut(&req.wiki_id).and_then(|r| r.pages.get_mut(&req.path)) {
+                                page.updated_by = page_info.updated_by.clone();
+                                page.updated_at = page_info.updated_at.clone();
+                                page.protected = page_info.protected;
+                                page.lock = page_info.lock.clone();
                             }
-                            Ok(WikiResponse::PageHistory(history)) => {
-                                return Ok(serde_json::to_string(&history).unwrap());
-                            }
-                            Ok(WikiResponse::Error(err)) => {
-                                return Err(format!("Remote error: {}", err));
-                            }
-                            _ => {
-                                return Err("Unexpected response from remote node".to_string());
+                            // Serve the replica's text, which edits are based on
+                            if let Some(replica_info) = self.replica_page_info(&req.wiki_id, &req.path) {
+                                page_info.content = replica_info.content;
+                                page_info.base = replica_info.base;
                             }
                         }
+                        return Ok(serde_json::to_string(&page_info).unwrap());
                     }
-                    _ => {
-                        return Err("Failed to get page history from remote wiki".to_string());
+                    Ok(WikiResponse::Error(err)) => {
+                        return Err(format!("Remote error: {}", err));
+                    }
+                    Ok(_) => {
+                        return Err("Failed to fetch page from remote wiki".to_string());
+                    }
+                    Err(_) => {
+                        return match self.replica_page_info(&req.wiki_id, &req.path) {
+                            Some(page_info) => Ok(serde_json::to_string(&page_info).unwrap()),
+                            None => Err("Failed to contact remote wiki".to_string()),
+                        };
                     }
                 }
             }
         }
 
-        // Local wiki handling
-        self.check_permission(&req.wiki_id, WikiRole::Reader)?;
+        // Local wiki
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Reader)?;
 
         let page_key = format!("{}:{}", req.wiki_id, req.path);
-        if let Some(history) = self.page_histories.get(&page_key) {
-            // Decode all versions
-            let decoded_versions: Vec<DecodedPageVersion> = history.versions.iter()
-                .map(|version| {
-                    let content = match self.decode_yrs_content(&version.content) {
-                        Ok(text) => text,
-                        Err(_) => "[Failed to decode content]".to_string(),
-                    };
-                    DecodedPageVersion {
-                        version_id: version.version_id.clone(),
-                        content,
-                        updated_by: version.updated_by.clone(),
-                        updated_at: version.updated_at.clone(),
-                        commit_message: version.commit_message.clone(),
+
+        if let Some(page) = self.pages.get(&page_key) {
+            let doc = self.active_docs.entry(page_key.clone())
+                .or_insert_with(|| {
+                    let new_doc = Doc::new();
+                    {
+                        let mut txn = new_doc.transact_mut();
+                        if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
+                            let _ = txn.apply_update(update);
+                        }
                     }
-                })
-                .collect();
+                    new_doc
+                });
 
-            let decoded_history = DecodedPageHistory {
-                path: history.path.clone(),
-                wiki_id: history.wiki_id.clone(),
-                versions: decoded_versions,
-                current_version_id: history.current_version_id.clone(),
-            };
+            let text = doc.get_or_insert_text("content");
+            let content = text.get_string(&doc.transact());
 
-            Ok(serde_json::to_string(&decoded_history).unwrap())
+            Ok(serde_json::to_string(&PageInfo {
+                path: page.path.clone(),
+                wiki_id: page.wiki_id.clone(),
+                content,
+                updated_by: page.current_version.updated_by.clone(),
+                updated_at: page.current_version.updated_at.clone(),
+                protected: page.protected,
+                lock: page.lock.clone().filter(PageLock::is_active),
+                base: None,
+            }).unwrap())
         } else {
-            Err("Page history not found".to_string())
+            Ok(serde_json::to_string(&PageInfo {
+                path: req.path,
+                wiki_id: req.wiki_id,
+                content: String::new(),
+                updated_by: String::new(),
+                updated_at: String::new(),
+                protected: false,
+                lock: None,
+                base: None,
+            }).unwrap())
         }
     }
 
     #[http]
-    async fn list_deleted_pages(&mut self, body: String) -> Result<String, String> {
-        let req: ListDeletedPagesRequest = serde_json::from_str(&body)
+    async fn list_pages(&mut self, body: String) -> Result<String, String> {
+        let req: ListPagesRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
 
-        // Check if this is a remote wiki
+        // Check if this is for a remote wiki
         if req.wiki_id.contains('@') {
             let parts: Vec<&str> = req.wiki_id.split('@').collect();
             if parts.len() == 2 {
                 let wiki_id = parts[0];
-                let node_id = parts[1];
 
-                // Send list deleted pages request to remote node
-                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::ListDeletedPages {
+                // Fetch pages from remote node
+                let message = WikiMessage::GetWikiPages {
                     wiki_id: wiki_id.to_string(),
                 };
 
-                let message_body = serde_json::to_string(&message)
-                    .map_err(|e| format!("Failed to serialize message: {}", e))?
-                    .into_bytes();
-
-                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-                    Ok(Ok(response_bytes)) => {
-                        let response_str = String::from_utf8(response_bytes)
-                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                        match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::DeletedPagesList(pages)) => {
+                match self.send_wiki_read(&req.wiki_id, &message).await {
+                    Ok(response) => {
+                        match response {
+                            WikiResponse::PageList(pages) => {
+                                self.replicas.entry(req.wiki_id.clone()).or_default().page_list = pages.clone();
                                 return Ok(serde_json::to_string(&pages).unwrap());
                             }
-                            Ok(WikiResponse::Error(err)) => {
+                            WikiResponse::Error(err) => {
                                 return Err(format!("Remote error: {}", err));
                             }
                             _ => {
-                                return Err("Unexpected response from remote node".to_string());
+                                return Ok(serde_json::to_string(&Vec::<PageSummary>::new()).unwrap());
                             }
                         }
                     }
-                    _ => {
-                        return Err("Failed to list deleted pages from remote wiki".to_string());
+                    Err(_) => {
+                        // Host unreachable; list what the replica knows about
+                        let pages = self.replicas.get(&req.wiki_id)
+                            .map(|replica| replica.page_list.clone())
+                            .unwrap_or_default();
+                        return Ok(serde_json::to_string(&pages).unwrap());
                     }
                 }
             }
         }
 
-        // Local wiki handling
+        // Local wiki
         self.check_permission(&req.wiki_id, WikiRole::Reader)?;
 
-        let mut deleted_summaries = Vec::new();
-        for (key, deleted_page) in &self.deleted_pages {
-            if deleted_page.wiki_id == req.wiki_id {
-                deleted_summaries.push(DeletedPageSummary {
-                    path: deleted_page.path.clone(),
-                    deleted_at: deleted_page.deleted_at.clone(),
-                    deleted_by: deleted_page.deleted_by.clone(),
-                    deleted_key: key.clone(),
-                });
-            }
-        }
+        let pages: Vec<PageSummary> = self.pages
+            .iter()
+            .filter(|(_, page)| page.wiki_id == req.wiki_id)
+            .filter(|(_, page)| self.check_page_permission(&req.wiki_id, &page.path, WikiRole::Reader).is_ok())
+            .map(|(_, page)| PageSummary {
+                path: page.path.clone(),
+                updated_by: page.current_version.updated_by.clone(),
+                updated_at: page.current_version.updated_at.clone(),
+            })
+            .collect();
 
-        Ok(serde_json::to_string(&deleted_summaries).unwrap())
+        Ok(serde_json::to_string(&pages).unwrap())
     }
 
     #[http]
-    async fn restore_deleted_page(&mut self, body: String) -> Result<String, String> {
-        let req: RestoreDeletedPageRequest = serde_json::from_str(&body)
+    async fn delete_page(&mut self, body: String) -> Result<String, String> {
+        let req: DeletePageRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
 
         // Check if this is a remote wiki
@@ -2324,12 +4466,11 @@ impl WikiState {
                 let wiki_id = parts[0];
                 let node_id = parts[1];
 
-                // Send restore deleted page request to remote node
+                // Send delete page request to remote node
                 let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::RestoreDeletedPage {
+                let message = WikiMessage::DeletePage {
                     wiki_id: wiki_id.to_string(),
                     path: req.path.clone(),
-                    deleted_key: req.deleted_key.clone(),
                     user_id: self.node_id.clone(),
                 };
 
@@ -2343,6 +4484,8 @@ impl WikiState {
                             .map_err(|e| format!("Failed to convert response to string: {}", e))?;
                         match serde_json::from_str::<WikiResponse>(&response_str) {
                             Ok(WikiResponse::Success(true)) => {
+                                self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+                                self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id.clone(), path: req.path.clone() });
                                 return Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap());
                             }
                             Ok(WikiResponse::Error(err)) => {
@@ -2354,71 +4497,47 @@ impl WikiState {
                         }
                     }
                     _ => {
-                        return Err("Failed to restore deleted page on remote wiki".to_string());
+                        return Err("Failed to delete page on remote wiki".to_string());
                     }
                 }
             }
         }
 
         // Local wiki handling
-        self.check_permission(&req.wiki_id, WikiRole::Writer)?;
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Writer)?;
+        let node_id = self.node_id.clone();
+        self.remove_page(&req.wiki_id, &req.path, &node_id)?;
+        self.flush_replication().await;
 
-        if let Some(deleted_page) = self.deleted_pages.remove(&req.deleted_key) {
-            // Check if this is the right page
-            if deleted_page.wiki_id != req.wiki_id || deleted_page.path != req.path {
-                self.deleted_pages.insert(req.deleted_key, deleted_page);
-                return Err("Deleted page key mismatch".to_string());
-            }
-
-            let page_key = format!("{}:{}", req.wiki_id, req.path);
-
-            // Check if page already exists
-            if self.pages.contains_key(&page_key) {
-                self.deleted_pages.insert(req.deleted_key, deleted_page);
-                return Err("Page already exists".to_string());
-            }
-
-            // Restore the page with its latest version
-            let history = deleted_page.history;
-            if let Some(latest_version) = history.versions.last() {
-                let page = WikiPage {
-                    path: req.path.clone(),
-                    wiki_id: req.wiki_id.clone(),
-                    current_version: latest_version.clone(),
-                    yrs_doc: latest_version.content.clone(),
-                };
-
-                self.pages.insert(page_key.clone(), page);
-                self.page_histories.insert(page_key, history);
+        self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+        self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: req.path });
 
-                Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
-            } else {
-                Err("No versions found in deleted page".to_string())
-            }
-        } else {
-            Err("Deleted page not found".to_string())
-        }
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
     }
 
     #[http]
-    async fn get_version_diff(&mut self, body: String) -> Result<String, String> {
-        let req: GetVersionDiffRequest = serde_json::from_str(&body)
+    async fn search_pages(&mut self, body: String) -> Result<String, String> {
+        #[derive(Deserialize)]
+        struct SearchRequest {
+            wiki_id: String,
+            query: String,
+        }
+
+        let req: SearchRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
 
-        // Check if this is a remote wiki
+        // For remote wikis, perform the search remotely
         if req.wiki_id.contains('@') {
             let parts: Vec<&str> = req.wiki_id.split('@').collect();
             if parts.len() == 2 {
                 let wiki_id = parts[0];
                 let node_id = parts[1];
 
-                // Send get version diff request to remote node
+                // Send search request to remote node
                 let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::GetVersionDiff {
+                let message = WikiMessage::SearchPages {
                     wiki_id: wiki_id.to_string(),
-                    path: req.path.clone(),
-                    version1_id: req.version1_id.clone(),
-                    version2_id: req.version2_id.clone(),
+                    query: req.query.clone(),
                 };
 
                 let message_body = serde_json::to_string(&message)
@@ -2430,11 +4549,11 @@ impl WikiState {
                         let response_str = String::from_utf8(response_bytes)
                             .map_err(|e| format!("Failed to convert response to string: {}", e))?;
                         match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::VersionDiff(diff)) => {
-                                return Ok(serde_json::to_string(&diff).unwrap());
+                            Ok(WikiResponse::SearchResults(results)) => {
+                                return Ok(serde_json::to_string(&results).unwrap());
                             }
                             Ok(WikiResponse::Error(err)) => {
-                                return Err(format!("Remote error: {}", err));
+                                return Err(format!("Remote search error: {}", err));
                             }
                             _ => {
                                 return Err("Unexpected response from remote node".to_string());
@@ -2442,63 +4561,203 @@ impl WikiState {
                         }
                     }
                     _ => {
-                        return Err("Failed to get version diff from remote wiki".to_string());
+                        return Err("Failed to search remote wiki".to_string());
                     }
                 }
             }
         }
 
-        // Local wiki handling
+        // For local wikis, check permissions and search directly
         self.check_permission(&req.wiki_id, WikiRole::Reader)?;
 
-        let page_key = format!("{}:{}", req.wiki_id, req.path);
-        if let Some(history) = self.page_histories.get(&page_key) {
-            // Find the two versions
-            let version1 = history.versions.iter().find(|v| v.version_id == req.version1_id);
-            let version2 = history.versions.iter().find(|v| v.version_id == req.version2_id);
-
-            match (version1, version2) {
-                (Some(v1), Some(v2)) => {
-                    // Decode content for both versions
-                    let content1 = self.decode_yrs_content(&v1.content)
-                        .map_err(|_| "Failed to decode version 1 content".to_string())?;
-                    let content2 = self.decode_yrs_content(&v2.content)
-                        .map_err(|_| "Failed to decode version 2 content".to_string())?;
-
-                    // Calculate diff
-                    let diff_lines = self.calculate_diff(&content1, &content2);
-
-                    let version_diff = VersionDiff {
-                        version1_id: req.version1_id.clone(),
-                        version2_id: req.version2_id.clone(),
-                        diff_lines,
-                    };
+        let message = WikiMessage::SearchPages {
+            wiki_id: req.wiki_id,
+            query: req.query,
+        };
+
+        // Process the message directly instead of calling handle_wiki_message
+        match message {
+            WikiMessage::SearchPages { wiki_id, query } => {
+                match self.wikis.get(&wiki_id) {
+                    Some(_) => {
+                        let query_lower = query.to_lowercase();
+                        let mut results: Vec<SearchResult> = Vec::new();
+
+                        for (page_key, page) in &self.pages {
+                            if page.wiki_id != wiki_id
+                                || self.check_page_permission(&wiki_id, &page.path, WikiRole::Reader).is_err()
+                            {
+                                continue;
+                            }
+
+                            // Search in path and content
+                            let path_matches = page.path.to_lowercase().contains(&query_lower);
+
+                            // Decode CRDT content to get actual text
+                            let content = if let Some(doc) = self.active_docs.get(page_key) {
+                                // Use existing doc
+                                let text = doc.get_or_insert_text("content");
+                                let content_string = text.get_string(&doc.transact());
+                                content_string
+                            } else {
+                                // Create temporary doc to decode content
+                                let doc = Doc::new();
+                                {
+                                    let mut txn = doc.transact_mut();
+                                    if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
+                                        let _ = txn.apply_update(update);
+                                    }
+                                }
+                                let text = doc.get_or_insert_text("content");
+                                let content_string = text.get_string(&doc.transact());
+                                content_string
+                            };
+
+                            let content_lower = content.to_lowercase();
+                            let content_matches = content_lower.contains(&query_lower);
+
+                            if path_matches || content_matches {
+                                // Create a snippet around the match
+                                let snippet = if content_matches {
+                                    if let Some(pos) = content_lower.find(&query_lower) {
+                                        let start = pos.saturating_sub(50);
+                                        let end = (pos + query_lower.len() + 50).min(content.len());
+                                        let mut snippet_text = content[start..end].to_string();
+                                        if start > 0 {
+                                            snippet_text = format!("...{}", snippet_text);
+                                        }
+                                        if end < content.len() {
+                                            snippet_text = format!("{}...", snippet_text);
+                                        }
+                                        snippet_text
+                                    } else {
+                                        content.chars().take(100).collect::<String>() + "..."
+                                    }
+                                } else {
+                                    // If match is only in path, show beginning of content
+                                    content.chars().take(100).collect::<String>() + "..."
+                                };
+
+                                results.push(SearchResult {
+                                    path: page.path.clone(),
+                                    updated_by: page.current_version.updated_by.clone(),
+                                    updated_at: page.current_version.updated_at.clone(),
+                                    snippet,
+                                });
+                            }
+                        }
+
+                        Ok(serde_json::to_string(&results).unwrap())
+                    }
+                    None => Err("Wiki not found".to_string()),
+                }
+            }
+            _ => Err("Invalid message type".to_string()),
+        }
+    }
+
+    #[http]
+    async fn get_page_history(&mut self, body: String) -> Result<String, String> {
+        let req: GetPageHistoryRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        // Check if this is a remote wiki
+        if req.wiki_id.contains('@') {
+            let parts: Vec<&str> = req.wiki_id.split('@').collect();
+            if parts.len() == 2 {
+                let wiki_id = parts[0];
+
+                // Send get page history request to remote node
+                let message = WikiMessage::GetPageHistory {
+                    wiki_id: wiki_id.to_string(),
+                    path: req.path.clone(),
+                };
 
-                    Ok(serde_json::to_string(&version_diff).unwrap())
+                match self.send_wiki_read(&req.wiki_id, &message).await {
+                    Ok(response) => {
+                        match response {
+                            WikiResponse::DecodedPageHistory(history) => {
+                                if let Some(page) = self.replicas.get_mut(&req.wiki_id).and_then(|r| r.pages.get_mut(&req.path)) {
+                                    page.history = Some(history.clone());
+                                }
+                                return Ok(serde_json::to_string(&history).unwrap());
+                            }
+                            WikiResponse::PageHistory(history) => {
+                                return Ok(serde_json::to_string(&history).unwrap());
+                            }
+                            WikiResponse::Error(err) => {
+                                return Err(format!("Remote error: {}", err));
+                            }
+                            _ => {
+                                return Err("Unexpected response from remote node".to_string());
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        // Host unreachable; serve the history the replica last saw
+                        let history = self.replicas.get(&req.wiki_id)
+                            .and_then(|replica| replica.pages.get(&req.path))
+                            .and_then(|page| page.history.as_ref());
+                        return match history {
+                            Some(history) => Ok(serde_json::to_string(history).unwrap()),
+                            None => Err("Failed to get page history from remote wiki".to_string()),
+                        };
+                    }
                 }
-                _ => Err("One or both versions not found".to_string()),
             }
+        }
+
+        // Local wiki handling
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Reader)?;
+
+        let page_key = format!("{}:{}", req.wiki_id, req.path);
+        if let Some(history) = self.page_histories.get(&page_key) {
+            // Decode all versions
+            let decoded_versions: Vec<DecodedPageVersion> = history.versions.iter()
+                .zip(history.version_texts())
+                .map(|(version, content)| {
+                    let content = content.unwrap_or_else(|_| "[Failed to decode content]".to_string());
+                    DecodedPageVersion {
+                        version_id: version.version_id.clone(),
+                        content,
+                        updated_by: version.updated_by.clone(),
+                        updated_at: version.updated_at.clone(),
+                        commit_message: version.commit_message.clone(),
+                        change: version.change.clone(),
+                        tag: version.tag.clone(),
+                    }
+                })
+                .collect();
+
+            let decoded_history = DecodedPageHistory {
+                path: history.path.clone(),
+                wiki_id: history.wiki_id.clone(),
+                versions: decoded_versions,
+                current_version_id: history.current_version_id.clone(),
+            };
+
+            Ok(serde_json::to_string(&decoded_history).unwrap())
         } else {
             Err("Page history not found".to_string())
         }
     }
 
     #[http]
-    async fn search_pages_disabled(&mut self, body: String) -> Result<String, String> {
-        let req: SearchRequest = serde_json::from_str(&body)
+    async fn list_deleted_pages(&mut self, body: String) -> Result<String, String> {
+        let req: ListDeletedPagesRequest = serde_json::from_str(&body)
             .map_err(|e| format!("Invalid request: {}", e))?;
 
-        // For remote wikis, perform the search remotely
+        // Check if this is a remote wiki
         if req.wiki_id.contains('@') {
             let parts: Vec<&str> = req.wiki_id.split('@').collect();
             if parts.len() == 2 {
                 let wiki_id = parts[0];
                 let node_id = parts[1];
 
+                // Send list deleted pages request to remote node
                 let target_address = Address::new(node_id, WIKI_PROCESS_ID);
-                let message = WikiMessage::SearchPages {
+                let message = WikiMessage::ListDeletedPages {
                     wiki_id: wiki_id.to_string(),
-                    query: req.query.clone(),
                 };
 
                 let message_body = serde_json::to_string(&message)
@@ -2510,42 +4769,397 @@ impl WikiState {
                         let response_str = String::from_utf8(response_bytes)
                             .map_err(|e| format!("Failed to convert response to string: {}", e))?;
                         match serde_json::from_str::<WikiResponse>(&response_str) {
-                            Ok(WikiResponse::SearchResults(results)) => {
-                                return Ok(serde_json::to_string(&results).unwrap());
+                            Ok(WikiResponse::DeletedPagesList(pages)) => {
+                                return Ok(serde_json::to_string(&pages).unwrap());
+                            }
+                            Ok(WikiResponse::Error(err)) => {
+                                return Err(format!("Remote error: {}", err));
+                            }
+                            _ => {
+                                return Err("Unexpected response from remote node".to_string());
                             }
-                            Ok(WikiResponse::Error(e)) => return Err(e),
-                            _ => return Err("Unexpected response from remote search".to_string()),
                         }
                     }
-                    _ => return Err("Failed to search remote wiki".to_string()),
+                    _ => {
+                        return Err("Failed to list deleted pages from remote wiki".to_string());
+                    }
                 }
             }
         }
 
-        // For local wikis, check permissions
+        // Local wiki handling
         self.check_permission(&req.wiki_id, WikiRole::Reader)?;
 
-        let query_lower = req.query.to_lowercase();
-        let mut results: Vec<SearchResult> = Vec::new();
-
-        // Search through all pages in the wiki
-        for (page_key, page) in &self.pages {
-            if page.wiki_id != req.wiki_id {
-                continue;
+        let mut deleted_summaries = Vec::new();
+        for (key, deleted_page) in &self.deleted_pages {
+            if deleted_page.wiki_id == req.wiki_id
+                && self.check_page_permission(&req.wiki_id, &deleted_page.path, WikiRole::Reader).is_ok()
+            {
+                deleted_summaries.push(DeletedPageSummary {
+                    path: deleted_page.path.clone(),
+                    deleted_at: deleted_page.deleted_at.clone(),
+                    deleted_by: deleted_page.deleted_by.clone(),
+                    deleted_key: key.clone(),
+                });
             }
+        }
 
-            // Search in path and content
-            let path_matches = page.path.to_lowercase().contains(&query_lower);
+        Ok(serde_json::to_string(&deleted_summaries).unwrap())
+    }
 
-            // Decode CRDT content to get actual text
-            let content = if let Some(doc) = self.active_docs.get(page_key) {
-                // Use existing doc
-                let text = doc.get_or_insert_text("content");
-                let txn = doc.transact();
-                text.get_string(&txn)
-            } else {
-                // Create temporary doc to decode content
-                let doc = Doc::new();
+    #[http]
+    async fn restore_deleted_page(&mut self, body: String) -> Result<String, String> {
+        let req: RestoreDeletedPageRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        // Check if this is a remote wiki
+        if req.wiki_id.contains('@') {
+            let parts: Vec<&str> = req.wiki_id.split('@').collect();
+            if parts.len() == 2 {
+                let wiki_id = parts[0];
+                let node_id = parts[1];
+
+                // Send restore deleted page request to remote node
+                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
+                let message = WikiMessage::RestoreDeletedPage {
+                    wiki_id: wiki_id.to_string(),
+                    path: req.path.clone(),
+                    deleted_key: req.deleted_key.clone(),
+                    user_id: self.node_id.clone(),
+                };
+
+                let message_body = serde_json::to_string(&message)
+                    .map_err(|e| format!("Failed to serialize message: {}", e))?
+                    .into_bytes();
+
+                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
+                    Ok(Ok(response_bytes)) => {
+                        let response_str = String::from_utf8(response_bytes)
+                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
+                        match serde_json::from_str::<WikiResponse>(&response_str) {
+                            Ok(WikiResponse::Success(true)) => {
+                                self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+                                self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id.clone(), path: req.path.clone() });
+                                return Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap());
+                            }
+                            Ok(WikiResponse::Error(err)) => {
+                                return Err(format!("Remote error: {}", err));
+                            }
+                            _ => {
+                                return Err("Unexpected response from remote node".to_string());
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err("Failed to restore deleted page on remote wiki".to_string());
+                    }
+                }
+            }
+        }
+
+        // Local wiki handling
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Writer)?;
+
+        if let Some(deleted_page) = self.deleted_pages.remove(&req.deleted_key) {
+            // Check if this is the right page
+            if deleted_page.wiki_id != req.wiki_id || deleted_page.path != req.path {
+                self.deleted_pages.insert(req.deleted_key, deleted_page);
+                return Err("Deleted page key mismatch".to_string());
+            }
+
+            let page_key = format!("{}:{}", req.wiki_id, req.path);
+
+            // Check if page already exists
+            if self.pages.contains_key(&page_key) {
+                self.deleted_pages.insert(req.deleted_key, deleted_page);
+                return Err("Page already exists".to_string());
+            }
+
+            // Restore the page with its latest content, recorded as a new version
+            let mut history = deleted_page.history;
+            let latest = history.versions.len().checked_sub(1)
+                .and_then(|index| history.version_doc(index).ok());
+            if let Some(doc) = latest {
+                let restored_version = history.record_version(PageVersion {
+                    version_id: Uuid::new_v4().to_string(),
+                    content: Vec::new(),
+                    delta: false,
+                    updated_by: self.node_id.clone(),
+                    updated_at: Utc::now().to_rfc3339(),
+                    commit_message: None,
+                    change: PageChange::Restored {
+                        deleted_at: deleted_page.deleted_at,
+                        deleted_by: deleted_page.deleted_by,
+                    },
+                    tag: None,
+                }, &doc);
+                let page = WikiPage {
+                    path: req.path.clone(),
+                    wiki_id: req.wiki_id.clone(),
+                    current_version: restored_version,
+                    yrs_doc: doc.transact().encode_state_as_update_v1(&yrs::StateVector::default()),
+                    protected: false,
+                    lock: None,
+                };
+
+                self.pages.insert(page_key.clone(), page);
+                self.page_histories.insert(page_key, history);
+                self.resync_replicas(&req.wiki_id);
+                let actor = self.node_id.clone();
+                self.audit(&req.wiki_id, &actor, AuditAction::PageRestored, Some(&req.path), None);
+                self.notify_page_watchers(&req.wiki_id, &req.path);
+                self.flush_replication().await;
+
+                self.notify(WsNotification::PageListUpdated { wiki_id: req.wiki_id.clone() });
+                self.notify(WsNotification::PageUpdated { wiki_id: req.wiki_id, path: req.path });
+
+                Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+            } else {
+                Err("No versions found in deleted page".to_string())
+            }
+        } else {
+            Err("Deleted page not found".to_string())
+        }
+    }
+
+    #[http]
+    async fn get_version_diff(&mut self, body: String) -> Result<String, String> {
+        let req: GetVersionDiffRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        // Check if this is a remote wiki
+        if req.wiki_id.contains('@') {
+            let parts: Vec<&str> = req.wiki_id.split('@').collect();
+            if parts.len() == 2 {
+                let wiki_id = parts[0];
+                let node_id = parts[1];
+
+                // Send get version diff request to remote node
+                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
+                let message = WikiMessage::GetVersionDiff {
+                    wiki_id: wiki_id.to_string(),
+                    path: req.path.clone(),
+                    version1_id: req.version1_id.clone(),
+                    version2_id: req.version2_id.clone(),
+                    options: req.options.clone(),
+                };
+
+                let message_body = serde_json::to_string(&message)
+                    .map_err(|e| format!("Failed to serialize message: {}", e))?
+                    .into_bytes();
+
+                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
+                    Ok(Ok(response_bytes)) => {
+                        let response_str = String::from_utf8(response_bytes)
+                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
+                        match serde_json::from_str::<WikiResponse>(&response_str) {
+                            Ok(WikiResponse::VersionDiff(diff)) => {
+                                return Ok(serde_json::to_string(&diff).unwrap());
+                            }
+                            Ok(WikiResponse::Error(err)) => {
+                                return Err(format!("Remote error: {}", err));
+                            }
+                            _ => {
+                                return Err("Unexpected response from remote node".to_string());
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err("Failed to get version diff from remote wiki".to_string());
+                    }
+                }
+            }
+        }
+
+        // Local wiki handling
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Reader)?;
+
+        let page_key = format!("{}:{}", req.wiki_id, req.path);
+        if let Some(history) = self.page_histories.get(&page_key) {
+            let version_diff = self.version_diff(history, &req.version1_id, &req.version2_id, &req.options)?;
+            Ok(serde_json::to_string(&version_diff).unwrap())
+        } else {
+            Err("Page history not found".to_string())
+        }
+    }
+
+    #[http]
+    async fn get_page_blame(&mut self, body: String) -> Result<String, String> {
+        let req: GetPageBlameRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        if let Some((wiki_id, _)) = req.wiki_id.split_once('@') {
+            let message = WikiMessage::GetPageBlame {
+                wiki_id: wiki_id.to_string(),
+                path: req.path,
+            };
+            return match self.send_wiki_read(&req.wiki_id, &message).await? {
+                WikiResponse::PageBlame(blame) => Ok(serde_json::to_string(&blame).unwrap()),
+                WikiResponse::Error(err) => Err(format!("Remote error: {}", err)),
+                _ => Err("Unexpected response from remote node".to_string()),
+            };
+        }
+
+        self.check_page_permission(&req.wiki_id, &req.path, WikiRole::Reader)?;
+        let page_key = format!("{}:{}", req.wiki_id, req.path);
+        let history = self.page_histories.get(&page_key)
+            .ok_or_else(|| "Page history not found".to_string())?;
+        Ok(serde_json::to_string(&Self::page_blame(history)).unwrap())
+    }
+
+    #[http]
+    async fn recent_changes(&mut self, body: String) -> Result<String, String> {
+        let req: RecentChangesRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        if let Some((wiki_id, _)) = req.wiki_id.split_once('@') {
+            let message = WikiMessage::GetRecentChanges {
+                wiki_id: wiki_id.to_string(),
+                offset: req.offset,
+                limit: req.limit,
+            };
+            return match self.send_wiki_read(&req.wiki_id, &message).await? {
+                WikiResponse::RecentChanges(page) => Ok(serde_json::to_string(&page).unwrap()),
+                WikiResponse::Error(err) => Err(format!("Remote error: {}", err)),
+                _ => Err("Unexpected response from remote node".to_string()),
+            };
+        }
+
+        self.check_permission(&req.wiki_id, WikiRole::Reader)?;
+        let node_id = self.node_id.clone();
+        let page = self.collect_recent_changes(&req.wiki_id, &node_id, req.offset, req.limit)?;
+        Ok(serde_json::to_string(&page).unwrap())
+    }
+
+    #[http]
+    async fn watch_page(&mut self, body: String) -> Result<String, String> {
+        let req: WatchPageRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        if let Some((wiki_id, node_id)) = req.wiki_id.split_once('@') {
+            // The host sends the notices, so it has to know about the watch
+            let message = WikiMessage::WatchPage {
+                wiki_id: wiki_id.to_string(),
+                path: req.path.clone(),
+                user_id: self.node_id.clone(),
+                watch: req.watch,
+            };
+            match self.send_wiki_message(node_id, &message).await {
+                Ok(WikiResponse::Success(true)) => {}
+                Ok(WikiResponse::Error(err)) if !req.watch && err == "Wiki not found" => {}
+                Ok(WikiResponse::Error(err)) => return Err(format!("Remote error: {}", err)),
+                Ok(_) => return Err("Unexpected response from remote node".to_string()),
+                Err(_) if !req.watch => {}
+                Err(_) => return Err("Failed to reach wiki host".to_string()),
+            }
+        } else if req.watch {
+            match &req.path {
+                Some(path) => self.check_page_permission(&req.wiki_id, path, WikiRole::Reader)?,
+                None => self.check_permission(&req.wiki_id, WikiRole::Reader)?,
+            }
+        }
+
+        self.watches.retain(|watch| watch.wiki_id != req.wiki_id || watch.path != req.path);
+        if req.watch {
+            self.watches.push(PageWatch {
+                wiki_id: req.wiki_id,
+                path: req.path,
+                created_at: Utc::now().to_rfc3339(),
+            });
+        }
+
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
+    #[http]
+    async fn list_watches(&mut self) -> Result<String, String> {
+        Ok(serde_json::to_string(&self.watches).unwrap())
+    }
+
+    #[http]
+    async fn list_notices(&mut self) -> Result<String, String> {
+        let notices: Vec<&WatchNotice> = self.inbox.iter().rev().collect();
+        Ok(serde_json::to_string(&notices).unwrap())
+    }
+
+    #[http]
+    async fn mark_notices_read(&mut self, body: String) -> Result<String, String> {
+        let req: MarkNoticesReadRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        for notice in self.inbox.iter_mut() {
+            if req.notice_ids.as_ref().is_none_or(|ids| ids.contains(&notice.id)) {
+                notice.read = true;
+            }
+        }
+
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
+    #[http]
+    async fn search_pages_disabled(&mut self, body: String) -> Result<String, String> {
+        let req: SearchRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        // For remote wikis, perform the search remotely
+        if req.wiki_id.contains('@') {
+            let parts: Vec<&str> = req.wiki_id.split('@').collect();
+            if parts.len() == 2 {
+                let wiki_id = parts[0];
+                let node_id = parts[1];
+
+                let target_address = Address::new(node_id, WIKI_PROCESS_ID);
+                let message = WikiMessage::SearchPages {
+                    wiki_id: wiki_id.to_string(),
+                    query: req.query.clone(),
+                };
+
+                let message_body = serde_json::to_string(&message)
+                    .map_err(|e| format!("Failed to serialize message: {}", e))?
+                    .into_bytes();
+
+                match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
+                    Ok(Ok(response_bytes)) => {
+                        let response_str = String::from_utf8(response_bytes)
+                            .map_err(|e| format!("Failed to convert response to string: {}", e))?;
+                        match serde_json::from_str::<WikiResponse>(&response_str) {
+                            Ok(WikiResponse::SearchResults(results)) => {
+                                return Ok(serde_json::to_string(&results).unwrap());
+                            }
+                            Ok(WikiResponse::Error(e)) => return Err(e),
+                            _ => return Err("Unexpected response from remote search".to_string()),
+                        }
+                    }
+                    _ => return Err("Failed to search remote wiki".to_string()),
+                }
+            }
+        }
+
+        // For local wikis, check permissions
+        self.check_permission(&req.wiki_id, WikiRole::Reader)?;
+
+        let query_lower = req.query.to_lowercase();
+        let mut results: Vec<SearchResult> = Vec::new();
+
+        // Search through all pages in the wiki
+        for (page_key, page) in &self.pages {
+            if page.wiki_id != req.wiki_id
+                || self.check_page_permission(&req.wiki_id, &page.path, WikiRole::Reader).is_err()
+            {
+                continue;
+            }
+
+            // Search in path and content
+            let path_matches = page.path.to_lowercase().contains(&query_lower);
+
+            // Decode CRDT content to get actual text
+            let content = if let Some(doc) = self.active_docs.get(page_key) {
+                // Use existing doc
+                let text = doc.get_or_insert_text("content");
+                let txn = doc.transact();
+                text.get_string(&txn)
+            } else {
+                // Create temporary doc to decode content
+                let doc = Doc::new();
                 {
                     let mut txn = doc.transact_mut();
                     if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
@@ -2711,7 +5325,9 @@ impl WikiState {
         let mut results = Vec::new();
 
         for (key, page) in &self.pages {
-            if page.wiki_id != req.wiki_id {
+            if page.wiki_id != req.wiki_id
+                || self.check_page_permission(&req.wiki_id, &page.path, WikiRole::Reader).is_err()
+            {
                 continue;
             }
 
@@ -2758,6 +5374,13 @@ impl WikiState {
             return Err("User is already a member of this wiki".to_string());
         }
 
+        // Inviters can't grant more than they hold, and there's only one SuperAdmin
+        let role = req.role.unwrap_or_else(|| wiki.default_join_role.clone());
+        let inviter_role = wiki.members.get(&self.node_id).cloned().unwrap_or_default();
+        if role == WikiRole::SuperAdmin || !inviter_role.includes(&role) {
+            return Err("Cannot invite with a role above your own".to_string());
+        }
+
         // Check if there's already a pending invite
         let pending_exists = self.invites.values().any(|inv| {
             inv.wiki_id == req.wiki_id &&
@@ -2771,7 +5394,7 @@ impl WikiState {
 
         let invite_id = Uuid::new_v4().to_string();
         let now = Utc::now();
-        let expires_at = now + chrono::Duration::days(7);
+        let expires_at = now + chrono::Duration::days(INVITE_VALID_DAYS);
 
         let invite = WikiInvite {
             id: invite_id.clone(),
@@ -2782,48 +5405,126 @@ impl WikiState {
             created_at: now.to_rfc3339(),
             expires_at: expires_at.to_rfc3339(),
             status: InviteStatus::Pending,
+            role: role.clone(),
         };
+        let role_granted = role;
 
         self.invites.insert(invite_id.clone(), invite.clone());
 
         // Send the invite to the invitee via P2P
-        let target_address = Address::new(&req.invitee_id, WIKI_PROCESS_ID);
         let message = WikiMessage::SendInvite {
-            invite: invite.clone(),
+            invite,
             wiki: wiki.clone(),
         };
+        if let Err(e) = self.deliver_invite(&req.invitee_id, &message).await {
+            // Remove the invite from our local storage if sending failed
+            self.invites.remove(&invite_id);
+            return Err(e);
+        }
 
-        let message_body = serde_json::to_string(&message)
-            .map_err(|e| format!("Failed to serialize invite message: {}", e))?
-            .into_bytes();
+        let actor = self.node_id.clone();
+        self.audit(&req.wiki_id, &actor, AuditAction::InviteSent, Some(&req.invitee_id), Some(format!("As {:?}", role_granted)));
+        self.notify(WsNotification::InvitesUpdated);
+        Ok(serde_json::to_string(&InviteUserResponse {
+            invite_id,
+            success: true,
+        }).unwrap())
+    }
 
-        match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
-            Ok(Ok(response_bytes)) => {
-                let response_str = String::from_utf8(response_bytes)
-                    .map_err(|e| format!("Failed to convert response to string: {}", e))?;
-                match serde_json::from_str::<WikiResponse>(&response_str) {
-                    Ok(WikiResponse::Success(true)) => {
-                        Ok(serde_json::to_string(&InviteUserResponse {
-                            invite_id,
-                            success: true,
-                        }).unwrap())
-                    }
-                    Ok(WikiResponse::Error(err)) => {
-                        // Remove the invite from our local storage if sending failed
-                        self.invites.remove(&invite_id);
-                        Err(format!("Failed to send invite: {}", err))
-                    }
-                    _ => {
-                        self.invites.remove(&invite_id);
-                        Err("Failed to send invite to user".to_string())
-                    }
-                }
-            }
-            _ => {
-                self.invites.remove(&invite_id);
-                Err("Failed to reach invitee node".to_string())
-            }
+    #[http]
+    async fn list_sent_invites(&mut self, body: String) -> Result<String, String> {
+        let req: ListSentInvitesRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
+        self.run_maintenance();
+
+        let mut invites: Vec<SentInviteInfo> = self.invites
+            .values()
+            .filter(|inv| inv.wiki_id == req.wiki_id && inv.inviter_id == self.node_id)
+            .map(|inv| SentInviteInfo {
+                id: inv.id.clone(),
+                wiki_id: inv.wiki_id.clone(),
+                invitee_id: inv.invitee_id.clone(),
+                role: inv.role.clone(),
+                created_at: inv.created_at.clone(),
+                expires_at: inv.expires_at.clone(),
+                status: inv.status.clone(),
+            })
+            .collect();
+        invites.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+        Ok(serde_json::to_string(&invites).unwrap())
+    }
+
+    #[http]
+    async fn revoke_invite(&mut self, body: String) -> Result<String, String> {
+        let req: RevokeInviteRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        let invite = self.invites.get(&req.invite_id)
+            .filter(|invite| invite.inviter_id == self.node_id)
+            .ok_or_else(|| "Invite not found".to_string())?;
+        self.check_permission(&invite.wiki_id, WikiRole::Admin)?;
+
+        if invite.status != InviteStatus::Pending {
+            return Err("Only pending invites can be revoked".to_string());
+        }
+        let invitee_id = invite.invitee_id.clone();
+        let wiki_id = invite.wiki_id.clone();
+        if let Some(invite) = self.invites.get_mut(&req.invite_id) {
+            invite.status = InviteStatus::Revoked;
+        }
+        let actor = self.node_id.clone();
+        self.audit(&wiki_id, &actor, AuditAction::InviteRevoked, Some(&invitee_id), None);
+
+        // Best effort: the invitee can't accept a revoked invite either way
+        let message = WikiMessage::RevokeInvite {
+            invite_id: req.invite_id,
+            inviter_id: self.node_id.clone(),
+        };
+        let _ = self.send_wiki_message(&invitee_id, &message).await;
+
+        self.notify(WsNotification::InvitesUpdated);
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
+    #[http]
+    async fn resend_invite(&mut self, body: String) -> Result<String, String> {
+        let req: ResendInviteRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        let invite = self.invites.get(&req.invite_id)
+            .filter(|invite| invite.inviter_id == self.node_id)
+            .cloned()
+            .ok_or_else(|| "Invite not found".to_string())?;
+        self.check_permission(&invite.wiki_id, WikiRole::Admin)?;
+
+        let lapsed = invite.status == InviteStatus::Pending && Self::invite_expired(&invite);
+        if invite.status != InviteStatus::Expired && !lapsed {
+            return Err("Only expired invites can be resent".to_string());
         }
+        let wiki = self.wikis.get(&invite.wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+        if wiki.members.contains_key(&invite.invitee_id) {
+            return Err("User is already a member of this wiki".to_string());
+        }
+
+        let mut renewed = invite.clone();
+        renewed.status = InviteStatus::Pending;
+        renewed.wiki_name = wiki.name.clone();
+        renewed.expires_at = (Utc::now() + chrono::Duration::days(INVITE_VALID_DAYS)).to_rfc3339();
+        let message = WikiMessage::SendInvite {
+            invite: renewed.clone(),
+            wiki: wiki.clone(),
+        };
+        self.deliver_invite(&invite.invitee_id, &message).await?;
+
+        let actor = self.node_id.clone();
+        self.audit(&invite.wiki_id, &actor, AuditAction::InviteSent, Some(&invite.invitee_id), Some(format!("Resent as {:?}", renewed.role)));
+        self.invites.insert(renewed.id.clone(), renewed);
+        self.notify(WsNotification::InvitesUpdated);
+        Ok(serde_json::to_string(&InviteUserResponse {
+            invite_id: req.invite_id,
+            success: true,
+        }).unwrap())
     }
 
     #[http]
@@ -2852,6 +5553,35 @@ impl WikiState {
         }
 
         if req.accept {
+            // The inviter may have revoked the invite, so wait for it to confirm
+            let inviter_id = invite.inviter_id.clone();
+            let message = WikiMessage::InviteResponse {
+                invite_id: invite.id.clone(),
+                status: InviteStatus::Accepted,
+                invitee_id: self.node_id.clone(),
+            };
+            match self.send_wiki_message(&inviter_id, &message).await {
+                Ok(WikiResponse::Success(true)) => {}
+                Ok(WikiResponse::Error(err)) => {
+                    // Keep our copy in step with the inviter's
+                    let settled = match err.as_str() {
+                        "Invite was revoked" => Some(InviteStatus::Revoked),
+                        "Invite has expired" => Some(InviteStatus::Expired),
+                        _ => None,
+                    };
+                    if let (Some(status), Some(invite)) = (settled, self.invites.get_mut(&req.invite_id)) {
+                        invite.status = status;
+                        self.notify(WsNotification::InvitesUpdated);
+                    }
+                    return Err(format!("Remote error: {}", err));
+                }
+                Ok(_) => return Err("Unexpected response from inviter".to_string()),
+                Err(_) => return Err("Failed to reach inviter node".to_string()),
+            }
+            let Some(invite) = self.invites.get_mut(&req.invite_id) else {
+                return Err("Invite not found".to_string());
+            };
+
             // Store membership with remote reference if this wiki is from another node
             let membership_wiki_id = if invite.inviter_id != self.node_id {
                 format!("{}@{}", invite.wiki_id, invite.inviter_id)
@@ -2861,42 +5591,43 @@ impl WikiState {
 
             self.my_memberships.push(WikiMembership {
                 wiki_id: membership_wiki_id.clone(),
-                role: WikiRole::Reader,
+                role: invite.role.clone(),
                 joined_at: Utc::now().to_rfc3339(),
             });
 
             // Update wiki member list - check both with and without @ suffix
             if let Some(wiki) = self.wikis.get_mut(&membership_wiki_id) {
                 // Remote wiki stored with @ suffix
-                wiki.members.insert(self.node_id.clone(), WikiRole::Reader);
+                wiki.members.insert(self.node_id.clone(), invite.role.clone());
             } else if let Some(wiki) = self.wikis.get_mut(&invite.wiki_id) {
                 // Local wiki (we're the owner)
-                wiki.members.insert(self.node_id.clone(), WikiRole::Reader);
+                wiki.members.insert(self.node_id.clone(), invite.role.clone());
             }
 
             invite.status = InviteStatus::Accepted;
         } else {
             invite.status = InviteStatus::Rejected;
-        }
 
-        // Send notification back to inviter
-        let inviter_id = invite.inviter_id.clone();
-        let invite_id = invite.id.clone();
-        let invite_status = invite.status.clone();
-        let invitee_id = self.node_id.clone();
+            // Send notification back to inviter
+            let inviter_id = invite.inviter_id.clone();
+            let target_address = Address::new(&inviter_id, WIKI_PROCESS_ID);
+            let message = WikiMessage::InviteResponse {
+                invite_id: invite.id.clone(),
+                status: InviteStatus::Rejected,
+                invitee_id: self.node_id.clone(),
+            };
 
-        // Send async notification to inviter
-        let target_address = Address::new(&inviter_id, WIKI_PROCESS_ID);
-        let message = WikiMessage::InviteResponse {
-            invite_id,
-            status: invite_status.clone(),
-            invitee_id,
-        };
+            if let Ok(message_body) = serde_json::to_string(&message).map(|s| s.into_bytes()) {
+                // Fire and forget - we don't wait for the response
+                let _ = caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await;
+            }
+        }
 
-        if let Ok(message_body) = serde_json::to_string(&message).map(|s| s.into_bytes()) {
-            // Fire and forget - we don't wait for the response
-            let _ = caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await;
+        let invite_status = if req.accept { InviteStatus::Accepted } else { InviteStatus::Rejected };
+        if invite_status == InviteStatus::Accepted {
+            self.notify(WsNotification::WikiListUpdated);
         }
+        self.notify(WsNotification::InvitesUpdated);
 
         Ok(serde_json::to_string(&RespondToInviteResponse {
             success: true,
@@ -2906,6 +5637,7 @@ impl WikiState {
 
     #[http]
     async fn list_my_invites(&mut self) -> Result<String, String> {
+        self.run_maintenance();
         let my_invites: Vec<InviteInfo> = self.invites
             .values()
             .filter(|inv| inv.invitee_id == self.node_id)
@@ -2924,6 +5656,7 @@ impl WikiState {
                     created_at: inv.created_at.clone(),
                     expires_at: inv.expires_at.clone(),
                     is_expired,
+                    role: inv.role.clone(),
                 }
             })
             .collect();
@@ -2931,6 +5664,124 @@ impl WikiState {
         Ok(serde_json::to_string(&my_invites).unwrap())
     }
 
+    #[http]
+    async fn request_to_join(&mut self, body: String) -> Result<String, String> {
+        let req: RequestToJoinRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        if req.node_id == self.node_id {
+            return Err("Cannot request to join a wiki hosted on this node".to_string());
+        }
+
+        let request = JoinRequest {
+            id: Uuid::new_v4().to_string(),
+            wiki_id: req.wiki_id.clone(),
+            wiki_name: String::new(), // Filled in by the host
+            host_id: req.node_id.clone(),
+            requester_id: self.node_id.clone(),
+            message: req.message,
+            created_at: Utc::now().to_rfc3339(),
+            status: JoinRequestStatus::Pending,
+            role: None,
+            decided_by: None,
+            decided_at: None,
+        };
+
+        let message = WikiMessage::RequestToJoin { request: request.clone() };
+        match self.send_wiki_message(&req.node_id, &message).await {
+            Ok(WikiResponse::Success(true)) => {}
+            Ok(WikiResponse::Error(err)) => return Err(format!("Remote error: {}", err)),
+            Ok(_) => return Err("Unexpected response from remote node".to_string()),
+            Err(_) => return Err("Failed to reach wiki host".to_string()),
+        }
+
+        self.join_requests.insert(request.id.clone(), request);
+        self.notify(WsNotification::JoinRequestsUpdated);
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
+    #[http]
+    async fn list_my_join_requests(&mut self) -> Result<String, String> {
+        let mut requests: Vec<&JoinRequest> = self.join_requests
+            .values()
+            .filter(|request| request.requester_id == self.node_id)
+            .collect();
+        requests.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+        Ok(serde_json::to_string(&requests).unwrap())
+    }
+
+    #[http]
+    async fn list_join_requests(&mut self, body: String) -> Result<String, String> {
+        let req: ListJoinRequestsRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        self.check_permission(&req.wiki_id, WikiRole::Admin)?;
+
+        let mut requests: Vec<&JoinRequest> = self.join_requests
+            .values()
+            .filter(|request| request.wiki_id == req.wiki_id && request.host_id == self.node_id)
+            .filter(|request| request.status == JoinRequestStatus::Pending)
+            .collect();
+        requests.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+        Ok(serde_json::to_string(&requests).unwrap())
+    }
+
+    #[http]
+    async fn respond_to_join_request(&mut self, body: String) -> Result<String, String> {
+        let req: RespondToJoinRequestRequest = serde_json::from_str(&body)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        let request = self.join_requests.get(&req.request_id)
+            .filter(|request| request.host_id == self.node_id)
+            .cloned()
+            .ok_or_else(|| "Join request not found".to_string())?;
+        self.check_permission(&request.wiki_id, WikiRole::Admin)?;
+
+        if request.status != JoinRequestStatus::Pending {
+            return Err("Join request has already been processed".to_string());
+        }
+
+        let wiki = self.wikis.get_mut(&request.wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+        let mut decided = request.clone();
+        decided.decided_by = Some(self.node_id.clone());
+        decided.decided_at = Some(Utc::now().to_rfc3339());
+
+        if req.approve {
+            // Approvers can't grant more than they hold, and there's only one SuperAdmin
+            let role = req.role.unwrap_or_else(|| wiki.default_join_role.clone());
+            let approver_role = wiki.members.get(&self.node_id).cloned().unwrap_or_default();
+            if role == WikiRole::SuperAdmin || !approver_role.includes(&role) {
+                return Err("Cannot grant a role above your own".to_string());
+            }
+            wiki.members.insert(request.requester_id.clone(), role.clone());
+            decided.status = JoinRequestStatus::Approved;
+            decided.role = Some(role);
+        } else {
+            decided.status = JoinRequestStatus::Denied;
+        }
+        let wiki = req.approve.then(|| wiki.clone());
+
+        let (action, details) = match &decided.role {
+            Some(role) => (AuditAction::JoinRequestApproved, Some(format!("Joined as {:?}", role))),
+            None => (AuditAction::JoinRequestDenied, None),
+        };
+        let actor = self.node_id.clone();
+        self.audit(&request.wiki_id, &actor, action, Some(&request.requester_id), details);
+        self.join_requests.insert(decided.id.clone(), decided.clone());
+        if req.approve {
+            self.replicate_wiki(&request.wiki_id);
+            self.flush_replication().await;
+            self.notify(WsNotification::WikiUpdated { wiki_id: request.wiki_id.clone() });
+        }
+
+        // Best effort: membership is already settled on our side
+        let message = WikiMessage::JoinRequestDecision { request: decided, wiki };
+        let _ = self.send_wiki_message(&request.requester_id, &message).await;
+
+        self.notify(WsNotification::JoinRequestsUpdated);
+        Ok(serde_json::to_string(&SuccessResponse { success: true }).unwrap())
+    }
+
     #[http]
     async fn search_all_wikis(&mut self, body: String) -> Result<String, String> {
         #[derive(Deserialize)]
@@ -2970,7 +5821,10 @@ impl WikiState {
             let mut results: Vec<SearchResult> = Vec::new();
 
             for (page_key, page) in &self.pages {
-                if page.wiki_id != *wiki_id {
+                // Copies of remote wikis only hold what their host let us read
+                if page.wiki_id != *wiki_id
+                    || (!wiki_id.contains('@') && self.check_page_permission(wiki_id, &page.path, WikiRole::Reader).is_err())
+                {
                     continue;
                 }
 
@@ -3234,45 +6088,1680 @@ impl WikiState {
                                         format!("Remote Wiki on {}", node_id)
                                     };
 
-                                    // Add remote results to global results
-                                    for result in results {
-                                        all_results.push(GlobalSearchResult {
-                                            wiki_id: membership.wiki_id.clone(),
-                                            wiki_name: wiki_name.clone(),
-                                            path: result.path,
-                                            updated_by: result.updated_by,
-                                            updated_at: result.updated_at,
-                                            snippet: result.snippet,
-                                        });
-                                    }
-                                }
-                            }
-                        }
-                        _ => {} // Ignore errors from remote searches
-                    }
+                                    // Add remote results to global results
+                                    for result in results {
+                                        all_results.push(GlobalSearchResult {
+                                            wiki_id: membership.wiki_id.clone(),
+                                            wiki_name: wiki_name.clone(),
+                                            path: result.path,
+                                            updated_by: result.updated_by,
+                                            updated_at: result.updated_at,
+                                            snippet: result.snippet,
+                                        });
+                                    }
+                                }
+                            }
+                        }
+                        _ => {} // Ignore errors from remote searches
+                    }
+                }
+            }
+        }
+
+        Ok(serde_json::to_string(&all_results).unwrap())
+    }
+
+}
+
+impl WikiState {
+    fn extract_title_from_markdown(content: &str) -> String {
+        // Get the first non-empty line from the content
+        if let Some(first_line) = content.lines().find(|line| !line.trim().is_empty()) {
+            let trimmed = first_line.trim();
+            // Remove markdown heading syntax if present
+            if let Some(title) = trimmed.strip_prefix('#') {
+                title.trim().to_string()
+            } else {
+                trimmed.to_string()
+            }
+        } else {
+            "Untitled".to_string()
+        }
+    }
+
+    /// Applies `content` to the page's live yrs doc and records a new version.
+    /// The page is moved if its title changed; returns the resulting page path.
+    /// Creates a page with its first version, refusing to replace one that
+    /// already exists.
+    fn add_page(
+        &mut self,
+        wiki_id: &str,
+        path: &str,
+        initial_content: &str,
+        user_id: &str,
+        commit_message: Option<String>,
+    ) -> Result<(), String> {
+        let page_key = format!("{}:{}", wiki_id, path);
+        if self.pages.contains_key(&page_key) {
+            return Err("Page already exists".to_string());
+        }
+
+        // Create CRDT document
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("content");
+        {
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, initial_content);
+            txn.commit();
+        }
+
+        let mut encoder = EncoderV1::new();
+        doc.transact().encode_state_as_update(&yrs::StateVector::default(), &mut encoder);
+        let update = encoder.to_vec();
+
+        // Create the first version
+        let version_id = Uuid::new_v4().to_string();
+        let first_version = PageVersion {
+            version_id: version_id.clone(),
+            content: update.clone(),
+            delta: false,
+            updated_by: user_id.to_string(),
+            updated_at: Utc::now().to_rfc3339(),
+            commit_message,
+            change: PageChange::Created,
+            tag: None,
+        };
+
+        let page = WikiPage {
+            path: path.to_string(),
+            wiki_id: wiki_id.to_string(),
+            current_version: first_version.clone(),
+            yrs_doc: update.clone(),
+            protected: false,
+            lock: None,
+        };
+
+        // Create page history
+        let history = PageHistory {
+            path: path.to_string(),
+            wiki_id: wiki_id.to_string(),
+            versions: vec![first_version],
+            current_version_id: version_id,
+            head_state_vector: doc.transact().state_vector().encode_v1(),
+        };
+
+        self.pages.insert(page_key.clone(), page);
+        self.page_histories.insert(page_key.clone(), history);
+        self.active_docs.insert(page_key, doc);
+        self.replicate_page(wiki_id, path, None, update);
+        self.audit(wiki_id, user_id, AuditAction::PageCreated, Some(path), None);
+        self.notify_page_watchers(wiki_id, path);
+        Ok(())
+    }
+
+    /// Writes `user_id`'s new content to an existing page, if its protection,
+    /// edit lock and any retitle allow; returns the page's path afterwards.
+    fn edit_page(
+        &mut self,
+        wiki_id: &str,
+        path: &str,
+        content: &str,
+        user_id: &str,
+        commit_message: Option<String>,
+    ) -> Result<String, String> {
+        if !self.pages.contains_key(&format!("{}:{}", wiki_id, path)) {
+            return Err("Page not found".to_string());
+        }
+        self.check_page_editable(wiki_id, path, user_id)?;
+        self.check_retitle(wiki_id, path, content)?;
+        Ok(self.write_page(wiki_id, path, content, user_id, commit_message))
+    }
+
+    /// Moves a page and its history to the deleted pages, if its protection
+    /// and edit lock let `user_id` change it.
+    fn remove_page(&mut self, wiki_id: &str, path: &str, user_id: &str) -> Result<(), String> {
+        self.check_page_editable(wiki_id, path, user_id)?;
+
+        let page_key = format!("{}:{}", wiki_id, path);
+        if self.pages.remove(&page_key).is_none() {
+            return Err("Page not found".to_string());
+        }
+        if let Some(history) = self.page_histories.remove(&page_key) {
+            let deleted_key = format!("{}:{}:{}", wiki_id, path, Utc::now().timestamp());
+            let deleted_page = DeletedPage {
+                path: path.to_string(),
+                wiki_id: wiki_id.to_string(),
+                deleted_at: Utc::now().to_rfc3339(),
+                deleted_by: user_id.to_string(),
+                history,
+            };
+
+            self.replicate(wiki_id, WikiMessage::ReplicatePageDeleted {
+                deleted_key: deleted_key.clone(),
+                deleted_page: deleted_page.clone(),
+            });
+            self.deleted_pages.insert(deleted_key, deleted_page);
+        }
+
+        self.active_docs.remove(&page_key);
+        self.audit(wiki_id, user_id, AuditAction::PageDeleted, Some(path), None);
+        self.notify_watchers(wiki_id, RecentChange::deletion(path, user_id, &Utc::now().to_rfc3339()));
+        Ok(())
+    }
+
+    fn write_page(
+        &mut self,
+        wiki_id: &str,
+        path: &str,
+        content: &str,
+        updated_by: &str,
+        commit_message: Option<String>,
+    ) -> String {
+        self.write_page_since(wiki_id, path, content, updated_by, commit_message, None)
+    }
+
+    /// `write_page` for a doc already changed since `since`, a state it was at
+    /// before; those changes are relayed along with any made by `content`.
+    fn write_page_since(
+        &mut self,
+        wiki_id: &str,
+        path: &str,
+        content: &str,
+        updated_by: &str,
+        commit_message: Option<String>,
+        since: Option<yrs::StateVector>,
+    ) -> String {
+        let old_page_key = format!("{}:{}", wiki_id, path);
+
+        // Extract the new title from the content
+        let new_title = Self::extract_title_from_markdown(content);
+        let new_page_key = format!("{}:{}", wiki_id, new_title);
+
+        // Check if title has changed
+        let title_changed = path != new_title;
+
+        // Protection and the edit lock follow the page through a retitle
+        let (protected, lock) = self.pages.get(&old_page_key)
+            .map(|page| (page.protected, page.lock.clone()))
+            .unwrap_or_default();
+
+        let doc = if title_changed {
+            // Remove the old page and doc
+            if let Some(old_doc) = self.active_docs.remove(&old_page_key) {
+                self.pages.remove(&old_page_key);
+                old_doc
+            } else if let Some(page) = self.pages.remove(&old_page_key) {
+                let new_doc = Doc::new();
+                {
+                    let mut txn = new_doc.transact_mut();
+                    if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
+                        let _ = txn.apply_update(update);
+                    }
+                }
+                new_doc
+            } else {
+                Doc::new()
+            }
+        } else {
+            self.active_docs.entry(old_page_key.clone())
+                .or_insert_with(|| {
+                    if let Some(page) = self.pages.get(&old_page_key) {
+                        let new_doc = Doc::new();
+                        {
+                            let mut txn = new_doc.transact_mut();
+                            if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
+                                let _ = txn.apply_update(update);
+                            }
+                        }
+                        new_doc
+                    } else {
+                        Doc::new()
+                    }
+                })
+                .clone()
+        };
+
+        // Apply only the changed span so concurrent edits to the live doc survive
+        let state_before = since.unwrap_or_else(|| doc.transact().state_vector());
+        Self::apply_text_change(&doc, content);
+        let edit_update = doc.transact().encode_diff_v1(&state_before);
+
+        let mut encoder = EncoderV1::new();
+        doc.transact().encode_state_as_update(&yrs::StateVector::default(), &mut encoder);
+        let update = encoder.to_vec();
+
+        // Create new version
+        let change = if !self.page_histories.contains_key(&old_page_key) {
+            PageChange::Created
+        } else if title_changed {
+            PageChange::Renamed { from: path.to_string() }
+        } else {
+            PageChange::Edited
+        };
+        let new_version = PageVersion {
+            version_id: Uuid::new_v4().to_string(),
+            content: Vec::new(), // Filled in by record_version
+            delta: false,
+            updated_by: updated_by.to_string(),
+            updated_at: Utc::now().to_rfc3339(),
+            commit_message,
+            change,
+            tag: None,
+        };
+
+        // Record it in the history, which moves along with a retitled page
+        let mut history = self.page_histories.remove(&old_page_key)
+            .unwrap_or_else(|| PageHistory {
+                path: new_title.clone(),
+                wiki_id: wiki_id.to_string(),
+                versions: Vec::new(),
+                current_version_id: String::new(),
+                head_state_vector: Vec::new(),
+            });
+        history.path = new_title.clone();
+        let new_version = history.record_version(new_version, &doc);
+        self.page_histories.insert(new_page_key.clone(), history);
+
+        let page = WikiPage {
+            path: new_title.clone(),
+            wiki_id: wiki_id.to_string(),
+            current_version: new_version,
+            yrs_doc: update,
+            protected,
+            lock,
+        };
+
+        // Insert with new key
+        self.pages.insert(new_page_key.clone(), page);
+        self.active_docs.insert(new_page_key.clone(), doc);
+
+        // Keep collaborative editors on this page in sync
+        if title_changed {
+            if let Some(channels) = self.edit_sessions.remove(&old_page_key) {
+                self.edit_sessions.insert(new_page_key.clone(), channels);
+            }
+        }
+        self.broadcast_edit_update(wiki_id, &new_title, &edit_update, None);
+        self.replicate_page(wiki_id, &new_title, title_changed.then_some(path), edit_update);
+        let details = title_changed.then(|| format!("Renamed from {}", path));
+        self.audit(wiki_id, updated_by, AuditAction::PageUpdated, Some(&new_title), details);
+        self.notify_page_watchers(wiki_id, &new_title);
+
+        new_title
+    }
+
+    /// Sets or clears a version's tag. Tagged versions survive retention pruning.
+    fn set_version_tag(&mut self, wiki_id: &str, path: &str, version_id: &str, tag: Option<String>) -> Result<(), String> {
+        if self.wikis.get(wiki_id).is_some_and(|wiki| wiki.replica_of.is_some()) {
+            return Err("This wiki is a read-only replica".to_string());
+        }
+        let page_key = format!("{}:{}", wiki_id, path);
+        let version = self.page_histories.get_mut(&page_key)
+            .and_then(|history| history.versions.iter_mut().find(|version| version.version_id == version_id))
+            .ok_or_else(|| "Version not found".to_string())?;
+        version.tag = tag.map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty());
+
+        // Replicas only hear about new versions, so send them the whole history
+        self.resync_replicas(wiki_id);
+        Ok(())
+    }
+
+    /// Writes an old version's text back to a page as a new version; returns the
+    /// resulting page path, which moves if the old text had a different title.
+    fn revert_page_to(&mut self, wiki_id: &str, path: &str, version_id: &str, user_id: &str) -> Result<String, String> {
+        let wiki = self.wikis.get(wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+        if wiki.replica_of.is_some() {
+            return Err("Wiki is a read-only replica".to_string());
+        }
+        let page_key = format!("{}:{}", wiki_id, path);
+        if !self.pages.contains_key(&page_key) {
+            return Err("Page not found".to_string());
+        }
+        let history = self.page_histories.get(&page_key)
+            .ok_or_else(|| "Version not found".to_string())?;
+        let index = history.versions.iter().position(|version| version.version_id == version_id)
+            .ok_or_else(|| "Version not found".to_string())?;
+        let content = history.version_text(index)?;
+        let version = &history.versions[index];
+        let commit_message = format!("Reverted to version {} from {}", version.version_id, version.updated_at);
+
+        // A retitle moves the page, so the reverter must be able to write there too
+        wiki.check_page_access(user_id, &Self::extract_title_from_markdown(&content), WikiRole::Writer)?;
+        self.check_page_editable(wiki_id, path, user_id)?;
+        self.check_retitle(wiki_id, path, &content)?;
+
+        Ok(self.write_page(wiki_id, path, &content, user_id, Some(commit_message)))
+    }
+
+    /// Rewrites the "content" text of `doc` to `new_content`, touching only the
+    /// span between the common prefix and suffix of the old and new text.
+    fn apply_text_change(doc: &Doc, new_content: &str) {
+        let text = doc.get_or_insert_text("content");
+        let mut txn = doc.transact_mut();
+        let current = text.get_string(&txn);
+        if current == new_content {
+            return;
+        }
+
+        // Text offsets are UTF-8 byte offsets (yrs default OffsetKind::Bytes)
+        let prefix_len: usize = current.chars()
+            .zip(new_content.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        let suffix_len: usize = current[prefix_len..].chars().rev()
+            .zip(new_content[prefix_len..].chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+
+        let removed_len = current.len() - prefix_len - suffix_len;
+        if removed_len > 0 {
+            text.remove_range(&mut txn, prefix_len as u32, removed_len as u32);
+        }
+        let inserted = &new_content[prefix_len..new_content.len() - suffix_len];
+        if !inserted.is_empty() {
+            text.insert(&mut txn, prefix_len as u32, inserted);
+        }
+    }
+
+    /// Returns the live doc for a page, loading it from the stored update if needed.
+    fn page_doc(&mut self, page_key: &str) -> Option<Doc> {
+        let page = self.pages.get(page_key)?;
+        let doc = self.active_docs.entry(page_key.to_string())
+            .or_insert_with(|| {
+                let new_doc = Doc::new();
+                {
+                    let mut txn = new_doc.transact_mut();
+                    if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
+                        let _ = txn.apply_update(update);
+                    }
+                }
+                new_doc
+            });
+        Some(doc.clone())
+    }
+
+    /// Returns the text `doc` would hold after `update`, without changing `doc`.
+    fn preview_update(doc: &Doc, update: &[u8]) -> Result<String, String> {
+        let preview = Doc::new();
+        let state = doc.transact().encode_state_as_update_v1(&yrs::StateVector::default());
+        for bytes in [state.as_slice(), update] {
+            let decoded = yrs::Update::decode_v1(bytes)
+                .map_err(|e| format!("Failed to decode update: {}", e))?;
+            preview.transact_mut().apply_update(decoded)
+                .map_err(|e| format!("Failed to apply update: {}", e))?;
+        }
+        let text = preview.get_or_insert_text("content");
+        let content = text.get_string(&preview.transact());
+        Ok(content)
+    }
+
+    fn join_edit_session(
+        &mut self,
+        channel_id: u32,
+        wiki_id: &str,
+        path: &str,
+        state_vector: Option<String>,
+    ) -> Result<(), String> {
+        self.check_page_permission(wiki_id, path, WikiRole::Writer)?;
+
+        let page_key = format!("{}:{}", wiki_id, path);
+        let doc = self.page_doc(&page_key)
+            .ok_or_else(|| "Page not found".to_string())?;
+
+        // Send the client only what it is missing
+        let client_state = match state_vector {
+            Some(encoded) => {
+                let bytes = BASE64.decode(encoded)
+                    .map_err(|e| format!("Invalid state vector: {}", e))?;
+                yrs::StateVector::decode_v1(&bytes)
+                    .map_err(|e| format!("Invalid state vector: {}", e))?
+            }
+            None => yrs::StateVector::default(),
+        };
+        let (update, server_state) = {
+            let txn = doc.transact();
+            (txn.encode_diff_v1(&client_state), txn.state_vector().encode_v1())
+        };
+
+        self.edit_sessions.entry(page_key).or_default().insert(channel_id);
+        Self::push_to_channel(channel_id, &WsNotification::EditSync {
+            wiki_id: wiki_id.to_string(),
+            path: path.to_string(),
+            update: BASE64.encode(update),
+            state_vector: BASE64.encode(server_state),
+        });
+
+        Ok(())
+    }
+
+    fn leave_edit_session(&mut self, channel_id: u32, wiki_id: &str, path: &str) {
+        let page_key = format!("{}:{}", wiki_id, path);
+        let Some(channels) = self.edit_sessions.get_mut(&page_key) else {
+            return;
+        };
+        if !channels.remove(&channel_id) {
+            return;
+        }
+
+        // Clear the departed editor's cursor for everyone else
+        let notification = WsNotification::Awareness {
+            wiki_id: wiki_id.to_string(),
+            path: path.to_string(),
+            channel_id,
+            state: None,
+        };
+        for other in channels.iter() {
+            Self::push_to_channel(*other, &notification);
+        }
+        if channels.is_empty() {
+            self.edit_sessions.remove(&page_key);
+        }
+    }
+
+    fn leave_all_edit_sessions(&mut self, channel_id: u32) {
+        let sessions: Vec<(String, String)> = self.edit_sessions
+            .iter()
+            .filter(|(_, channels)| channels.contains(&channel_id))
+            .filter_map(|(page_key, _)| {
+                self.pages.get(page_key).map(|page| (page.wiki_id.clone(), page.path.clone()))
+            })
+            .collect();
+
+        for (wiki_id, path) in sessions {
+            self.leave_edit_session(channel_id, &wiki_id, &path);
+        }
+        self.edit_sessions.retain(|_, channels| {
+            channels.remove(&channel_id);
+            !channels.is_empty()
+        });
+    }
+
+    fn apply_edit_update(&mut self, channel_id: u32, wiki_id: &str, path: &str, update: &str) -> Result<(), String> {
+        let page_key = format!("{}:{}", wiki_id, path);
+        if !self.edit_sessions.get(&page_key).is_some_and(|c| c.contains(&channel_id)) {
+            return Err("Not in an edit session for this page".to_string());
+        }
+        // Roles, protection and locks can change mid-session
+        self.check_page_permission(wiki_id, path, WikiRole::Writer)?;
+        self.check_page_editable(wiki_id, path, &self.node_id)?;
+
+        let bytes = BASE64.decode(update)
+            .map_err(|e| format!("Invalid update encoding: {}", e))?;
+        let decoded = yrs::Update::decode_v1(&bytes)
+            .map_err(|e| format!("Failed to decode update: {}", e))?;
+
+        let doc = self.page_doc(&page_key)
+            .ok_or_else(|| "Page not found".to_string())?;
+        doc.transact_mut().apply_update(decoded)
+            .map_err(|e| format!("Failed to apply update: {}", e))?;
+
+        // Persist the merged state; a version is only recorded on commit
+        let mut encoder = EncoderV1::new();
+        doc.transact().encode_state_as_update(&yrs::StateVector::default(), &mut encoder);
+        if let Some(page) = self.pages.get_mut(&page_key) {
+            page.yrs_doc = encoder.to_vec();
+        }
+
+        self.broadcast_edit_update(wiki_id, path, &bytes, Some(channel_id));
+        Ok(())
+    }
+
+    fn commit_edit_session(
+        &mut self,
+        channel_id: u32,
+        wiki_id: &str,
+        path: &str,
+        commit_message: Option<String>,
+    ) -> Result<(), String> {
+        let page_key = format!("{}:{}", wiki_id, path);
+        if !self.edit_sessions.get(&page_key).is_some_and(|c| c.contains(&channel_id)) {
+            return Err("Not in an edit session for this page".to_string());
+        }
+        self.check_page_permission(wiki_id, path, WikiRole::Writer)?;
+        self.check_page_editable(wiki_id, path, &self.node_id)?;
+
+        let doc = self.page_doc(&page_key)
+            .ok_or_else(|| "Page not found".to_string())?;
+        let text = doc.get_or_insert_text("content");
+        let content = text.get_string(&doc.transact());
+        // A retitle moves the page, so we must be able to write there too
+        self.check_page_permission(wiki_id, &Self::extract_title_from_markdown(&content), WikiRole::Writer)?;
+        self.check_retitle(wiki_id, path, &content)?;
+
+        let node_id = self.node_id.clone();
+        let new_path = self.write_page(wiki_id, path, &content, &node_id, commit_message);
+
+        self.notify(WsNotification::PageListUpdated { wiki_id: wiki_id.to_string() });
+        if new_path != path {
+            self.notify(WsNotification::PageUpdated { wiki_id: wiki_id.to_string(), path: path.to_string() });
+        }
+        self.notify(WsNotification::PageUpdated { wiki_id: wiki_id.to_string(), path: new_path.clone() });
+
+        let notification = WsNotification::EditCommitted {
+            wiki_id: wiki_id.to_string(),
+            previous_path: path.to_string(),
+            path: new_path.clone(),
+        };
+        if let Some(channels) = self.edit_sessions.get(&format!("{}:{}", wiki_id, new_path)) {
+            for channel in channels {
+                Self::push_to_channel(*channel, &notification);
+            }
+        }
+        Ok(())
+    }
+
+    /// Relays a yrs update to everyone editing the page except its sender.
+    fn broadcast_edit_update(&self, wiki_id: &str, path: &str, update: &[u8], except: Option<u32>) {
+        let page_key = format!("{}:{}", wiki_id, path);
+        let Some(channels) = self.edit_sessions.get(&page_key) else {
+            return;
+        };
+
+        let notification = WsNotification::EditUpdate {
+            wiki_id: wiki_id.to_string(),
+            path: path.to_string(),
+            update: BASE64.encode(update),
+        };
+        for channel in channels.iter().filter(|c| Some(**c) != except) {
+            Self::push_to_channel(*channel, &notification);
+        }
+    }
+
+    fn push_to_channel(channel_id: u32, notification: &WsNotification) {
+        match serde_json::to_vec(notification) {
+            Ok(bytes) => send_ws_push(
+                channel_id,
+                WsMessageType::Text,
+                LazyLoadBlob::new(Some("application/json"), bytes),
+            ),
+            Err(e) => println!("Failed to serialize notification: {}", e),
+        }
+    }
+
+    /// Sends a `SendInvite` to the invitee and checks it was stored.
+    async fn deliver_invite(&self, invitee_id: &str, message: &WikiMessage) -> Result<(), String> {
+        match self.send_wiki_message(invitee_id, message).await {
+            Ok(WikiResponse::Success(true)) => Ok(()),
+            Ok(WikiResponse::Error(err)) => Err(format!("Failed to send invite: {}", err)),
+            Ok(_) => Err("Failed to send invite to user".to_string()),
+            Err(_) => Err("Failed to reach invitee node".to_string()),
+        }
+    }
+
+    fn invite_expired(invite: &WikiInvite) -> bool {
+        chrono::DateTime::parse_from_rfc3339(&invite.expires_at)
+            .is_ok_and(|expires_at| Utc::now() > expires_at)
+    }
+
+    /// Migrates histories saved before versions were stored as deltas.
+    fn compact_page_histories(&mut self) {
+        let histories = self.page_histories.values_mut()
+            .chain(self.deleted_pages.values_mut().map(|deleted_page| &mut deleted_page.history));
+        for history in histories {
+            if history.head_state_vector.is_empty() && !history.versions.is_empty() {
+                history.compact();
+            }
+        }
+    }
+
+    /// Housekeeping that would otherwise need a timer. Runs off incoming
+    /// requests, at most once per MAINTENANCE_INTERVAL_MINUTES.
+    fn run_maintenance(&mut self) {
+        let now = Utc::now();
+        let due = self.last_maintenance_at.as_ref()
+            .and_then(|at| chrono::DateTime::parse_from_rfc3339(at).ok())
+            .is_none_or(|at| now.signed_duration_since(at) >= chrono::Duration::minutes(MAINTENANCE_INTERVAL_MINUTES));
+        if !due {
+            return;
+        }
+        self.last_maintenance_at = Some(now.to_rfc3339());
+
+        self.sweep_invites();
+        self.apply_retention_policies();
+        self.sweep_join_requests();
+        self.purge_deleted_wikis();
+    }
+
+    /// Runs the retention policy of every wiki we host.
+    fn apply_retention_policies(&mut self) {
+        let wiki_ids: Vec<String> = self.wikis.values()
+            .filter(|wiki| wiki.replica_of.is_none() && !wiki.id.contains('@'))
+            .map(|wiki| wiki.id.clone())
+            .collect();
+        let actor = self.node_id.clone();
+        for wiki_id in wiki_ids {
+            self.purge_history_for(&wiki_id, &actor);
+        }
+    }
+
+    /// Applies a hosted wiki's retention policy: prunes page versions it no
+    /// longer keeps and purges deleted pages past their grace period.
+    fn purge_history_for(&mut self, wiki_id: &str, actor: &str) -> PurgeResult {
+        let mut result = PurgeResult::default();
+        let Some(policy) = self.wikis.get(wiki_id)
+            .filter(|wiki| wiki.replica_of.is_none())
+            .map(|wiki| wiki.retention.clone()) else { return result };
+
+        let now = Utc::now();
+        // Anything with an unreadable timestamp counts as recent and is kept
+        let is_recent = |timestamp: &str, days: u32| {
+            chrono::DateTime::parse_from_rfc3339(timestamp)
+                .map_or(true, |at| now.signed_duration_since(at) < chrono::Duration::days(days as i64))
+        };
+
+        if policy.keep_last.is_some() || policy.keep_days.is_some() {
+            let prefix = format!("{}:", wiki_id);
+            let live = self.page_histories.iter_mut()
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .map(|(_, history)| history);
+            let deleted = self.deleted_pages.values_mut()
+                .filter(|deleted_page| deleted_page.wiki_id == wiki_id)
+                .map(|deleted_page| &mut deleted_page.history);
+            for history in live.chain(deleted) {
+                let count = history.versions.len();
+                result.versions_removed += history.prune(|index, version| {
+                    version.tag.is_some()
+                        || policy.keep_last.is_some_and(|keep_last| index + keep_last >= count)
+                        || policy.keep_days.is_some_and(|days| is_recent(&version.updated_at, days))
+                });
+            }
+        }
+
+        if let Some(days) = policy.deleted_page_days {
+            let before = self.deleted_pages.len();
+            self.deleted_pages.retain(|_, deleted_page| deleted_page.wiki_id != wiki_id || is_recent(&deleted_page.deleted_at, days));
+            result.deleted_pages_removed = before - self.deleted_pages.len();
+        }
+
+        if result.versions_removed > 0 || result.deleted_pages_removed > 0 {
+            let details = format!("{} versions, {} deleted pages", result.versions_removed, result.deleted_pages_removed);
+            self.audit(wiki_id, actor, AuditAction::HistoryPurged, None, Some(details));
+            self.resync_replicas(wiki_id);
+        }
+        result
+    }
+
+    /// Permanently removes wikis whose deletion grace period has passed.
+    fn purge_deleted_wikis(&mut self) {
+        let now = Utc::now();
+        let expired: Vec<String> = self.deleted_wikis.iter()
+            .filter(|(_, deleted)| {
+                chrono::DateTime::parse_from_rfc3339(&deleted.purge_at)
+                    .is_ok_and(|purge_at| now > purge_at)
+            })
+            .map(|(wiki_id, _)| wiki_id.clone())
+            .collect();
+
+        for wiki_id in expired {
+            println!("Purging deleted wiki {}", wiki_id);
+            self.deleted_wikis.remove(&wiki_id);
+            self.drop_wiki_copy(&wiki_id);
+            self.my_memberships.retain(|m| m.wiki_id != wiki_id);
+            self.invites.retain(|_, invite| invite.wiki_id != wiki_id || invite.inviter_id != self.node_id);
+            self.join_requests.retain(|_, request| request.wiki_id != wiki_id || request.host_id != self.node_id);
+            self.audit_log.remove(&wiki_id);
+            self.watchers.remove(&wiki_id);
+            self.watches.retain(|watch| watch.wiki_id != wiki_id);
+        }
+    }
+
+    /// Page versions and deletions across a wiki, newest first, limited to the
+    /// pages `viewer` may read.
+    fn collect_recent_changes(
+        &self,
+        wiki_id: &str,
+        viewer: &str,
+        offset: Option<usize>,
+        limit: Option<usize>,
+    ) -> Result<RecentChangesPage, String> {
+        let wiki = self.wikis.get(wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+        let readable = |path: &str| wiki.check_page_access(viewer, path, WikiRole::Reader).is_ok();
+
+        let mut changes = Vec::new();
+        let push_history = |history: &PageHistory, changes: &mut Vec<RecentChange>| {
+            // Walk backwards so each version knows the path it left the page at
+            let mut path = history.path.clone();
+            for (index, version) in history.versions.iter().enumerate().rev() {
+                let mut change = RecentChange::from_version(&path, version);
+                // Versions from before changes were recorded
+                if index == 0 && change.kind == RecentChangeKind::Edited {
+                    change.kind = RecentChangeKind::Created;
+                }
+                if let Some(previous_path) = &change.previous_path {
+                    path = previous_path.clone();
+                }
+                if readable(&change.path) {
+                    if let PageChange::Restored { deleted_at, deleted_by } = &version.change {
+                        changes.push(RecentChange::deletion(&change.path, deleted_by, deleted_at));
+                    }
+                    changes.push(change);
+                }
+            }
+        };
+
+        for history in self.page_histories.values().filter(|history| history.wiki_id == wiki_id) {
+            push_history(history, &mut changes);
+        }
+        for deleted_page in self.deleted_pages.values().filter(|deleted| deleted.wiki_id == wiki_id) {
+            push_history(&deleted_page.history, &mut changes);
+            if readable(&deleted_page.path) {
+                changes.push(RecentChange::deletion(&deleted_page.path, &deleted_page.deleted_by, &deleted_page.deleted_at));
+            }
+        }
+        changes.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        let limit = limit.unwrap_or(RECENT_CHANGES_PAGE_SIZE).min(MAX_RECENT_CHANGES_PAGE_SIZE);
+        Ok(RecentChangesPage {
+            total: changes.len(),
+            changes: changes.into_iter().skip(offset.unwrap_or(0)).take(limit).collect(),
+        })
+    }
+
+    fn notify_page_watchers(&mut self, wiki_id: &str, path: &str) {
+        let Some(page) = self.pages.get(&format!("{}:{}", wiki_id, path)) else { return };
+        let change = RecentChange::from_version(path, &page.current_version);
+        self.notify_watchers(wiki_id, change);
+    }
+
+    /// Tells everyone watching a page of a wiki we host, or the whole wiki, that
+    /// the page changed. Remote watchers are sent a PageChanged on the next flush.
+    fn notify_watchers(&mut self, wiki_id: &str, change: RecentChange) {
+        let Some(wiki) = self.wikis.get(wiki_id) else { return };
+        if wiki.replica_of.is_some() || wiki_id.contains('@') {
+            return;
+        }
+
+        let mut recipients: Vec<String> = Vec::new();
+        for watcher in self.watchers.get_mut(wiki_id).into_iter().flatten() {
+            let watched = watcher.path.as_ref()
+                .is_none_or(|path| path == &change.path || Some(path) == change.previous_path.as_ref());
+            if watched
+                && watcher.node_id != change.author
+                && !recipients.contains(&watcher.node_id)
+                && wiki.check_page_access(&watcher.node_id, &change.path, WikiRole::Reader).is_ok()
+            {
+                recipients.push(watcher.node_id.clone());
+            }
+            // Page watches follow the page when it's renamed
+            if change.previous_path.is_some() && watcher.path == change.previous_path {
+                watcher.path = Some(change.path.clone());
+            }
+        }
+        for node_id in recipients {
+            let queue = self.watch_outbox.entry(node_id).or_default();
+            if queue.len() >= WATCH_OUTBOX_LIMIT {
+                queue.remove(0);
+            }
+            queue.push(WikiMessage::PageChanged {
+                wiki_id: wiki_id.to_string(),
+                host_id: self.node_id.clone(),
+                change: change.clone(),
+            });
+        }
+
+        self.record_watch_notice(wiki_id, change);
+    }
+
+    /// Files a change to a page we watch in the inbox, unless we made it.
+    /// Returns whether we watch the page at all.
+    fn record_watch_notice(&mut self, wiki_id: &str, change: RecentChange) -> bool {
+        let mut watching = false;
+        for watch in self.watches.iter_mut().filter(|watch| watch.wiki_id == wiki_id) {
+            let watched = watch.path.as_ref()
+                .is_none_or(|path| path == &change.path || Some(path) == change.previous_path.as_ref());
+            watching |= watched;
+            if change.previous_path.is_some() && watch.path == change.previous_path {
+                watch.path = Some(change.path.clone());
+            }
+        }
+        if !watching || change.author == self.node_id {
+            return watching;
+        }
+
+        let notice = WatchNotice {
+            id: Uuid::new_v4().to_string(),
+            wiki_id: wiki_id.to_string(),
+            change,
+            received_at: Utc::now().to_rfc3339(),
+            read: false,
+        };
+        if self.inbox.len() >= INBOX_LIMIT {
+            self.inbox.remove(0);
+        }
+        self.inbox.push(notice.clone());
+        self.notify(WsNotification::WatchNotice { notice });
+        true
+    }
+
+    /// Appends to the audit log of a wiki we host. Replica and remote copies keep none.
+    fn audit(&mut self, wiki_id: &str, actor: &str, action: AuditAction, target: Option<&str>, details: Option<String>) {
+        let hosted = !wiki_id.contains('@')
+            && self.wikis.get(wiki_id).is_some_and(|wiki| wiki.replica_of.is_none());
+        if !hosted {
+            return;
+        }
+        self.audit_log.entry(wiki_id.to_string()).or_default().push(AuditEntry {
+            id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            actor: actor.to_string(),
+            action,
+            target: target.map(str::to_string),
+            details,
+        });
+    }
+
+    /// One page of a hosted wiki's audit log, newest first.
+    fn query_audit_log(&self, wiki_id: &str, query: &AuditLogQuery) -> Result<AuditLogPage, String> {
+        match self.wikis.get(wiki_id) {
+            Some(wiki) if wiki.replica_of.is_some() => {
+                return Err("The audit log is kept by the wiki's host".to_string());
+            }
+            Some(_) => {}
+            None => return Err("Wiki not found".to_string()),
+        }
+
+        let parse = |at: &Option<String>| {
+            at.as_deref()
+                .map(|at| chrono::DateTime::parse_from_rfc3339(at).map_err(|_| format!("Invalid timestamp: {}", at)))
+                .transpose()
+        };
+        let since = parse(&query.since)?;
+        let until = parse(&query.until)?;
+
+        let matching: Vec<&AuditEntry> = self.audit_log.get(wiki_id)
+            .into_iter()
+            .flatten()
+            .rev()
+            .filter(|entry| query.actor.as_ref().is_none_or(|actor| &entry.actor == actor))
+            .filter(|entry| query.action.as_ref().is_none_or(|action| &entry.action == action))
+            .filter(|entry| {
+                let Ok(at) = chrono::DateTime::parse_from_rfc3339(&entry.timestamp) else {
+                    return false;
+                };
+                since.is_none_or(|since| at >= since) && until.is_none_or(|until| at < until)
+            })
+            .collect();
+
+        let limit = query.limit.unwrap_or(AUDIT_PAGE_SIZE).min(MAX_AUDIT_PAGE_SIZE);
+        Ok(AuditLogPage {
+            total: matching.len(),
+            entries: matching.into_iter()
+                .skip(query.offset.unwrap_or(0))
+                .take(limit)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Records our role in a remote wiki fetched from its host (`wiki.id` is
+    /// "wiki_id@node_id"), in case a RoleUpdate never reached us.
+    fn cache_remote_role(&mut self, wiki: &Wiki) {
+        let Some(role) = wiki.members.get(&self.node_id) else {
+            return;
+        };
+        let Some(membership) = self.my_memberships.iter_mut().find(|m| m.wiki_id == wiki.id) else {
+            return;
+        };
+        if &membership.role != role {
+            membership.role = role.clone();
+            self.notify(WsNotification::RoleUpdated {
+                wiki_id: wiki.id.clone(),
+                new_role: role.clone(),
+            });
+        }
+    }
+
+    /// Forgets everything we hold for a remote wiki ("wiki_id@node_id") we're
+    /// no longer part of: membership, cached copy and queued edits.
+    fn forget_remote_wiki(&mut self, remote_wiki_id: &str) {
+        self.my_memberships.retain(|m| m.wiki_id != remote_wiki_id);
+        self.replicas.remove(remote_wiki_id);
+        self.watches.retain(|watch| watch.wiki_id != remote_wiki_id);
+        self.drop_wiki_copy(remote_wiki_id);
+    }
+
+    /// Forgets decided join requests INVITE_RETENTION_DAYS after the decision.
+    fn sweep_join_requests(&mut self) {
+        let cutoff = Utc::now() - chrono::Duration::days(INVITE_RETENTION_DAYS);
+        let count_before = self.join_requests.len();
+        self.join_requests.retain(|_, request| {
+            request.decided_at.as_ref()
+                .and_then(|at| chrono::DateTime::parse_from_rfc3339(at).ok())
+                .is_none_or(|decided_at| decided_at > cutoff)
+        });
+        if self.join_requests.len() != count_before {
+            self.notify(WsNotification::JoinRequestsUpdated);
+        }
+    }
+
+    /// Marks lapsed pending invites Expired and forgets settled invites
+    /// INVITE_RETENTION_DAYS after they expire.
+    fn sweep_invites(&mut self) {
+        let now = Utc::now();
+        let mut changed = false;
+        self.invites.retain(|_, invite| {
+            let Ok(expires_at) = chrono::DateTime::parse_from_rfc3339(&invite.expires_at) else {
+                return true;
+            };
+            if invite.status == InviteStatus::Pending && now > expires_at {
+                invite.status = InviteStatus::Expired;
+                changed = true;
+            }
+            let keep = invite.status == InviteStatus::Pending
+                || now < expires_at + chrono::Duration::days(INVITE_RETENTION_DAYS);
+            changed |= !keep;
+            keep
+        });
+        if changed {
+            self.notify(WsNotification::InvitesUpdated);
+        }
+    }
+
+    fn notify(&self, notification: WsNotification) {
+        let bytes = match serde_json::to_vec(&notification) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("Failed to serialize notification: {}", e);
+                return;
+            }
+        };
+
+        for (channel_id, subscriptions) in &self.ws_subscriptions {
+            if notification.matches(subscriptions) {
+                send_ws_push(
+                    *channel_id,
+                    WsMessageType::Text,
+                    LazyLoadBlob::new(Some("application/json"), bytes.clone()),
+                );
+            }
+        }
+    }
+
+    async fn send_wiki_message(&self, node_id: &str, message: &WikiMessage) -> Result<WikiResponse, String> {
+        let target_address = Address::new(node_id, WIKI_PROCESS_ID);
+        let message_body = serde_json::to_string(message)
+            .map_err(|e| format!("Failed to serialize message: {}", e))?
+            .into_bytes();
+
+        match caller_utils::wiki::handle_wiki_message_remote_rpc(&target_address, message_body).await {
+            Ok(Ok(response_bytes)) => {
+                let response_str = String::from_utf8(response_bytes)
+                    .map_err(|e| format!("Failed to convert response to string: {}", e))?;
+                match serde_json::from_str::<WikiResponse>(&response_str) {
+                    // Callers only tell refusals apart by their reason
+                    Ok(WikiResponse::PermissionDenied(err)) => Ok(WikiResponse::Error(err.reason)),
+                    response => response.map_err(|e| format!("Failed to parse response: {}", e)),
+                }
+            }
+            Ok(Err(err)) => Err(format!("Remote node returned error: {}", err)),
+            Err(e) => Err(format!("Failed to contact remote node: {:?}", e)),
+        }
+    }
+
+    /// Sends a read to a remote wiki ("wiki_id@node_id"), failing over to the
+    /// wiki's replica nodes (as of our last copy of the wiki) if the host is down.
+    async fn send_wiki_read(&self, remote_wiki_id: &str, message: &WikiMessage) -> Result<WikiResponse, String> {
+        let (_, node_id) = remote_wiki_id.split_once('@')
+            .ok_or_else(|| "Invalid remote wiki ID".to_string())?;
+        let err = match self.send_wiki_message(node_id, message).await {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+
+        let replica_nodes = self.replicas.get(remote_wiki_id)
+            .and_then(|replica| replica.wiki.as_ref())
+            .map(|wiki| wiki.replica_nodes.clone())
+            .unwrap_or_default();
+        for replica_node in replica_nodes.iter().filter(|node| **node != self.node_id) {
+            if let Ok(response) = self.send_wiki_message(replica_node, message).await {
+                return Ok(response);
+            }
+        }
+
+        Err(err)
+    }
+
+    /// Queues a replication message for each of a wiki's replica nodes.
+    /// Nothing is sent until `flush_replication`.
+    fn replicate(&mut self, wiki_id: &str, message: WikiMessage) {
+        let Some(wiki) = self.wikis.get(wiki_id) else { return };
+        if wiki.replica_of.is_some() {
+            return;
+        }
+        for node_id in &wiki.replica_nodes {
+            self.replication_outbox.entry(format!("{}@{}", wiki_id, node_id))
+                .or_default()
+                .push(message.clone());
+        }
+    }
+
+    fn replicate_wiki(&mut self, wiki_id: &str) {
+        if let Some(wiki) = self.wikis.get(wiki_id).cloned() {
+            self.replicate(wiki_id, WikiMessage::ReplicateMembers { wiki });
+        }
+    }
+
+    fn replicate_page(&mut self, wiki_id: &str, path: &str, previous_path: Option<&str>, update: Vec<u8>) {
+        let Some(page) = self.pages.get(&format!("{}:{}", wiki_id, path)) else { return };
+        let message = WikiMessage::ReplicatePage {
+            wiki_id: wiki_id.to_string(),
+            path: path.to_string(),
+            previous_path: previous_path.map(str::to_string),
+            update,
+            version: page.current_version.clone(),
+        };
+        self.replicate(wiki_id, message);
+    }
+
+    /// Replaces anything queued for a replica with a full copy of the wiki.
+    fn resync_replica(&mut self, wiki_id: &str, node_id: &str) {
+        let Some(WikiSnapshot { wiki, pages, histories, deleted_pages }) = self.wiki_snapshot(wiki_id) else { return };
+        let message = WikiMessage::ReplicateWiki { wiki, pages, histories, deleted_pages };
+        self.replication_outbox.insert(format!("{}@{}", wiki_id, node_id), vec![message]);
+    }
+
+    /// A full copy of a wiki we hold, with its pages, histories and deleted pages.
+    fn wiki_snapshot(&self, wiki_id: &str) -> Option<WikiSnapshot> {
+        let wiki = self.wikis.get(wiki_id)?.clone();
+        let prefix = format!("{}:", wiki_id);
+        let pages = self.pages.iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, page)| page.clone())
+            .collect();
+        let histories = self.page_histories.iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, history)| history.clone())
+            .collect();
+        let deleted_pages = self.deleted_pages.iter()
+            .filter(|(_, deleted_page)| deleted_page.wiki_id == wiki_id)
+            .map(|(key, deleted_page)| (key.clone(), deleted_page.clone()))
+            .collect();
+        Some(WikiSnapshot { wiki, pages, histories, deleted_pages })
+    }
+
+    /// Stores a wiki from a snapshot, with its pages under our own keys.
+    fn install_snapshot(&mut self, snapshot: WikiSnapshot) {
+        let WikiSnapshot { wiki, pages, histories, deleted_pages } = snapshot;
+        let wiki_id = wiki.id.clone();
+        for page in pages {
+            self.pages.insert(format!("{}:{}", wiki_id, page.path), page);
+        }
+        for history in histories {
+            self.page_histories.insert(format!("{}:{}", wiki_id, history.path), history);
+        }
+        for (deleted_key, deleted_page) in deleted_pages {
+            if deleted_page.wiki_id == wiki_id {
+                self.deleted_pages.insert(Self::deleted_page_key(&deleted_key, &deleted_page), deleted_page);
+            }
+        }
+        self.wikis.insert(wiki_id, wiki);
+    }
+
+    /// Our key for a deleted page the host sent as `deleted_key`: rebuilt from
+    /// the page itself, keeping only the host's deletion timestamp.
+    fn deleted_page_key(deleted_key: &str, deleted_page: &DeletedPage) -> String {
+        let deleted_timestamp = deleted_key.rsplit_once(':')
+            .and_then(|(_, timestamp)| timestamp.parse::<i64>().ok())
+            .or_else(|| chrono::DateTime::parse_from_rfc3339(&deleted_page.deleted_at).ok().map(|at| at.timestamp()))
+            .unwrap_or_default();
+        format!("{}:{}:{}", deleted_page.wiki_id, deleted_page.path, deleted_timestamp)
+    }
+
+    /// Hands a wiki we host to `new_owner`, tells its members and replicas
+    /// where it went, and answers with the copy the new owner starts from.
+    async fn move_wiki_to(&mut self, wiki_id: &str, new_owner: &str) -> WikiResponse {
+        let Some((snapshot, watchers)) = self.hand_over_wiki(wiki_id, new_owner) else {
+            return WikiResponse::Error("Wiki not found".to_string());
+        };
+        println!("Ownership of wiki {} transferred to {}", wiki_id, new_owner);
+
+        let message = WikiMessage::WikiMoved {
+            wiki_id: wiki_id.to_string(),
+            host_id: self.node_id.clone(),
+            new_host_id: new_owner.to_string(),
+        };
+        let mut targets: Vec<&String> = snapshot.wiki.members.keys()
+            .chain(&snapshot.wiki.replica_nodes)
+            .filter(|node_id| **node_id != self.node_id && *node_id != new_owner)
+            .collect();
+        targets.sort();
+        targets.dedup();
+        for node_id in targets {
+            let _ = self.send_wiki_message(node_id, &message).await;
+        }
+
+        self.notify(WsNotification::WikiListUpdated);
+        WikiResponse::WikiHandedOver { snapshot, watchers }
+    }
+
+    /// Makes `new_owner` the owner and host of a wiki we host. Our copy turns
+    /// into a replica of theirs and we stay on as an Admin. Returns the copy
+    /// they start from and the wiki's watchers, who move with it.
+    fn hand_over_wiki(&mut self, wiki_id: &str, new_owner: &str) -> Option<(WikiSnapshot, Vec<PageWatcher>)> {
+        let wiki = self.wikis.get_mut(wiki_id).filter(|wiki| wiki.replica_of.is_none())?;
+        wiki.members.insert(self.node_id.clone(), WikiRole::Admin);
+        wiki.members.insert(new_owner.to_string(), WikiRole::SuperAdmin);
+        wiki.created_by = new_owner.to_string();
+        wiki.pending_transfer = None;
+        wiki.replica_nodes.retain(|node_id| node_id != new_owner);
+        if !wiki.replica_nodes.contains(&self.node_id) {
+            wiki.replica_nodes.push(self.node_id.clone());
+        }
+        let snapshot = self.wiki_snapshot(wiki_id)?;
+
+        if let Some(wiki) = self.wikis.get_mut(wiki_id) {
+            wiki.replica_of = Some(new_owner.to_string());
+        }
+        // Replication is the new host's job now
+        let outbox_prefix = format!("{}@", wiki_id);
+        self.replication_outbox.retain(|target, _| !target.starts_with(&outbox_prefix));
+        let watchers = self.watchers.remove(wiki_id).unwrap_or_default();
+
+        self.my_memberships.retain(|m| m.wiki_id != wiki_id);
+        self.my_memberships.push(WikiMembership {
+            wiki_id: format!("{}@{}", wiki_id, new_owner),
+            role: WikiRole::Admin,
+            joined_at: Utc::now().to_rfc3339(),
+        });
+        Some((snapshot, watchers))
+    }
+
+    /// Starts hosting a wiki its previous host `from_id` handed to us.
+    fn take_over_wiki(&mut self, from_id: &str, mut snapshot: WikiSnapshot, watchers: Vec<PageWatcher>) {
+        let wiki_id = snapshot.wiki.id.clone();
+        let remote_wiki_id = format!("{}@{}", wiki_id, from_id);
+        for watch in self.watches.iter_mut().filter(|watch| watch.wiki_id == remote_wiki_id) {
+            watch.wiki_id = wiki_id.clone();
+        }
+        self.forget_remote_wiki(&remote_wiki_id);
+        self.drop_wiki_copy(&wiki_id);
+
+        snapshot.wiki.replica_of = None;
+        self.install_snapshot(snapshot);
+        let watchers: Vec<PageWatcher> = watchers.into_iter()
+            .filter(|watcher| watcher.node_id != self.node_id)
+            .collect();
+        if !watchers.is_empty() {
+            self.watchers.insert(wiki_id.clone(), watchers);
+        }
+        self.my_memberships.retain(|m| m.wiki_id != wiki_id);
+        self.my_memberships.push(WikiMembership {
+            wiki_id: wiki_id.clone(),
+            role: WikiRole::SuperAdmin,
+            joined_at: Utc::now().to_rfc3339(),
+        });
+
+        // Our replicas, the previous host among them, start from our copy
+        self.resync_replicas(&wiki_id);
+    }
+
+    /// Points what we hold for a wiki at its new host: our membership, queued
+    /// edits and watches, and our replica copy if we keep one.
+    fn follow_moved_wiki(&mut self, wiki_id: &str, host_id: &str, new_host_id: &str) {
+        if let Some(wiki) = self.wikis.get_mut(wiki_id).filter(|wiki| wiki.replica_of.as_deref() == Some(host_id)) {
+            wiki.replica_of = Some(new_host_id.to_string());
+        }
+        if new_host_id == self.node_id {
+            return;
+        }
+
+        let remote_wiki_id = format!("{}@{}", wiki_id, host_id);
+        let new_remote_wiki_id = format!("{}@{}", wiki_id, new_host_id);
+        let Some(membership) = self.my_memberships.iter_mut().find(|m| m.wiki_id == remote_wiki_id) else { return };
+        membership.wiki_id = new_remote_wiki_id.clone();
+        if let Some(mut replica) = self.replicas.remove(&remote_wiki_id) {
+            replica.wiki = None;
+            self.replicas.insert(new_remote_wiki_id.clone(), replica);
+        }
+        for watch in self.watches.iter_mut().filter(|watch| watch.wiki_id == remote_wiki_id) {
+            watch.wiki_id = new_remote_wiki_id.clone();
+        }
+        self.drop_wiki_copy(&remote_wiki_id);
+    }
+
+    fn resync_replicas(&mut self, wiki_id: &str) {
+        let replica_nodes = self.wikis.get(wiki_id)
+            .map(|wiki| wiki.replica_nodes.clone())
+            .unwrap_or_default();
+        for node_id in replica_nodes {
+            self.resync_replica(wiki_id, &node_id);
+        }
+    }
+
+    /// Delivers queued replication, in order per replica. An unreachable replica
+    /// keeps its queue for the next flush; one that rejects an update has fallen
+    /// out of step and gets a full copy instead.
+    async fn flush_replication(&mut self) {
+        let targets: Vec<String> = self.replication_outbox.keys().cloned().collect();
+        for target in targets {
+            let Some((wiki_id, node_id)) = target.split_once('@') else { continue };
+
+            while let Some(message) = self.replication_outbox.get(&target).and_then(|queue| queue.first().cloned()) {
+                let rejected = match self.send_wiki_message(node_id, &message).await {
+                    Ok(WikiResponse::Error(err)) => Some(err),
+                    Ok(_) => None,
+                    Err(_) => break,
+                };
+
+                if let Some(queue) = self.replication_outbox.get_mut(&target) {
+                    queue.remove(0);
+                }
+                if let Some(err) = rejected {
+                    println!("Replica {} rejected update for wiki {}: {}", node_id, wiki_id, err);
+                    let is_member = self.wikis.get(wiki_id)
+                        .is_some_and(|wiki| wiki.replica_nodes.iter().any(|n| n == node_id));
+                    if is_member && !matches!(message, WikiMessage::ReplicateWiki { .. }) {
+                        self.resync_replica(wiki_id, node_id);
+                    }
+                }
+            }
+
+            if self.replication_outbox.get(&target).is_some_and(|queue| queue.is_empty()) {
+                self.replication_outbox.remove(&target);
+            }
+        }
+
+        // Watch notices go out with replication; watchers that stopped watching are dropped
+        let watchers: Vec<String> = self.watch_outbox.keys().cloned().collect();
+        for node_id in watchers {
+            while let Some(message) = self.watch_outbox.get(&node_id).and_then(|queue| queue.first().cloned()) {
+                match self.send_wiki_message(&node_id, &message).await {
+                    Err(_) => break,
+                    Ok(WikiResponse::Error(_)) => {
+                        if let WikiMessage::PageChanged { wiki_id, .. } = &message {
+                            if let Some(watchers) = self.watchers.get_mut(wiki_id) {
+                                watchers.retain(|watcher| watcher.node_id != node_id);
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                }
+                if let Some(queue) = self.watch_outbox.get_mut(&node_id) {
+                    queue.remove(0);
+                }
+            }
+
+            if self.watch_outbox.get(&node_id).is_some_and(|queue| queue.is_empty()) {
+                self.watch_outbox.remove(&node_id);
+            }
+        }
+    }
+
+    /// Access control for the remote API: checks a message's sender against the
+    /// wiki it targets before any handler runs. Messages for wikis we don't hold
+    /// pass, so their handlers can answer "Wiki not found".
+    fn authorize(&self, message: &WikiMessage, caller: &str) -> Result<(), PermissionError> {
+        let deny = |wiki_id: &str, required_role: Option<WikiRole>, reason: &str| PermissionError {
+            wiki_id: wiki_id.to_string(),
+            node_id: caller.to_string(),
+            required_role,
+            reason: reason.to_string(),
+        };
+
+        match message.required_access() {
+            RemoteAccess::Anyone => Ok(()),
+            RemoteAccess::Reader(wiki_id) => match self.wikis.get(wiki_id) {
+                Some(wiki) if !Self::can_read(wiki, caller) => {
+                    Err(deny(wiki_id, Some(WikiRole::Reader), "Not a member of this wiki"))
+                }
+                _ => Ok(()),
+            },
+            RemoteAccess::Page(wiki_id, path, required) => match self.wikis.get(wiki_id) {
+                Some(wiki) => wiki.check_page_access(caller, path, required.clone())
+                    .map_err(|reason| deny(wiki_id, Some(required), &reason)),
+                None => Ok(()),
+            },
+            RemoteAccess::Admin(wiki_id) => match self.wikis.get(wiki_id) {
+                Some(wiki) if !wiki.members.get(caller).is_some_and(|role| role.includes(&WikiRole::Admin)) => {
+                    Err(deny(wiki_id, Some(WikiRole::Admin), "Insufficient permissions"))
+                }
+                _ => Ok(()),
+            },
+            RemoteAccess::WikiHost(wiki_id) => {
+                let membership_id = format!("{}@{}", wiki_id, caller);
+                if self.my_memberships.iter().any(|m| m.wiki_id == membership_id) {
+                    Ok(())
+                } else {
+                    Err(deny(wiki_id, None, "Only the wiki's host can send this"))
+                }
+            }
+            RemoteAccess::ReplicaHost(wiki_id) => match self.wikis.get(wiki_id) {
+                Some(wiki) if wiki.replica_of.as_deref() == Some(caller) => Ok(()),
+                // The host answers this by sending a snapshot
+                _ => Err(deny(wiki_id, None, "Not a replica of this wiki")),
+            },
+            RemoteAccess::NewReplica(wiki) => {
+                if wiki.replica_nodes.contains(&self.node_id) && self.accepts_replication(&wiki.id, caller) {
+                    Ok(())
+                } else {
+                    Err(deny(&wiki.id, None, "Not a replica of this wiki"))
+                }
+            }
+        }
+    }
+
+    /// Private wikis are readable by members only.
+    fn can_read(wiki: &Wiki, node_id: &str) -> bool {
+        wiki.is_public || wiki.members.contains_key(node_id)
+    }
+
+    /// Whether `sender` may push a full copy of `wiki_id`: the node hosting our
+    /// current copy, or anyone if we don't have one yet.
+    fn accepts_replication(&self, wiki_id: &str, sender: &str) -> bool {
+        match self.wikis.get(wiki_id) {
+            Some(wiki) => wiki.replica_of.as_deref() == Some(sender),
+            None => true,
+        }
+    }
+
+    /// Removes a replica copy of a wiki along with its pages.
+    fn drop_wiki_copy(&mut self, wiki_id: &str) {
+        self.wikis.remove(wiki_id);
+        let prefix = format!("{}:", wiki_id);
+        self.pages.retain(|key, _| !key.starts_with(&prefix));
+        self.page_histories.retain(|key, _| !key.starts_with(&prefix));
+        self.active_docs.retain(|key, _| !key.starts_with(&prefix));
+        self.deleted_pages.retain(|_, deleted_page| deleted_page.wiki_id != wiki_id);
+    }
+
+    /// Applies a page update pushed by a wiki's host to our replica copy,
+    /// following the host's rename if there was one.
+    fn apply_replicated_page(
+        &mut self,
+        wiki_id: &str,
+        path: &str,
+        previous_path: Option<&str>,
+        update: &[u8],
+        version: PageVersion,
+    ) -> Result<(), String> {
+        let old_page_key = format!("{}:{}", wiki_id, previous_path.unwrap_or(path));
+        let page_key = format!("{}:{}", wiki_id, path);
+
+        let doc = self.page_doc(&old_page_key).unwrap_or_default();
+        let decoded = yrs::Update::decode_v1(update)
+            .map_err(|e| format!("Failed to decode update: {}", e))?;
+        doc.transact_mut().apply_update(decoded)
+            .map_err(|e| format!("Failed to apply update: {}", e))?;
+
+        let (protected, lock) = self.pages.remove(&old_page_key)
+            .map(|page| (page.protected, page.lock))
+            .unwrap_or_default();
+        self.active_docs.remove(&old_page_key);
+        let mut history = self.page_histories.remove(&old_page_key)
+            .unwrap_or_else(|| PageHistory {
+                path: path.to_string(),
+                wiki_id: wiki_id.to_string(),
+                versions: Vec::new(),
+                current_version_id: String::new(),
+                head_state_vector: Vec::new(),
+            });
+        history.path = path.to_string();
+        history.current_version_id = version.version_id.clone();
+        history.versions.push(version.clone());
+        history.head_state_vector = doc.transact().state_vector().encode_v1();
+
+        let yrs_doc = doc.transact().encode_state_as_update_v1(&yrs::StateVector::default());
+        self.pages.insert(page_key.clone(), WikiPage {
+            path: path.to_string(),
+            wiki_id: wiki_id.to_string(),
+            current_version: version,
+            yrs_doc,
+            protected,
+            lock,
+        });
+        self.page_histories.insert(page_key.clone(), history);
+        self.active_docs.insert(page_key, doc);
+
+        Ok(())
+    }
+
+    /// Returns our replica doc for a page of a remote wiki ("wiki_id@node_id"),
+    /// loading it from the stored state if needed.
+    fn replica_doc(&mut self, remote_wiki_id: &str, path: &str) -> Doc {
+        let page_key = format!("{}:{}", remote_wiki_id, path);
+        if let Some(doc) = self.active_docs.get(&page_key) {
+            return doc.clone();
+        }
+
+        let doc = Doc::new();
+        if let Some(page) = self.replicas.get(remote_wiki_id).and_then(|r| r.pages.get(path)) {
+            if let Ok(update) = yrs::Update::decode_v1(&page.yrs_doc) {
+                let _ = doc.transact_mut().apply_update(update);
+            }
+        }
+        self.active_docs.insert(page_key, doc.clone());
+        doc
+    }
+
+    fn store_replica_doc(&mut self, remote_wiki_id: &str, path: &str, doc: &Doc) {
+        let yrs_doc = doc.transact().encode_state_as_update_v1(&yrs::StateVector::default());
+        self.replicas.entry(remote_wiki_id.to_string()).or_default()
+            .pages.entry(path.to_string()).or_default()
+            .yrs_doc = yrs_doc;
+    }
+
+    fn drop_replica_page(&mut self, remote_wiki_id: &str, path: &str) {
+        if let Some(replica) = self.replicas.get_mut(remote_wiki_id) {
+            replica.pages.remove(path);
+        }
+        self.active_docs.remove(&format!("{}:{}", remote_wiki_id, path));
+    }
+
+    fn replica_page_info(&mut self, remote_wiki_id: &str, path: &str) -> Option<PageInfo> {
+        let page = self.replicas.get(remote_wiki_id)?.pages.get(path)?.clone();
+        let doc = self.replica_doc(remote_wiki_id, path);
+        let text = doc.get_or_insert_text("content");
+        let content = text.get_string(&doc.transact());
+        let base = self.record_edit_base(remote_wiki_id, path, &doc);
+
+        Some(PageInfo {
+            path: path.to_string(),
+            wiki_id: remote_wiki_id.to_string(),
+            content,
+            updated_by: page.updated_by,
+            updated_at: page.updated_at,
+            protected: page.protected,
+            lock: page.lock.filter(PageLock::is_active),
+            base: Some(base),
+        })
+    }
+
+    /// Remembers `doc`'s current state as one an edit of the page may start
+    /// from, and returns the key the editor should send back with it.
+    fn record_edit_base(&mut self, remote_wiki_id: &str, path: &str, doc: &Doc) -> String {
+        let txn = doc.transact();
+        let state_vector = BASE64.encode(txn.state_vector().encode_v1());
+        let yrs_doc = txn.encode_state_as_update_v1(&yrs::StateVector::default());
+
+        if let Some(page) = self.replicas.get_mut(remote_wiki_id).and_then(|r| r.pages.get_mut(path)) {
+            page.edit_bases.retain(|base| base.state_vector != state_vector);
+            page.edit_bases.push(EditBase { state_vector: state_vector.clone(), yrs_doc });
+            let excess = page.edit_bases.len().saturating_sub(EDIT_BASE_LIMIT);
+            page.edit_bases.drain(..excess);
+        }
+        state_vector
+    }
+
+    /// Takes the base an edit of the page started from: the one named, or the
+    /// last one served. None if it's no longer kept.
+    fn take_edit_base(&mut self, remote_wiki_id: &str, path: &str, base: Option<&str>) -> Option<Doc> {
+        let page = self.replicas.get_mut(remote_wiki_id)?.pages.get_mut(path)?;
+        let index = match base {
+            Some(base) => page.edit_bases.iter().position(|edit_base| edit_base.state_vector == base)?,
+            None => page.edit_bases.len().checked_sub(1)?,
+        };
+        let edit_base = page.edit_bases.remove(index);
+
+        let doc = Doc::new();
+        let update = yrs::Update::decode_v1(&edit_base.yrs_doc).ok()?;
+        doc.transact_mut().apply_update(update).ok()?;
+        Some(doc)
+    }
+
+    /// Pulls the host's changes to a page into our replica via state-vector sync.
+    /// The outer error means the host couldn't be reached; the inner one that it
+    /// refused the sync.
+    async fn pull_replica_page(&mut self, remote_wiki_id: &str, path: &str) -> Result<Result<(), SyncRefusal>, String> {
+        let Some((wiki_id, _)) = remote_wiki_id.split_once('@') else {
+            return Ok(Err(SyncRefusal::Rejected("Invalid remote wiki ID".to_string())));
+        };
+        let doc = self.replica_doc(remote_wiki_id, path);
+        let message = WikiMessage::SyncPage {
+            wiki_id: wiki_id.to_string(),
+            path: path.to_string(),
+            state_vector: doc.transact().state_vector().encode_v1(),
+        };
+
+        match self.send_wiki_read(remote_wiki_id, &message).await? {
+            WikiResponse::PageSync { update, .. } => {
+                let applied = yrs::Update::decode_v1(&update)
+                    .map_err(|e| format!("Failed to decode update: {}", e))
+                    .and_then(|decoded| {
+                        doc.transact_mut().apply_update(decoded)
+                            .map_err(|e| format!("Failed to apply update: {}", e))
+                    });
+                if applied.is_ok() {
+                    self.store_replica_doc(remote_wiki_id, path, &doc);
+                }
+                Ok(applied.map_err(SyncRefusal::Rejected))
+            }
+            WikiResponse::UnsupportedMessage(_) => Ok(Err(SyncRefusal::Unsupported)),
+            WikiResponse::Error(err) => Ok(Err(SyncRefusal::Rejected(err))),
+            _ => Ok(Err(SyncRefusal::Rejected("Unexpected response from remote node".to_string()))),
+        }
+    }
+
+    /// Applies an edit to our replica of a remote page and queues it for the host
+    /// as a yrs update. The update is the change from `base`, the state the user
+    /// read the page at, so anything merged in since survives on both sides.
+    /// Returns the page's path after the edit, which follows the title the same
+    /// way the host's `write_page` does.
+    fn queue_replica_edit(
+        &mut self,
+        remote_wiki_id: &str,
+        path: &str,
+        content: &str,
+        commit_message: Option<String>,
+        base: Option<&str>,
+    ) -> String {
+        let doc = self.replica_doc(remote_wiki_id, path);
+        let update = match self.take_edit_base(remote_wiki_id, path, base) {
+            Some(base_doc) => {
+                let state_before = base_doc.transact().state_vector();
+                Self::apply_text_change(&base_doc, content);
+                let update = base_doc.transact().encode_diff_v1(&state_before);
+                if let Ok(decoded) = yrs::Update::decode_v1(&update) {
+                    let _ = doc.transact_mut().apply_update(decoded);
                 }
+                // What the user now sees, should they edit again without reloading
+                self.record_edit_base(remote_wiki_id, path, &base_doc);
+                update
+            }
+            None => {
+                let state_before = doc.transact().state_vector();
+                Self::apply_text_change(&doc, content);
+                doc.transact().encode_diff_v1(&state_before)
+            }
+        };
+        self.store_replica_doc(remote_wiki_id, path, &doc);
+
+        let now = Utc::now().to_rfc3339();
+        let new_title = Self::extract_title_from_markdown(content);
+        let replica = self.replicas.entry(remote_wiki_id.to_string()).or_default();
+        replica.pending_edits.push(PendingEdit {
+            path: path.to_string(),
+            update,
+            commit_message,
+            queued_at: now.clone(),
+        });
+
+        if let Some(mut page) = replica.pages.remove(path) {
+            page.updated_by = self.node_id.clone();
+            page.updated_at = now.clone();
+            replica.pages.insert(new_title.clone(), page);
+        }
+        if let Some(summary) = replica.page_list.iter_mut().find(|summary| summary.path == path) {
+            summary.path = new_title.clone();
+            summary.updated_by = self.node_id.clone();
+            summary.updated_at = now;
+        }
+        if new_title != path {
+            if let Some(doc) = self.active_docs.remove(&format!("{}:{}", remote_wiki_id, path)) {
+                self.active_docs.insert(format!("{}:{}", remote_wiki_id, new_title), doc);
             }
         }
 
-        Ok(serde_json::to_string(&all_results).unwrap())
+        new_title
     }
 
-}
+    /// Sends queued edits for a remote wiki to its host, oldest first, and returns
+    /// the host's reasons for any it rejected. Later edits to a rejected page build
+    /// on the rejected one, so they're dropped with it and the page is re-pulled
+    /// on the next read. The error means the host couldn't be reached.
+    async fn flush_pending_edits(&mut self, remote_wiki_id: &str) -> Result<Vec<String>, String> {
+        let Some((wiki_id, node_id)) = remote_wiki_id.split_once('@') else {
+            return Ok(Vec::new());
+        };
+        let mut rejected = Vec::new();
+
+        while let Some(edit) = self.replicas.get(remote_wiki_id).and_then(|r| r.pending_edits.first().cloned()) {
+            let message = WikiMessage::ApplyPageUpdate {
+                wiki_id: wiki_id.to_string(),
+                path: edit.path.clone(),
+                update: edit.update,
+                user_id: self.node_id.clone(),
+                commit_message: edit.commit_message,
+            };
+            let response = self.send_wiki_message(node_id, &message).await?;
+
+            let Some(replica) = self.replicas.get_mut(remote_wiki_id) else { break };
+            replica.pending_edits.remove(0);
+            let err = match response {
+                WikiResponse::Success(true) => continue,
+                WikiResponse::Error(err) => err,
+                _ => "Unexpected response from remote node".to_string(),
+            };
+            replica.pending_edits.retain(|pending| pending.path != edit.path);
+            self.drop_replica_page(remote_wiki_id, &edit.path);
+            rejected.push(err);
+        }
 
-impl WikiState {
-    fn extract_title_from_markdown(content: &str) -> String {
-        // Get the first non-empty line from the content
-        if let Some(first_line) = content.lines().find(|line| !line.trim().is_empty()) {
-            let trimmed = first_line.trim();
-            // Remove markdown heading syntax if present
-            if let Some(title) = trimmed.strip_prefix('#') {
-                title.trim().to_string()
-            } else {
-                trimmed.to_string()
+        Ok(rejected)
+    }
+
+    /// Brings a remote wiki's replica back in line once its host is reachable:
+    /// queued offline edits are pushed, then every replicated page is re-pulled.
+    async fn reconcile_replica(&mut self, remote_wiki_id: &str) {
+        let has_pending = self.replicas.get(remote_wiki_id)
+            .is_some_and(|replica| !replica.pending_edits.is_empty());
+        if !has_pending {
+            return;
+        }
+
+        match self.flush_pending_edits(remote_wiki_id).await {
+            Ok(rejected) => {
+                for err in rejected {
+                    println!("Host rejected a queued edit to {}: {}", remote_wiki_id, err);
+                }
+            }
+            Err(_) => return,
+        }
+
+        let paths: Vec<String> = self.replicas.get(remote_wiki_id)
+            .map(|replica| replica.pages.keys().cloned().collect())
+            .unwrap_or_default();
+        for path in paths {
+            if let Ok(Err(_)) = self.pull_replica_page(remote_wiki_id, &path).await {
+                // Deleted or no longer readable on the host
+                self.drop_replica_page(remote_wiki_id, &path);
             }
-        } else {
-            "Untitled".to_string()
         }
+
+        self.notify(WsNotification::PageListUpdated { wiki_id: remote_wiki_id.to_string() });
     }
 
     async fn get_remote_wiki_data(&self, wiki_id: &str, node_id: &str) -> Result<Wiki, String> {
@@ -3298,29 +7787,138 @@ impl WikiState {
         }
     }
 
-    fn check_permission(&self, wiki_id: &str, required_role: WikiRole) -> Result<(), String> {
-        // For remote wikis (format: wiki_id@node_id), check our membership
-        if wiki_id.contains('@') {
-            // For remote wikis, check if we have a membership
-            let has_membership = self.my_memberships.iter()
-                .any(|m| m.wiki_id == wiki_id || m.wiki_id.starts_with(&format!("{}@", wiki_id)));
+    /// Checks `node_id` may change a page's content, given its protection and edit lock.
+    fn check_page_editable(&self, wiki_id: &str, path: &str, node_id: &str) -> Result<(), String> {
+        let (Some(wiki), Some(page)) = (self.wikis.get(wiki_id), self.pages.get(&format!("{}:{}", wiki_id, path))) else {
+            return Ok(());
+        };
+        if page.protected && wiki.check_page_access(node_id, path, WikiRole::Admin).is_err() {
+            return Err("Page is protected; only admins can edit it".to_string());
+        }
+        match page.lock.as_ref().filter(|lock| lock.is_active()) {
+            Some(lock) if lock.node_id != node_id => {
+                Err(format!("Page is locked by {} until {}", lock.node_id, lock.expires_at))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks new content wouldn't retitle a page onto another existing page.
+    fn check_retitle(&self, wiki_id: &str, path: &str, content: &str) -> Result<(), String> {
+        let new_title = Self::extract_title_from_markdown(content);
+        if new_title != path && self.pages.contains_key(&format!("{}:{}", wiki_id, new_title)) {
+            return Err(format!("A page titled \"{}\" already exists", new_title));
+        }
+        Ok(())
+    }
+
+    /// Acquires (or extends), releases or breaks a page's edit lock for `node_id`.
+    fn update_page_lock(
+        &mut self,
+        wiki_id: &str,
+        path: &str,
+        node_id: &str,
+        action: &str,
+        duration_minutes: Option<i64>,
+    ) -> Result<(), String> {
+        let wiki = self.wikis.get(wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+        wiki.check_page_access(node_id, path, WikiRole::Writer)?;
+        let is_admin = wiki.check_page_access(node_id, path, WikiRole::Admin).is_ok();
 
-            if has_membership {
-                // For remote wikis, we assume reader permissions
-                // More sophisticated permission checks would require P2P communication
-                match required_role {
-                    WikiRole::Reader => return Ok(()),
-                    _ => return Err("Cannot modify remote wiki".to_string()),
+        let page = self.pages.get_mut(&format!("{}:{}", wiki_id, path))
+            .ok_or_else(|| "Page not found".to_string())?;
+        let current = page.lock.clone().filter(PageLock::is_active);
+
+        match action {
+            "acquire" => {
+                if page.protected && !is_admin {
+                    return Err("Page is protected; only admins can edit it".to_string());
                 }
-            } else {
-                return Err("Not a member of this wiki".to_string());
+                if let Some(lock) = current.filter(|lock| lock.node_id != node_id) {
+                    return Err(format!("Page is already locked by {} until {}", lock.node_id, lock.expires_at));
+                }
+                let minutes = duration_minutes.unwrap_or(DEFAULT_LOCK_MINUTES).clamp(1, MAX_LOCK_MINUTES);
+                let now = Utc::now();
+                page.lock = Some(PageLock {
+                    node_id: node_id.to_string(),
+                    acquired_at: now.to_rfc3339(),
+                    expires_at: (now + chrono::Duration::minutes(minutes)).to_rfc3339(),
+                });
+            }
+            "release" => {
+                if current.is_some_and(|lock| lock.node_id != node_id) {
+                    return Err("Page is locked by someone else; an admin can break the lock".to_string());
+                }
+                page.lock = None;
+            }
+            "break" => {
+                if !is_admin {
+                    return Err("Only admins can break page locks".to_string());
+                }
+                page.lock = None;
+            }
+            _ => return Err("Invalid action".to_string()),
+        }
+        Ok(())
+    }
+
+    fn set_page_protected(&mut self, wiki_id: &str, path: &str, node_id: &str, protected: bool) -> Result<(), String> {
+        let wiki = self.wikis.get(wiki_id)
+            .ok_or_else(|| "Wiki not found".to_string())?;
+        wiki.check_page_access(node_id, path, WikiRole::Admin)?;
+
+        let page = self.pages.get_mut(&format!("{}:{}", wiki_id, path))
+            .ok_or_else(|| "Page not found".to_string())?;
+        page.protected = protected;
+        Ok(())
+    }
+
+    /// Like `check_permission`, but for one page of the wiki, so its ACL applies.
+    fn check_page_permission(&self, wiki_id: &str, path: &str, required_role: WikiRole) -> Result<(), String> {
+        // Remote wikis enforce the ACL on their host; replica copies carry it
+        // and serve reads themselves
+        if wiki_id.contains('@') {
+            return self.check_permission(wiki_id, required_role);
+        }
+        match self.wikis.get(wiki_id) {
+            Some(wiki) if wiki.replica_of.is_some() && required_role != WikiRole::Reader => {
+                Err("Wiki is a read-only replica".to_string())
+            }
+            Some(wiki) => wiki.check_page_access(&self.node_id, path, required_role),
+            None => self.check_permission(wiki_id, required_role),
+        }
+    }
+
+    fn check_permission(&self, wiki_id: &str, required_role: WikiRole) -> Result<(), String> {
+        // For remote wikis (format: wiki_id@node_id), check the role the host last
+        // gave us; the host re-checks everything we send it
+        if wiki_id.contains('@') {
+            let role = self.my_memberships.iter()
+                .find(|m| m.wiki_id == wiki_id)
+                .map(|m| &m.role)
+                .ok_or_else(|| "Not a member of this wiki".to_string())?;
+
+            // Wiki administration only happens on the hosting node
+            if required_role.includes(&WikiRole::Admin) {
+                return Err("Remote wikis can only be administered on their host".to_string());
             }
+            return if role.includes(&required_role) {
+                Ok(())
+            } else {
+                Err("Insufficient permissions".to_string())
+            };
         }
 
         // Local wiki check
         let wiki = self.wikis.get(wiki_id)
             .ok_or_else(|| "Wiki not found".to_string())?;
 
+        // Replica copies are read-only
+        if wiki.replica_of.is_some() && required_role != WikiRole::Reader {
+            return Err("Wiki is a read-only replica".to_string());
+        }
+
         let user_role = wiki.members.get(&self.node_id)
             .ok_or_else(|| "Not a member of this wiki".to_string())?;
 
@@ -3350,3 +7948,921 @@ impl WikiState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc_with(content: &str) -> Doc {
+        let doc = Doc::new();
+        WikiState::apply_text_change(&doc, content);
+        doc
+    }
+
+    fn text_of(doc: &Doc) -> String {
+        doc.get_or_insert_text("content").get_string(&doc.transact())
+    }
+
+    fn full_update(doc: &Doc) -> Vec<u8> {
+        doc.transact().encode_state_as_update_v1(&yrs::StateVector::default())
+    }
+
+    fn apply(doc: &Doc, update: &[u8]) {
+        doc.transact_mut().apply_update(yrs::Update::decode_v1(update).unwrap()).unwrap();
+    }
+
+    const HOST: &str = "host.os";
+    const ADMIN: &str = "admin.os";
+    const WRITER: &str = "writer.os";
+    const READER: &str = "reader.os";
+    const STRANGER: &str = "stranger.os";
+
+    fn wiki(id: &str, is_public: bool) -> Wiki {
+        let members = [(HOST, WikiRole::SuperAdmin), (ADMIN, WikiRole::Admin), (WRITER, WikiRole::Writer), (READER, WikiRole::Reader)];
+        Wiki {
+            id: id.to_string(),
+            name: id.to_string(),
+            description: String::new(),
+            is_public,
+            created_by: HOST.to_string(),
+            created_at: Utc::now().to_rfc3339(),
+            members: members.into_iter().map(|(node_id, role)| (node_id.to_string(), role)).collect(),
+            replica_nodes: Vec::new(),
+            replica_of: None,
+            acl: Vec::new(),
+            join_codes: Vec::new(),
+            default_join_role: WikiRole::Reader,
+            pending_transfer: None,
+            retention: RetentionPolicy::default(),
+        }
+    }
+
+    /// A host holding a public and a private wiki.
+    fn host_state() -> WikiState {
+        let mut state = WikiState::new(HOST);
+        for wiki in [wiki("public", true), wiki("private", false)] {
+            state.wikis.insert(wiki.id.clone(), wiki);
+        }
+        state
+    }
+
+    #[test]
+    fn remote_edit_keeps_host_changes_made_after_it_was_loaded() {
+        let remote_wiki_id = "w@host.os";
+        let host = doc_with("# Page\nfirst\n");
+        let mut state = WikiState::new("me.os");
+        let page = ReplicaPage { yrs_doc: full_update(&host), ..ReplicaPage::default() };
+        state.replicas.entry(remote_wiki_id.to_string()).or_default()
+            .pages.insert("Page".to_string(), page);
+
+        let base = state.replica_page_info(remote_wiki_id, "Page").unwrap().base;
+
+        // Someone else edits on the host, and a pull brings it into our replica
+        let before = host.transact().state_vector();
+        WikiState::apply_text_change(&host, "# Page\nfirst\nsecond\n");
+        let host_edit = host.transact().encode_diff_v1(&before);
+        apply(&state.replica_doc(remote_wiki_id, "Page"), &host_edit);
+
+        // Our user submits the text they loaded, edited
+        state.queue_replica_edit(remote_wiki_id, "Page", "# Page\nfirst, edited\n", None, base.as_deref());
+
+        let queued = state.replicas[remote_wiki_id].pending_edits[0].update.clone();
+        apply(&host, &queued);
+        assert_eq!(text_of(&host), "# Page\nfirst, edited\nsecond\n");
+        assert_eq!(text_of(&state.replica_doc(remote_wiki_id, "Page")), text_of(&host));
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Access {
+        Anyone,
+        Reader,
+        PageReader,
+        PageWriter,
+        PageAdmin,
+        Admin,
+        WikiHost,
+        ReplicaHost,
+        NewReplica,
+    }
+
+    fn access_of(message: &WikiMessage) -> Access {
+        match message.required_access() {
+            RemoteAccess::Anyone => Access::Anyone,
+            RemoteAccess::Reader(_) => Access::Reader,
+            RemoteAccess::Page(_, _, WikiRole::Reader) => Access::PageReader,
+            RemoteAccess::Page(_, _, WikiRole::Writer) => Access::PageWriter,
+            RemoteAccess::Page(_, _, _) => Access::PageAdmin,
+            RemoteAccess::Admin(_) => Access::Admin,
+            RemoteAccess::WikiHost(_) => Access::WikiHost,
+            RemoteAccess::ReplicaHost(_) => Access::ReplicaHost,
+            RemoteAccess::NewReplica(_) => Access::NewReplica,
+        }
+    }
+
+    fn message(value: serde_json::Value) -> WikiMessage {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// One of every message about `wiki_id`, sent by `caller`, with the access it
+    /// needs and whether it claims to act for `caller`.
+    fn every_message(state: &WikiState, wiki_id: &str, caller: &str) -> Vec<(WikiMessage, Access, bool)> {
+        let wiki = state.wikis[wiki_id].clone();
+        let now = Utc::now().to_rfc3339();
+        let version = serde_json::json!({
+            "version_id": "v1", "content": [], "updated_by": caller, "updated_at": now, "commit_message": null,
+        });
+        let join_request = serde_json::json!({
+            "id": "r1", "wiki_id": wiki_id, "wiki_name": wiki_id, "host_id": caller, "requester_id": caller,
+            "message": null, "created_at": now, "status": "Pending", "role": null, "decided_by": null, "decided_at": null,
+        });
+        let deleted_page = serde_json::json!({
+            "path": "Page", "wiki_id": wiki_id, "deleted_at": now, "deleted_by": caller,
+            "history": { "path": "Page", "wiki_id": wiki_id, "versions": [], "current_version_id": "" },
+        });
+
+        vec![
+            (message(serde_json::json!({ "FindWikisByUser": { "username": caller } })), Access::Anyone, false),
+            (message(serde_json::json!({ "GetPublicWiki": { "wiki_id": wiki_id } })), Access::Anyone, false),
+            (message(serde_json::json!({ "JoinPublicWiki": { "wiki_id": wiki_id, "user_id": caller } })), Access::Anyone, true),
+            (message(serde_json::json!({ "JoinWithCode": { "wiki_id": wiki_id, "user_id": caller, "join_code": "code" } })), Access::Anyone, true),
+            (message(serde_json::json!({ "LeaveWiki": { "wiki_id": wiki_id, "user_id": caller } })), Access::Anyone, true),
+            (message(serde_json::json!({ "GetWikiData": { "wiki_id": wiki_id } })), Access::Reader, false),
+            (message(serde_json::json!({ "GetWikiPages": { "wiki_id": wiki_id } })), Access::Reader, false),
+            (message(serde_json::json!({ "GetWikiPage": { "wiki_id": wiki_id, "path": "Page" } })), Access::PageReader, false),
+            (message(serde_json::json!({ "CreatePage": { "wiki_id": wiki_id, "path": "Page", "initial_content": "", "user_id": caller, "commit_message": null } })), Access::PageWriter, true),
+            (message(serde_json::json!({ "UpdatePage": { "wiki_id": wiki_id, "path": "Page", "content": "", "user_id": caller, "commit_message": null } })), Access::PageWriter, true),
+            (message(serde_json::json!({ "DeletePage": { "wiki_id": wiki_id, "path": "Page", "user_id": caller } })), Access::PageWriter, true),
+            (message(serde_json::json!({ "GetPageHistory": { "wiki_id": wiki_id, "path": "Page" } })), Access::PageReader, false),
+            (message(serde_json::json!({ "RestoreDeletedPage": { "wiki_id": wiki_id, "path": "Page", "deleted_key": "key", "user_id": caller } })), Access::PageWriter, true),
+            (message(serde_json::json!({ "ListDeletedPages": { "wiki_id": wiki_id } })), Access::Reader, false),
+            (message(serde_json::json!({ "GetVersionDiff": { "wiki_id": wiki_id, "path": "Page", "version1_id": "v1", "version2_id": "v2" } })), Access::PageReader, false),
+            (message(serde_json::json!({ "GetPageBlame": { "wiki_id": wiki_id, "path": "Page" } })), Access::PageReader, false),
+            (message(serde_json::json!({ "GetRecentChanges": { "wiki_id": wiki_id, "offset": null, "limit": null } })), Access::Reader, false),
+            (message(serde_json::json!({ "SendInvite": {
+                "invite": {
+                    "id": "i1", "wiki_id": wiki_id, "wiki_name": wiki_id, "inviter_id": caller, "invitee_id": HOST,
+                    "created_at": now, "expires_at": now, "status": "Pending",
+                },
+                "wiki": wiki,
+            } })), Access::Anyone, true),
+            (message(serde_json::json!({ "InviteResponse": { "invite_id": "i1", "status": "Accepted", "invitee_id": caller } })), Access::Anyone, true),
+            (message(serde_json::json!({ "RevokeInvite": { "invite_id": "i1", "inviter_id": caller } })), Access::Anyone, true),
+            (message(serde_json::json!({ "RequestToJoin": { "request": join_request } })), Access::Anyone, true),
+            (message(serde_json::json!({ "JoinRequestDecision": { "request": join_request, "wiki": null } })), Access::Anyone, true),
+            (message(serde_json::json!({ "OfferOwnership": { "transfer": {
+                "wiki_id": wiki_id, "wiki_name": wiki_id, "from_id": caller, "to_id": HOST, "requested_at": now, "expires_at": now,
+            } } })), Access::Anyone, true),
+            (message(serde_json::json!({ "CancelOwnershipOffer": { "wiki_id": wiki_id, "from_id": caller } })), Access::Anyone, true),
+            (message(serde_json::json!({ "RespondToOwnershipOffer": { "wiki_id": wiki_id, "user_id": caller, "accept": true } })), Access::Reader, true),
+            (message(serde_json::json!({ "GetAuditLog": { "wiki_id": wiki_id, "query": AuditLogQuery::default() } })), Access::Admin, false),
+            (message(serde_json::json!({ "WatchPage": { "wiki_id": wiki_id, "path": "Page", "user_id": caller, "watch": true } })), Access::Reader, true),
+            (message(serde_json::json!({ "PageChanged": { "wiki_id": wiki_id, "host_id": caller, "change": {
+                "path": "Page", "kind": "Edited", "previous_path": null, "version_id": "v1", "author": caller, "timestamp": now, "commit_message": null,
+            } } })), Access::Anyone, true),
+            (message(serde_json::json!({ "MembershipRevoked": { "wiki_id": wiki_id, "host_id": caller } })), Access::Anyone, true),
+            (message(serde_json::json!({ "WikiDeleted": { "wiki_id": wiki_id, "host_id": caller } })), Access::Anyone, true),
+            (message(serde_json::json!({ "WikiRestored": { "wiki": wiki, "host_id": caller } })), Access::Anyone, true),
+            (message(serde_json::json!({ "WikiMoved": { "wiki_id": wiki_id, "host_id": caller, "new_host_id": WRITER } })), Access::Anyone, true),
+            (message(serde_json::json!({ "RoleUpdate": { "wiki_id": wiki_id, "member_id": HOST, "new_role": "Admin" } })), Access::WikiHost, false),
+            (message(serde_json::json!({ "SearchPages": { "wiki_id": wiki_id, "query": "text" } })), Access::Reader, false),
+            (message(serde_json::json!({ "SyncPage": { "wiki_id": wiki_id, "path": "Page", "state_vector": [] } })), Access::PageReader, false),
+            (message(serde_json::json!({ "ApplyPageUpdate": { "wiki_id": wiki_id, "path": "Page", "update": [], "user_id": caller, "commit_message": null } })), Access::PageWriter, true),
+            (message(serde_json::json!({ "LockPage": { "wiki_id": wiki_id, "path": "Page", "user_id": caller, "action": "acquire", "duration_minutes": null } })), Access::PageWriter, true),
+            (message(serde_json::json!({ "ProtectPage": { "wiki_id": wiki_id, "path": "Page", "user_id": caller, "protected": true } })), Access::PageAdmin, true),
+            (message(serde_json::json!({ "RevertPage": { "wiki_id": wiki_id, "path": "Page", "version_id": "v1", "user_id": caller } })), Access::PageWriter, true),
+            (message(serde_json::json!({ "TagVersion": { "wiki_id": wiki_id, "path": "Page", "version_id": "v1", "tag": "v1.0", "user_id": caller } })), Access::PageWriter, true),
+            (message(serde_json::json!({ "ReplicateWiki": { "wiki": wiki, "pages": [], "histories": [], "deleted_pages": {} } })), Access::NewReplica, false),
+            (message(serde_json::json!({ "ReplicateMembers": { "wiki": wiki } })), Access::ReplicaHost, false),
+            (message(serde_json::json!({ "ReplicatePage": { "wiki_id": wiki_id, "path": "Page", "previous_path": null, "update": [], "version": version } })), Access::ReplicaHost, false),
+            (message(serde_json::json!({ "ReplicatePageDeleted": { "deleted_key": "key", "deleted_page": deleted_page } })), Access::ReplicaHost, false),
+        ]
+    }
+
+    /// Breaks the build when a variant is added, as a reminder to add it to
+    /// `every_message` too.
+    fn variant_name(message: &WikiMessage) -> &'static str {
+        match message {
+            WikiMessage::FindWikisByUser { .. } => "FindWikisByUser",
+            WikiMessage::GetPublicWiki { .. } => "GetPublicWiki",
+            WikiMessage::JoinPublicWiki { .. } => "JoinPublicWiki",
+            WikiMessage::JoinWithCode { .. } => "JoinWithCode",
+            WikiMessage::LeaveWiki { .. } => "LeaveWiki",
+            WikiMessage::GetWikiData { .. } => "GetWikiData",
+            WikiMessage::GetWikiPages { .. } => "GetWikiPages",
+            WikiMessage::GetWikiPage { .. } => "GetWikiPage",
+            WikiMessage::CreatePage { .. } => "CreatePage",
+            WikiMessage::UpdatePage { .. } => "UpdatePage",
+            WikiMessage::DeletePage { .. } => "DeletePage",
+            WikiMessage::GetPageHistory { .. } => "GetPageHistory",
+            WikiMessage::RestoreDeletedPage { .. } => "RestoreDeletedPage",
+            WikiMessage::ListDeletedPages { .. } => "ListDeletedPages",
+            WikiMessage::GetVersionDiff { .. } => "GetVersionDiff",
+            WikiMessage::GetPageBlame { .. } => "GetPageBlame",
+            WikiMessage::GetRecentChanges { .. } => "GetRecentChanges",
+            WikiMessage::SendInvite { .. } => "SendInvite",
+            WikiMessage::InviteResponse { .. } => "InviteResponse",
+            WikiMessage::RevokeInvite { .. } => "RevokeInvite",
+            WikiMessage::RequestToJoin { .. } => "RequestToJoin",
+            WikiMessage::JoinRequestDecision { .. } => "JoinRequestDecision",
+            WikiMessage::OfferOwnership { .. } => "OfferOwnership",
+            WikiMessage::CancelOwnershipOffer { .. } => "CancelOwnershipOffer",
+            WikiMessage::RespondToOwnershipOffer { .. } => "RespondToOwnershipOffer",
+            WikiMessage::GetAuditLog { .. } => "GetAuditLog",
+            WikiMessage::WatchPage { .. } => "WatchPage",
+            WikiMessage::PageChanged { .. } => "PageChanged",
+            WikiMessage::MembershipRevoked { .. } => "MembershipRevoked",
+            WikiMessage::WikiDeleted { .. } => "WikiDeleted",
+            WikiMessage::WikiRestored { .. } => "WikiRestored",
+            WikiMessage::WikiMoved { .. } => "WikiMoved",
+            WikiMessage::RoleUpdate { .. } => "RoleUpdate",
+            WikiMessage::SearchPages { .. } => "SearchPages",
+            WikiMessage::SyncPage { .. } => "SyncPage",
+            WikiMessage::ApplyPageUpdate { .. } => "ApplyPageUpdate",
+            WikiMessage::LockPage { .. } => "LockPage",
+            WikiMessage::ProtectPage { .. } => "ProtectPage",
+            WikiMessage::RevertPage { .. } => "RevertPage",
+            WikiMessage::TagVersion { .. } => "TagVersion",
+            WikiMessage::ReplicateWiki { .. } => "ReplicateWiki",
+            WikiMessage::ReplicateMembers { .. } => "ReplicateMembers",
+            WikiMessage::ReplicatePage { .. } => "ReplicatePage",
+            WikiMessage::ReplicatePageDeleted { .. } => "ReplicatePageDeleted",
+        }
+    }
+    const MESSAGE_VARIANTS: usize = 44;
+
+    #[test]
+    fn every_message_variant_is_covered() {
+        let state = host_state();
+        let names: HashSet<&str> = every_message(&state, "public", READER).iter()
+            .map(|(message, _, _)| variant_name(message))
+            .collect();
+        assert_eq!(names.len(), MESSAGE_VARIANTS);
+    }
+
+    #[test]
+    fn messages_declare_their_access_and_claimed_sender() {
+        let state = host_state();
+        for (message, access, claims) in every_message(&state, "public", READER) {
+            let name = variant_name(&message);
+            assert_eq!(access_of(&message), access, "{}", name);
+            let expected_claim = claims.then_some(READER);
+            assert_eq!(message.claimed_user_id(), expected_claim, "{}", name);
+        }
+    }
+
+    #[test]
+    fn authorize_follows_membership_and_visibility() {
+        let state = host_state();
+        for (wiki_id, is_public) in [("public", true), ("private", false)] {
+            for caller in [ADMIN, WRITER, READER, STRANGER] {
+                let is_member = caller != STRANGER;
+                for (message, access, _) in every_message(&state, wiki_id, caller) {
+                    let allowed = match access {
+                        Access::Anyone => true,
+                        Access::Reader | Access::PageReader => is_member || is_public,
+                        Access::PageWriter => caller == ADMIN || caller == WRITER,
+                        Access::PageAdmin | Access::Admin => caller == ADMIN,
+                        // We host both wikis, so nobody hosts them for us
+                        Access::WikiHost | Access::ReplicaHost | Access::NewReplica => false,
+                    };
+                    assert_eq!(
+                        state.authorize(&message, caller).is_ok(),
+                        allowed,
+                        "{} from {} on {}",
+                        variant_name(&message),
+                        caller,
+                        wiki_id,
+                    );
+                }
+            }
+        }
+    }
+
+    fn acl_entry(pattern: &str, node_id: Option<&str>, role: Option<WikiRole>, read_role: Option<WikiRole>, write_role: Option<WikiRole>) -> PageAclEntry {
+        PageAclEntry {
+            pattern: pattern.to_string(),
+            node_id: node_id.map(str::to_string),
+            role,
+            read_role,
+            write_role,
+        }
+    }
+
+    const CONTRACTOR: &str = "contractor.os";
+
+    fn acl_wiki() -> Wiki {
+        let mut wiki = wiki("public", true);
+        wiki.members.insert(CONTRACTOR.to_string(), WikiRole::Reader);
+        wiki.acl = vec![
+            acl_entry("Policies/Handbook", None, None, None, Some(WikiRole::Admin)),
+            acl_entry("Drafts/*", None, None, Some(WikiRole::Writer), None),
+            acl_entry("Drafts/Public/*", None, None, Some(WikiRole::Reader), None),
+            acl_entry("Clients/*", None, None, Some(WikiRole::Writer), None),
+            acl_entry("Clients/Acme/*", Some(CONTRACTOR), Some(WikiRole::Writer), None, None),
+            acl_entry("Archive/*", Some(WRITER), Some(WikiRole::Reader), None, None),
+        ];
+        wiki
+    }
+
+    #[test]
+    fn acl_lookup_prefers_the_most_specific_entry() {
+        let wiki = acl_wiki();
+        let read_role = |path: &str| wiki.acl_lookup(path, |entry| entry.read_role.clone());
+        assert_eq!(read_role("Drafts/Plan"), Some(WikiRole::Writer));
+        assert_eq!(read_role("Drafts/Public/Notes"), Some(WikiRole::Reader));
+        assert_eq!(read_role("Home"), None);
+
+        // Entries without the selected field don't shadow broader ones
+        assert_eq!(read_role("Clients/Acme/Brief"), Some(WikiRole::Writer));
+        let mut exact = wiki.clone();
+        exact.acl.push(acl_entry("Drafts/Plan", None, None, Some(WikiRole::Admin), None));
+        assert_eq!(exact.acl_lookup("Drafts/Plan", |entry| entry.read_role.clone()), Some(WikiRole::Admin));
+        assert_eq!(exact.acl_lookup("Drafts/Plan2", |entry| entry.read_role.clone()), Some(WikiRole::Writer));
+    }
+
+    #[test]
+    fn check_page_access_applies_the_acl() {
+        let wiki = acl_wiki();
+        let cases = [
+            // Unlisted pages follow wiki roles and visibility
+            (READER, "Home", WikiRole::Reader, true),
+            (READER, "Home", WikiRole::Writer, false),
+            (WRITER, "Home", WikiRole::Writer, true),
+            (STRANGER, "Home", WikiRole::Reader, true),
+            (STRANGER, "Home", WikiRole::Writer, false),
+            // An Admin-only policy page: everyone reads, only Admins edit
+            (READER, "Policies/Handbook", WikiRole::Reader, true),
+            (WRITER, "Policies/Handbook", WikiRole::Writer, false),
+            (ADMIN, "Policies/Handbook", WikiRole::Writer, true),
+            // Drafts are hidden from Readers and outsiders, except the longer public prefix
+            (READER, "Drafts/Plan", WikiRole::Reader, false),
+            (STRANGER, "Drafts/Plan", WikiRole::Reader, false),
+            (WRITER, "Drafts/Plan", WikiRole::Reader, true),
+            (ADMIN, "Drafts/Plan", WikiRole::Writer, true),
+            (READER, "Drafts/Public/Notes", WikiRole::Reader, true),
+            (STRANGER, "Drafts/Public/Notes", WikiRole::Reader, true),
+            // A contractor writes under their client's prefix only
+            (CONTRACTOR, "Clients/Acme/Brief", WikiRole::Reader, true),
+            (CONTRACTOR, "Clients/Acme/Brief", WikiRole::Writer, true),
+            (CONTRACTOR, "Clients/Other/Brief", WikiRole::Reader, false),
+            (CONTRACTOR, "Home", WikiRole::Writer, false),
+            (READER, "Clients/Acme/Brief", WikiRole::Reader, false),
+            (WRITER, "Clients/Other/Brief", WikiRole::Writer, true),
+            // Node-specific entries replace that member's role, nobody else's
+            (WRITER, "Archive/2020", WikiRole::Reader, true),
+            (WRITER, "Archive/2020", WikiRole::Writer, false),
+            (ADMIN, "Archive/2020", WikiRole::Writer, true),
+        ];
+        for (caller, path, role, allowed) in cases {
+            assert_eq!(
+                wiki.check_page_access(caller, path, role.clone()).is_ok(),
+                allowed,
+                "{} as {:?} on {}",
+                caller,
+                role,
+                path,
+            );
+        }
+
+        let mut private = wiki.clone();
+        private.is_public = false;
+        assert!(private.check_page_access(STRANGER, "Home", WikiRole::Reader).is_err());
+        assert!(private.check_page_access(STRANGER, "Drafts/Public/Notes", WikiRole::Reader).is_err());
+    }
+
+    #[test]
+    fn replica_copies_apply_their_page_acl() {
+        let mut state = WikiState::new(READER);
+        let mut replica = acl_wiki();
+        replica.replica_of = Some(HOST.to_string());
+        state.wikis.insert(replica.id.clone(), replica);
+
+        assert!(state.check_page_permission("public", "Home", WikiRole::Reader).is_ok());
+        assert!(state.check_page_permission("public", "Drafts/Plan", WikiRole::Reader).is_err());
+        assert_eq!(
+            state.check_page_permission("public", "Home", WikiRole::Writer),
+            Err("Wiki is a read-only replica".to_string()),
+        );
+    }
+
+    #[test]
+    fn authorize_lets_hosts_reach_their_members_and_replicas() {
+        let mut state = WikiState::new(READER);
+        state.my_memberships.push(WikiMembership {
+            wiki_id: format!("member@{}", HOST),
+            role: WikiRole::Reader,
+            joined_at: Utc::now().to_rfc3339(),
+        });
+        let mut replica = wiki("replica", false);
+        replica.replica_of = Some(HOST.to_string());
+        replica.replica_nodes = vec![READER.to_string()];
+        state.wikis.insert(replica.id.clone(), replica.clone());
+
+        let role_update = message(serde_json::json!({ "RoleUpdate": { "wiki_id": "member", "member_id": READER, "new_role": "Writer" } }));
+        assert!(state.authorize(&role_update, HOST).is_ok());
+        assert!(state.authorize(&role_update, STRANGER).is_err());
+
+        for (message, access, _) in every_message(&state, "replica", HOST) {
+            if access == Access::ReplicaHost || access == Access::NewReplica {
+                assert!(state.authorize(&message, HOST).is_ok(), "{}", variant_name(&message));
+            }
+        }
+        for (message, access, _) in every_message(&state, "replica", STRANGER) {
+            if access == Access::ReplicaHost || access == Access::NewReplica {
+                assert!(state.authorize(&message, STRANGER).is_err(), "{}", variant_name(&message));
+            }
+        }
+
+        // A wiki we don't hold yet can be pushed by whoever lists us as a replica
+        let mut offered = wiki("offered", false);
+        offered.replica_nodes = vec![READER.to_string()];
+        let snapshot = WikiMessage::ReplicateWiki { wiki: offered, pages: Vec::new(), histories: Vec::new(), deleted_pages: HashMap::new() };
+        assert!(state.authorize(&snapshot, HOST).is_ok());
+    }
+
+    fn diff(old: &str, new: &str, granularity: DiffGranularity) -> Vec<DiffLine> {
+        WikiState::new(HOST).calculate_diff(old, new, &DiffOptions { granularity, context_lines: None })
+    }
+
+    /// Each line as its unified-diff marker followed by its content.
+    fn marked(lines: &[DiffLine]) -> Vec<String> {
+        lines.iter()
+            .map(|line| {
+                let marker = match line.line_type {
+                    DiffLineType::Added => '+',
+                    DiffLineType::Removed => '-',
+                    DiffLineType::Unchanged => ' ',
+                };
+                format!("{}{}", marker, line.content)
+            })
+            .collect()
+    }
+
+    fn segments(line: &DiffLine) -> Vec<(&str, bool)> {
+        line.segments.iter().map(|segment| (segment.text.as_str(), segment.changed)).collect()
+    }
+
+    #[test]
+    fn diff_line_inserted_at_the_top() {
+        let lines = diff("b\nc", "a\nb\nc", DiffGranularity::Line);
+        assert_eq!(marked(&lines), ["+a", " b", " c"]);
+        assert_eq!((lines[0].line_number_old, lines[0].line_number_new), (None, Some(1)));
+        assert_eq!((lines[1].line_number_old, lines[1].line_number_new), (Some(1), Some(2)));
+    }
+
+    #[test]
+    fn diff_line_deleted() {
+        let lines = diff("a\nb\nc", "a\nc", DiffGranularity::Line);
+        assert_eq!(marked(&lines), [" a", "-b", " c"]);
+        assert_eq!((lines[1].line_number_old, lines[1].line_number_new), (Some(2), None));
+        assert_eq!((lines[2].line_number_old, lines[2].line_number_new), (Some(3), Some(2)));
+    }
+
+    #[test]
+    fn diff_highlights_changed_words_within_a_line() {
+        let lines = diff("the quick brown fox", "the slow brown fox!", DiffGranularity::Word);
+        assert_eq!(marked(&lines), ["-the quick brown fox", "+the slow brown fox!"]);
+        assert_eq!(segments(&lines[0]), [("the ", false), ("quick", true), (" brown fox", false)]);
+        assert_eq!(segments(&lines[1]), [("the ", false), ("slow", true), (" brown fox", false), ("!", true)]);
+
+        let lines = diff("colour", "color", DiffGranularity::Char);
+        assert_eq!(segments(&lines[0]), [("colo", false), ("u", true), ("r", false)]);
+        assert_eq!(segments(&lines[1]), [("color", false)]);
+
+        assert!(diff("a b", "a c", DiffGranularity::Line).iter().all(|line| line.segments.is_empty()));
+    }
+
+    #[test]
+    fn diff_hunks_merge_when_their_context_overlaps() {
+        let old: Vec<String> = (1..=20).map(|n| n.to_string()).collect();
+        let mut new = old.clone();
+        new[4] = "five".to_string();
+        new[9] = "ten".to_string();
+        let lines = diff(&old.join("\n"), &new.join("\n"), DiffGranularity::Line);
+
+        // Changes four unchanged lines apart share a hunk once the context covers the gap
+        let hunks = WikiState::diff_hunks(&lines, 1);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].old_start, hunks[0].old_lines, hunks[0].new_start, hunks[0].new_lines), (4, 3, 4, 3));
+        assert_eq!((hunks[1].old_start, hunks[1].old_lines, hunks[1].new_start, hunks[1].new_lines), (9, 3, 9, 3));
+
+        let hunks = WikiState::diff_hunks(&lines, 2);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!((hunks[0].old_start, hunks[0].old_lines, hunks[0].new_start, hunks[0].new_lines), (3, 10, 3, 10));
+        assert_eq!(hunks[0].line_count, 12);
+
+        let hunks = WikiState::diff_hunks(&lines, 0);
+        assert_eq!(hunks.iter().map(|hunk| hunk.line_count).collect::<Vec<_>>(), [2, 2]);
+    }
+
+    #[test]
+    fn unified_diff_text() {
+        let old = "title\n\nfirst\nsecond\nthird\nfourth\nfifth\nsixth\nseventh\nlast";
+        let new = "new title\n\nfirst\nsecond\nthird\nfourth\nfifth\nsixth\nseventh";
+        let lines = diff(old, new, DiffGranularity::Line);
+        let hunks = WikiState::diff_hunks(&lines, 1);
+        assert_eq!(
+            WikiState::unified_diff("page@v1", "page@v2", &lines, &hunks),
+            "--- page@v1\n+++ page@v2\n\
+             @@ -1,2 +1,2 @@\n-title\n+new title\n \n\
+             @@ -9,2 +9,1 @@\n seventh\n-last\n",
+        );
+
+        // Adding to an empty page numbers the empty side by the line before it
+        let lines = diff("", "one\ntwo", DiffGranularity::Line);
+        let hunks = WikiState::diff_hunks(&lines, 3);
+        assert_eq!(
+            WikiState::unified_diff("a", "b", &lines, &hunks),
+            "--- a\n+++ b\n@@ -0,0 +1,2 @@\n+one\n+two\n",
+        );
+
+        assert_eq!(WikiState::unified_diff("a", "b", &[], &[]), "");
+    }
+
+    #[test]
+    fn myers_diff_finds_a_shortest_script() {
+        let a: Vec<char> = "ABCABBA".chars().collect();
+        let b: Vec<char> = "CBABAC".chars().collect();
+        let ops = WikiState::myers_diff(&a, &b);
+        assert_eq!(ops.iter().filter(|op| !matches!(op, DiffOp::Equal(..))).count(), 5);
+
+        // Replaying the script rebuilds the new sequence
+        let rebuilt: Vec<char> = ops.iter()
+            .filter_map(|op| match *op {
+                DiffOp::Equal(i, _) => Some(a[i]),
+                DiffOp::Insert(j) => Some(b[j]),
+                DiffOp::Delete(_) => None,
+            })
+            .collect();
+        assert_eq!(rebuilt, b);
+
+        // Past the edit limit the remainder is a plain replacement
+        let a: Vec<usize> = (0..MAX_DIFF_EDITS * 2).collect();
+        let b: Vec<usize> = a.iter().rev().copied().collect();
+        let ops = WikiState::myers_diff(&a, &b);
+        assert_eq!(ops.len(), a.len() + b.len());
+    }
+
+    #[test]
+    fn accepted_ownership_moves_the_wiki_to_its_new_owner() {
+        let mut host = host_state();
+        host.write_page("private", "Home", "# Home\n\nhello", HOST, None);
+        host.watchers.insert("private".to_string(), vec![PageWatcher { node_id: READER.to_string(), path: None }]);
+
+        let mut owner = WikiState::new(WRITER);
+        owner.my_memberships.push(WikiMembership {
+            wiki_id: format!("private@{}", HOST),
+            role: WikiRole::Writer,
+            joined_at: Utc::now().to_rfc3339(),
+        });
+        assert!(owner.check_permission(&format!("private@{}", HOST), WikiRole::Admin).is_err());
+
+        let (snapshot, watchers) = host.hand_over_wiki("private", WRITER).unwrap();
+        owner.take_over_wiki(HOST, snapshot, watchers);
+
+        // The new owner hosts the wiki and can administer it
+        assert!(owner.check_permission("private", WikiRole::SuperAdmin).is_ok());
+        let wiki = &owner.wikis["private"];
+        assert_eq!(wiki.created_by, WRITER);
+        assert_eq!(wiki.replica_of, None);
+        assert_eq!(wiki.replica_nodes, [HOST]);
+        assert!(owner.my_memberships.iter().any(|m| m.wiki_id == "private" && m.role == WikiRole::SuperAdmin));
+        assert!(owner.my_memberships.iter().all(|m| m.wiki_id != format!("private@{}", HOST)));
+        assert!(owner.pages.contains_key("private:Home"));
+        assert_eq!(owner.watchers["private"], [PageWatcher { node_id: READER.to_string(), path: None }]);
+        assert!(owner.replication_outbox.contains_key(&format!("private@{}", HOST)));
+
+        // The previous host keeps a read-only copy that follows the new host, and stays an Admin
+        let wiki = &host.wikis["private"];
+        assert_eq!(wiki.replica_of.as_deref(), Some(WRITER));
+        assert_eq!(wiki.members[HOST], WikiRole::Admin);
+        assert!(host.check_permission("private", WikiRole::Writer).is_err());
+        assert!(host.my_memberships.iter().any(|m| m.wiki_id == format!("private@{}", WRITER) && m.role == WikiRole::Admin));
+        assert!(host.accepts_replication("private", WRITER));
+        assert!(!host.accepts_replication("private", HOST));
+        assert!(host.watchers.is_empty());
+    }
+
+    #[test]
+    fn members_follow_a_moved_wiki_to_its_new_host() {
+        let mut member = WikiState::new(READER);
+        let remote_wiki_id = format!("private@{}", HOST);
+        member.my_memberships.push(WikiMembership {
+            wiki_id: remote_wiki_id.clone(),
+            role: WikiRole::Reader,
+            joined_at: Utc::now().to_rfc3339(),
+        });
+        member.watches.push(PageWatch { wiki_id: remote_wiki_id.clone(), path: None, created_at: Utc::now().to_rfc3339() });
+        member.replicas.insert(remote_wiki_id.clone(), WikiReplica::default());
+        let mut replica = wiki("private", false);
+        replica.replica_of = Some(HOST.to_string());
+        member.wikis.insert("private".to_string(), replica);
+
+        member.follow_moved_wiki("private", HOST, WRITER);
+
+        let moved_id = format!("private@{}", WRITER);
+        assert_eq!(member.my_memberships[0].wiki_id, moved_id);
+        assert_eq!(member.watches[0].wiki_id, moved_id);
+        assert!(member.replicas.contains_key(&moved_id) && !member.replicas.contains_key(&remote_wiki_id));
+        assert!(member.accepts_replication("private", WRITER));
+    }
+
+    fn version(id: usize) -> PageVersion {
+        PageVersion {
+            version_id: format!("v{}", id),
+            content: Vec::new(),
+            delta: false,
+            updated_by: WRITER.to_string(),
+            updated_at: Utc::now().to_rfc3339(),
+            commit_message: None,
+            change: PageChange::Edited,
+            tag: None,
+        }
+    }
+
+    fn empty_history() -> PageHistory {
+        PageHistory {
+            path: "Home".to_string(),
+            wiki_id: "public".to_string(),
+            versions: Vec::new(),
+            current_version_id: String::new(),
+            head_state_vector: Vec::new(),
+        }
+    }
+
+    /// The text of each of `count` edits to one page, with lines added,
+    /// rewritten and removed along the way.
+    fn edited_texts(count: usize) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        (0..count)
+            .map(|n| {
+                match n % 4 {
+                    0 | 1 => lines.push(format!("line {}", n)),
+                    2 => lines[n / 4] = format!("rewritten {}", n),
+                    _ => { lines.remove(0); }
+                }
+                lines.join("\n")
+            })
+            .collect()
+    }
+
+    /// A history of `texts` as saved before deltas: every version in full,
+    /// and no head state vector.
+    fn snapshot_history(texts: &[String]) -> PageHistory {
+        let doc = Doc::new();
+        let mut history = empty_history();
+        for (n, text) in texts.iter().enumerate() {
+            WikiState::apply_text_change(&doc, text);
+            let mut version = version(n);
+            version.content = full_update(&doc);
+            history.current_version_id = version.version_id.clone();
+            history.versions.push(version);
+        }
+        history
+    }
+
+    fn full_text(version: &PageVersion) -> String {
+        let doc = Doc::new();
+        apply(&doc, &version.content);
+        text_of(&doc)
+    }
+
+    #[test]
+    fn delta_history_rebuilds_every_version() {
+        let texts = edited_texts(VERSION_SNAPSHOT_INTERVAL * 2 + 5);
+        let snapshots = snapshot_history(&texts);
+        let doc = Doc::new();
+        let mut history = empty_history();
+        for (n, text) in texts.iter().enumerate() {
+            WikiState::apply_text_change(&doc, text);
+            history.record_version(version(n), &doc);
+        }
+
+        for (index, version) in history.versions.iter().enumerate() {
+            assert_eq!(version.delta, !index.is_multiple_of(VERSION_SNAPSHOT_INTERVAL), "version {}", index);
+            assert_eq!(history.version_text(index).unwrap(), full_text(&snapshots.versions[index]), "version {}", index);
+        }
+        let replayed: Vec<String> = history.version_texts().into_iter().map(Result::unwrap).collect();
+        assert_eq!(replayed, texts);
+        assert_eq!(history.current_version_id, format!("v{}", texts.len() - 1));
+    }
+
+    #[test]
+    fn compacted_history_rebuilds_every_version() {
+        let texts = edited_texts(VERSION_SNAPSHOT_INTERVAL + 7);
+        let mut history = snapshot_history(&texts);
+        let current_version_id = history.current_version_id.clone();
+        history.compact();
+
+        assert!(!history.head_state_vector.is_empty());
+        assert_eq!(history.current_version_id, current_version_id);
+        assert_eq!(history.versions.iter().filter(|version| !version.delta).count(), 2);
+        for (index, text) in texts.iter().enumerate() {
+            assert_eq!(&history.version_text(index).unwrap(), text, "version {}", index);
+        }
+
+        // Compacting again changes nothing a reader can see
+        history.compact();
+        let replayed: Vec<String> = history.version_texts().into_iter().map(Result::unwrap).collect();
+        assert_eq!(replayed, texts);
+
+        // New versions carry on from the compacted head
+        let doc = history.version_doc(texts.len() - 1).unwrap();
+        WikiState::apply_text_change(&doc, "after compaction");
+        assert!(history.record_version(version(texts.len()), &doc).delta);
+        assert_eq!(history.version_text(texts.len()).unwrap(), "after compaction");
+    }
+
+    #[test]
+    fn histories_saved_before_deltas_are_migrated() {
+        let texts = edited_texts(6);
+        let saved = snapshot_history(&texts);
+        // As serialized before versions had `delta` and histories a head state vector
+        let mut old_json = serde_json::to_value(&saved).unwrap();
+        old_json.as_object_mut().unwrap().remove("head_state_vector");
+        for version in old_json["versions"].as_array_mut().unwrap() {
+            version.as_object_mut().unwrap().remove("delta");
+        }
+        let history: PageHistory = serde_json::from_value(old_json).unwrap();
+        assert!(history.head_state_vector.is_empty());
+        assert!(history.versions.iter().all(|version| !version.delta));
+
+        let mut state = WikiState::new(HOST);
+        state.page_histories.insert("public:Home".to_string(), history.clone());
+        state.deleted_pages.insert("public:Old:0".to_string(), DeletedPage {
+            path: "Old".to_string(),
+            wiki_id: "public".to_string(),
+            deleted_at: Utc::now().to_rfc3339(),
+            deleted_by: HOST.to_string(),
+            history,
+        });
+        state.compact_page_histories();
+
+        let histories = [&state.page_histories["public:Home"], &state.deleted_pages["public:Old:0"].history];
+        for history in histories {
+            assert!(!history.head_state_vector.is_empty());
+            assert!(history.versions.iter().skip(1).all(|version| version.delta));
+            let replayed: Vec<String> = history.version_texts().into_iter().map(Result::unwrap).collect();
+            assert_eq!(replayed, texts);
+        }
+    }
+
+    #[test]
+    fn blame_credits_lines_through_insert_edit_and_revert() {
+        let mut state = host_state();
+        state.write_page("public", "Home", "# Home\nalpha\nbeta", WRITER, None);
+        state.write_page("public", "Home", "# Home\nalpha\nBETA\ngamma", ADMIN, None);
+        let history = &state.page_histories["public:Home"];
+        let (first, second) = (history.versions[0].version_id.clone(), history.versions[1].version_id.clone());
+
+        let blame = |state: &WikiState| -> Vec<(String, String, String)> {
+            WikiState::page_blame(&state.page_histories["public:Home"]).lines.into_iter()
+                .map(|line| (line.content, line.version_id, line.author))
+                .collect()
+        };
+        let line = |content: &str, version_id: &str, author: &str| (content.to_string(), version_id.to_string(), author.to_string());
+
+        // The edited and the inserted line belong to the edit, the rest to the first version
+        assert_eq!(blame(&state), [
+            line("# Home", &first, WRITER),
+            line("alpha", &first, WRITER),
+            line("BETA", &second, ADMIN),
+            line("gamma", &second, ADMIN),
+        ]);
+
+        // A revert brings back the old text as a change of its own
+        state.revert_page_to("public", "Home", &first, HOST).unwrap();
+        let history = &state.page_histories["public:Home"];
+        let third = history.versions[2].version_id.clone();
+        let blamed = WikiState::page_blame(history);
+        assert_eq!(blamed.version_id, third);
+        assert_eq!(blamed.lines.iter().map(|line| line.line_number).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(blame(&state), [
+            line("# Home", &first, WRITER),
+            line("alpha", &first, WRITER),
+            line("beta", &third, HOST),
+        ]);
+    }
+
+    #[test]
+    fn replicated_deleted_pages_stay_within_their_wiki() {
+        let deleted_page = |wiki_id: &str, path: &str| DeletedPage {
+            path: path.to_string(),
+            wiki_id: wiki_id.to_string(),
+            deleted_at: "2026-01-02T03:04:05+00:00".to_string(),
+            deleted_by: HOST.to_string(),
+            history: empty_history(),
+        };
+        let snapshot = WikiSnapshot {
+            wiki: wiki("public", true),
+            pages: Vec::new(),
+            histories: Vec::new(),
+            deleted_pages: HashMap::from([
+                ("public:Old:1700000000".to_string(), deleted_page("public", "Old")),
+                ("private:Secret:1700000000".to_string(), deleted_page("public", "Moved")),
+                ("public:Other:1700000000".to_string(), deleted_page("private", "Other")),
+                ("public:Bad".to_string(), deleted_page("public", "Bad")),
+            ]),
+        };
+
+        let mut state = WikiState::new(READER);
+        state.install_snapshot(snapshot);
+
+        let mut keys: Vec<&String> = state.deleted_pages.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["public:Bad:1767323045", "public:Moved:1700000000", "public:Old:1700000000"]);
+        assert!(state.deleted_pages.values().all(|deleted_page| deleted_page.wiki_id == "public"));
+    }
+
+    fn page_text(state: &WikiState, page_key: &str) -> String {
+        let doc = Doc::new();
+        apply(&doc, &state.pages[page_key].yrs_doc);
+        text_of(&doc)
+    }
+
+    #[test]
+    fn creating_a_page_never_replaces_an_existing_one() {
+        let mut state = host_state();
+        state.add_page("public", "Policy", "# Policy\nrules", HOST, None).unwrap();
+        state.set_page_protected("public", "Policy", HOST, true).unwrap();
+        state.update_page_lock("public", "Policy", HOST, "acquire", None).unwrap();
+
+        assert_eq!(state.add_page("public", "Policy", "# Policy\nmine now", WRITER, None), Err("Page already exists".to_string()));
+        let page = &state.pages["public:Policy"];
+        assert!(page.protected);
+        assert_eq!(page.lock.as_ref().map(|lock| lock.node_id.as_str()), Some(HOST));
+        assert_eq!(page_text(&state, "public:Policy"), "# Policy\nrules");
+        assert_eq!(state.page_histories["public:Policy"].versions.len(), 1);
+    }
+
+    #[test]
+    fn protection_and_locks_guard_page_edits() {
+        // HOST edits as the local node; the others arrive as remote UpdatePage/DeletePage
+        const EDITOR: &str = "editor.os";
+        let mut state = host_state();
+        state.wikis.get_mut("public").unwrap().members.insert(EDITOR.to_string(), WikiRole::Writer);
+        state.add_page("public", "Home", "# Home", HOST, None).unwrap();
+
+        // Protected pages are left to admins
+        assert!(state.set_page_protected("public", "Home", WRITER, true).is_err());
+        state.set_page_protected("public", "Home", ADMIN, true).unwrap();
+        let refused = state.edit_page("public", "Home", "# Home\nwriter", WRITER, None).unwrap_err();
+        assert!(refused.contains("protected"), "{}", refused);
+        assert!(state.remove_page("public", "Home", WRITER).is_err());
+        assert!(state.update_page_lock("public", "Home", WRITER, "acquire", None).is_err());
+        state.edit_page("public", "Home", "# Home\nadmin", ADMIN, None).unwrap();
+        state.edit_page("public", "Home", "# Home\nhost", HOST, None).unwrap();
+        state.set_page_protected("public", "Home", ADMIN, false).unwrap();
+
+        // A lock keeps everyone but its holder out, admins and the host included
+        state.update_page_lock("public", "Home", WRITER, "acquire", Some(30)).unwrap();
+        let refused = state.edit_page("public", "Home", "# Home\nadmin", ADMIN, None).unwrap_err();
+        assert!(refused.contains("locked by writer.os"), "{}", refused);
+        assert!(state.edit_page("public", "Home", "# Home\nhost", HOST, None).is_err());
+        assert!(state.remove_page("public", "Home", HOST).is_err());
+        assert!(state.update_page_lock("public", "Home", EDITOR, "acquire", None).is_err());
+        state.edit_page("public", "Home", "# Home\nlocked edit", WRITER, None).unwrap();
+
+        // Only the holder releases it; only an admin breaks it
+        assert!(state.update_page_lock("public", "Home", EDITOR, "release", None).is_err());
+        assert!(state.update_page_lock("public", "Home", EDITOR, "break", None).is_err());
+        state.update_page_lock("public", "Home", ADMIN, "break", None).unwrap();
+        state.edit_page("public", "Home", "# Home\neditor", EDITOR, None).unwrap();
+        state.update_page_lock("public", "Home", WRITER, "acquire", None).unwrap();
+        state.update_page_lock("public", "Home", WRITER, "release", None).unwrap();
+        assert!(state.pages["public:Home"].lock.is_none());
+
+        // An expired lock no longer binds anyone
+        state.update_page_lock("public", "Home", WRITER, "acquire", None).unwrap();
+        state.pages.get_mut("public:Home").unwrap().lock.as_mut().unwrap().expires_at = (Utc::now() - chrono::Duration::minutes(1)).to_rfc3339();
+        state.edit_page("public", "Home", "# Home\nafter expiry", EDITOR, None).unwrap();
+        state.update_page_lock("public", "Home", EDITOR, "acquire", None).unwrap();
+
+        assert!(state.remove_page("public", "Home", WRITER).is_err());
+        state.remove_page("public", "Home", EDITOR).unwrap();
+        assert!(!state.pages.contains_key("public:Home"));
+        assert_eq!(state.remove_page("public", "Home", EDITOR), Err("Page not found".to_string()));
+    }
+
+    #[test]
+    fn edits_cannot_retitle_a_page_onto_another() {
+        let mut state = host_state();
+        state.add_page("public", "Home", "# Home", WRITER, None).unwrap();
+        state.add_page("public", "Policy", "# Policy\nrules", HOST, None).unwrap();
+        state.set_page_protected("public", "Policy", HOST, true).unwrap();
+
+        let refused = state.edit_page("public", "Home", "# Policy\nhijacked", WRITER, None).unwrap_err();
+        assert!(refused.contains("already exists"), "{}", refused);
+        assert!(state.pages["public:Policy"].protected);
+        assert_eq!(page_text(&state, "public:Policy"), "# Policy\nrules");
+        assert_eq!(page_text(&state, "public:Home"), "# Home");
+
+        // Nor can a revert to an older title that's been taken since
+        let first = state.page_histories["public:Home"].versions[0].version_id.clone();
+        assert_eq!(state.edit_page("public", "Home", "# Notes", WRITER, None).unwrap(), "Notes");
+        state.add_page("public", "Home", "# Home\nnew", WRITER, None).unwrap();
+        assert!(state.revert_page_to("public", "Notes", &first, WRITER).is_err());
+        assert_eq!(page_text(&state, "public:Home"), "# Home\nnew");
+        assert_eq!(state.page_histories["public:Notes"].versions.len(), 2);
+    }
+}